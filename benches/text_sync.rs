@@ -0,0 +1,138 @@
+//Typing in a starter pack sized TrustFrameworkBase.xml
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lsp_types::{Position, Range, TextDocumentContentChangeEvent, TextEdit, Url};
+use tree_sitter::Parser;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/error.rs"]
+mod error;
+//Declared the same way main.rs does, so its submodules are found
+#[allow(dead_code, unused_imports)]
+#[path = "../src"]
+mod server {
+    pub mod workspace;
+}
+
+use server::workspace::{self, new_workspace, Discovery};
+
+use workspace::sync::{PositionEncoding, TextSync};
+
+//Roughly the shape and size of TrustFrameworkBase.xml from the custom policy
+//starter pack, about 5,000 lines
+fn starter_pack_base() -> String {
+    let mut xml = String::from(
+        "<TrustFrameworkPolicy PolicySchemaVersion=\"0.3.0.0\" TenantId=\"yourtenant.onmicrosoft.com\" PolicyId=\"B2C_1A_TrustFrameworkBase\" PublicPolicyUri=\"http://yourtenant.onmicrosoft.com/B2C_1A_TrustFrameworkBase\">\n  <BuildingBlocks>\n    <ClaimsSchema>\n",
+    );
+    for i in 0..250 {
+        xml.push_str(&format!(
+            "      <ClaimType Id=\"claim{i}\">\n        <DisplayName>Claim {i} – ünïcödé</DisplayName>\n        <DataType>string</DataType>\n        <UserHelpText>Help for claim {i}</UserHelpText>\n        <UserInputType>TextBox</UserInputType>\n      </ClaimType>\n"
+        ));
+    }
+    xml.push_str("    </ClaimsSchema>\n  </BuildingBlocks>\n  <ClaimsProviders>\n    <ClaimsProvider>\n      <DisplayName>Local Account</DisplayName>\n      <TechnicalProfiles>\n");
+    for i in 0..175 {
+        xml.push_str(&format!(
+            "        <TechnicalProfile Id=\"Profile-{i}\">\n          <DisplayName>Profile {i}</DisplayName>\n          <Protocol Name=\"Proprietary\" Handler=\"Web.TPEngine.Providers.SelfAssertedAttributeProvider, Web.TPEngine, Version=1.0.0.0, Culture=neutral, PublicKeyToken=null\" />\n          <Metadata>\n            <Item Key=\"ContentDefinitionReferenceId\">api.selfasserted</Item>\n            <Item Key=\"setting.operatingMode\">Email</Item>\n          </Metadata>\n          <InputClaims>\n            <InputClaim ClaimTypeReferenceId=\"claim{i}\" />\n          </InputClaims>\n          <OutputClaims>\n            <OutputClaim ClaimTypeReferenceId=\"claim{i}\" Required=\"true\" />\n            <OutputClaim ClaimTypeReferenceId=\"objectId\" />\n          </OutputClaims>\n          <ValidationTechnicalProfiles>\n            <ValidationTechnicalProfile ReferenceId=\"login-NonInteractive\" />\n          </ValidationTechnicalProfiles>\n          <UseTechnicalProfileForSessionManagement ReferenceId=\"SM-AAD\" />\n        </TechnicalProfile>\n"
+        ));
+    }
+    xml.push_str("      </TechnicalProfiles>\n    </ClaimsProvider>\n  </ClaimsProviders>\n</TrustFrameworkPolicy>\n");
+    xml
+}
+
+fn parser() -> Parser {
+    let mut parser = Parser::new();
+    parser
+        .set_language(&tree_sitter_xml::language_xml())
+        .unwrap();
+    parser
+}
+
+fn bench_text_sync(c: &mut Criterion) {
+    let xml = starter_pack_base();
+    let middle = xml.lines().count() as u32 / 2;
+    let mut text = TextSync::new(xml.clone());
+
+    c.bench_function("edit: type and delete a character mid-file", |b| {
+        let pos = Position::new(middle, 8);
+        let end = Position::new(middle, 9);
+        b.iter(|| {
+            text.edit(&TextEdit {
+                range: Range::new(pos, pos),
+                new_text: String::from("x"),
+            });
+            text.edit(&TextEdit {
+                range: Range::new(pos, end),
+                new_text: String::new(),
+            });
+        })
+    });
+
+    c.bench_function("position: utf-16 to byte column near the end", |b| {
+        let pos = Position::new(middle * 2 - 5, 40);
+        b.iter(|| text.to_byte_position(black_box(pos), PositionEncoding::Utf16))
+    });
+
+    c.bench_function("position: byte offset near the end", |b| {
+        b.iter(|| text.byte_pos(black_box(middle as usize * 2 - 5), 12))
+    });
+
+    let mut parser = parser();
+    let tree = text.parse(&mut parser, None).unwrap();
+    c.bench_function("parse: full document from rope chunks", |b| {
+        b.iter(|| text.parse(&mut parser, None))
+    });
+
+    c.bench_function("parse: incremental after typing a character", |b| {
+        b.iter(|| {
+            let input_edit = text.edit(&TextEdit {
+                range: Range::new(Position::new(middle, 8), Position::new(middle, 8)),
+                new_text: String::from("x"),
+            });
+            let mut edited = tree.clone();
+            edited.edit(&input_edit);
+            let new_tree = text.parse(&mut parser, Some(&edited));
+            text.edit(&TextEdit {
+                range: Range::new(Position::new(middle, 8), Position::new(middle, 9)),
+                new_text: String::new(),
+            });
+            new_tree
+        })
+    });
+}
+
+//What a keystroke costs the server: the text, the tree and the Ids of the
+//document are brought up to date, its symbols are left for the next search
+fn bench_handle_edit(c: &mut Criterion) {
+    let xml = starter_pack_base();
+    let middle = xml.lines().count() as u32 / 2;
+    let root = std::env::temp_dir().join("ief_lsp_bench_empty");
+    std::fs::create_dir_all(&root).unwrap();
+    let mut ws = new_workspace(
+        root.to_str().unwrap(),
+        PositionEncoding::Utf16,
+        &Discovery::default(),
+    );
+    let document = Url::from_file_path(root.join("TrustFrameworkBase.xml")).unwrap();
+    ws.open_document(document.clone(), xml, 1).unwrap();
+
+    let change = |start: u32, end: u32, text: &str| TextDocumentContentChangeEvent {
+        range: Some(Range::new(
+            Position::new(middle, start),
+            Position::new(middle, end),
+        )),
+        range_length: None,
+        text: String::from(text),
+    };
+    let mut version = 1;
+    c.bench_function("handle_edit: type and delete a character mid-file", |b| {
+        b.iter(|| {
+            version += 2;
+            ws.update_document(document.clone(), version, vec![change(8, 8, "x")])
+                .unwrap();
+            ws.update_document(document.clone(), version + 1, vec![change(8, 9, "")])
+                .unwrap();
+        })
+    });
+}
+
+criterion_group!(benches, bench_text_sync, bench_handle_edit);
+criterion_main!(benches);
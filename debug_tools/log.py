@@ -1,23 +1,46 @@
 import asyncio
+import json
+from datetime import datetime
+
+SUPPORTED_VERSIONS={1, 2}
+
+def print_v2(line):
+  #One JSON object per record, see src/logging/protocol.rs
+  record = json.loads(line)
+  time = datetime.fromtimestamp(record["timestamp"] / 1000).strftime("%H:%M:%S.%f")[:-3]
+  span = ""
+  if "request" in record:
+    span = f" [{record['request']} {record['method']}]"
+  fields = ""
+  if "fields" in record:
+    fields = " " + " ".join(f"{k}={v}" for k, v in record["fields"].items())
+  print(f"{time} {record['level']:<5} {record['target']}{span} {record['message']}{fields}")
 
-CURRENT_VERSION=1
 async def handleMessage(reader, writer):
-  version_bytes = await reader.readline()
-  version = int(version_bytes.decode())
-  if CURRENT_VERSION != version:
-      print("Error version mismatch")
-      #IDK what to do here 
-      return
-  message_bytes = await reader.readline()
-  print(message_bytes.decode())
+  #The server keeps one connection open and sends every record over it
+  while True:
+    version_bytes = await reader.readline()
+    if not version_bytes:
+      break
+    version = int(version_bytes.decode())
+    if version not in SUPPORTED_VERSIONS:
+        print(f"Error unknown log protocol version {version}")
+        break
+    message_bytes = await reader.readline()
+    if version == 1:
+      print(message_bytes.decode().replace("\\n", "\n"), end="")
+    else:
+      print_v2(message_bytes.decode())
+  writer.close()
 
 async def main():
     path = "/tmp/debug.socket"
 
     server = await asyncio.start_unix_server(handleMessage, path=path)
     print(f"Starting server on path: {path}")
+    print("Start the server with IEF_LSP_LOG_BACKEND=socket to log here")
     async with server:
         await server.serve_forever()
     
 if __name__ == "__main__":
-    asyncio.run(main()) 
+    asyncio.run(main())
@@ -0,0 +1,126 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::io;
+use std::path::PathBuf;
+
+use lsp_server::{ErrorCode, Message, Notification, RequestId, Response};
+use lsp_types::notification::{Notification as _, ShowMessage};
+use lsp_types::{MessageType, Position, ShowMessageParams, Url};
+use tree_sitter::QueryError;
+
+//What can go wrong in the server. Requests answer with it, everything else is
+//shown to the user with window/showMessage
+#[derive(Debug)]
+pub enum ServerError {
+    //Reading a file from disk
+    Io(PathBuf, io::Error),
+    //Bytes that are not valid in the encoding of the file. They were replaced,
+    //the rest of the file is still of use
+    Encoding(PathBuf, &'static str),
+    //tree-sitter gave up on a document
+    Parse(String),
+    //One of the fixed queries does not compile
+    Query(QueryError),
+    //Not a file, or not one the workspace has loaded
+    UnknownDocument(Url),
+    //A line past the end of the document
+    PositionOutOfRange(Url, Position),
+    //A new name for rename that can not be used
+    InvalidName(String),
+    //Settings that do not fit the workspace, like an unknown environment
+    Settings(String),
+}
+
+impl Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ServerError::Io(path, e) => write!(f, "Could not read {}: {e}", path.display()),
+            ServerError::Encoding(path, encoding) => write!(
+                f,
+                "{} is not valid {encoding}, invalid bytes were replaced",
+                path.display()
+            ),
+            ServerError::Parse(document) => write!(f, "Could not parse {document}"),
+            ServerError::Query(e) => write!(f, "Invalid query: {e}"),
+            ServerError::UnknownDocument(document) => write!(f, "Unknown document {document}"),
+            ServerError::PositionOutOfRange(document, pos) => {
+                write!(f, "Line {} is past the end of {document}", pos.line + 1)
+            }
+            ServerError::InvalidName(msg) | ServerError::Settings(msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl Error for ServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ServerError::Io(_, e) => Some(e),
+            ServerError::Query(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ServerError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ServerError::UnknownDocument(_)
+            | ServerError::PositionOutOfRange(..)
+            | ServerError::InvalidName(_)
+            | ServerError::Settings(_) => ErrorCode::InvalidParams,
+            ServerError::Io(..) | ServerError::Encoding(..) | ServerError::Parse(_) => {
+                ErrorCode::RequestFailed
+            }
+            ServerError::Query(_) => ErrorCode::InternalError,
+        }
+    }
+
+    pub fn response(&self, id: RequestId) -> Message {
+        Message::Response(Response::new_err(id, self.code() as i32, self.to_string()))
+    }
+
+    pub fn show_message(&self) -> Message {
+        let typ = match self {
+            ServerError::Encoding(..) | ServerError::Settings(_) => MessageType::WARNING,
+            _ => MessageType::ERROR,
+        };
+        Message::Notification(Notification::new(
+            String::from(ShowMessage::METHOD),
+            ShowMessageParams {
+                typ,
+                message: self.to_string(),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lsp_server::{ErrorCode, Message, RequestId};
+    use lsp_types::{Position, Url};
+
+    use super::ServerError;
+
+    #[test]
+    fn test_error_messages() {
+        let document = Url::parse("file:///policies/TrustFrameworkBase.xml").unwrap();
+        let error = ServerError::PositionOutOfRange(document, Position::new(41, 0));
+        let Message::Response(response) = error.response(RequestId::from(3)) else {
+            panic!("Expected a response");
+        };
+        let response = response.error.unwrap();
+        assert_eq!(response.code, ErrorCode::InvalidParams as i32);
+        assert_eq!(
+            response.message,
+            "Line 42 is past the end of file:///policies/TrustFrameworkBase.xml"
+        );
+
+        let error = ServerError::Encoding("/policies/Base.xml".into(), "UTF-8");
+        let Message::Notification(notification) = error.show_message() else {
+            panic!("Expected window/showMessage");
+        };
+        assert_eq!(notification.method, "window/showMessage");
+        assert_eq!(notification.params["type"], 2);
+        assert_eq!(error.code() as i32, ErrorCode::RequestFailed as i32);
+    }
+}
@@ -0,0 +1,193 @@
+use log::{error, info, warn};
+use lsp_server::{ErrorCode, Message, RequestId, Response};
+use lsp_types::notification::{Exit, Notification as _};
+use lsp_types::request::{Initialize, Request as _, Shutdown};
+use lsp_types::InitializeParams;
+
+//Where the server is between initialize and exit. The main loop only sees the
+//messages the state allows, the rest is answered here
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Lifecycle {
+    #[default]
+    Uninitialized,
+    Running,
+    //shutdown was answered, only exit is left
+    ShuttingDown,
+}
+
+#[derive(Debug)]
+pub enum Step {
+    //The initialize request, the caller answers it with the capabilities
+    Initialize(RequestId, Box<InitializeParams>),
+    Handle(Message),
+    //Answered by the lifecycle itself
+    Reply(Message),
+    Ignore,
+    //Stop with this exit code, 0 when shutdown came before exit
+    Exit(u8),
+}
+
+fn error(id: RequestId, code: ErrorCode, message: &str) -> Step {
+    Step::Reply(Message::Response(Response::new_err(
+        id,
+        code as i32,
+        String::from(message),
+    )))
+}
+
+impl Lifecycle {
+    pub fn step(&mut self, msg: Message) -> Step {
+        match (*self, msg) {
+            (_, Message::Notification(not)) if not.method == Exit::METHOD => {
+                info!("Exit while {self:?}");
+                Step::Exit(if *self == Lifecycle::ShuttingDown {
+                    0
+                } else {
+                    1
+                })
+            }
+            //Answers to our own requests, like workspace/configuration, the
+            //main loop only needs them while running
+            (Lifecycle::Running, Message::Response(response)) => {
+                Step::Handle(Message::Response(response))
+            }
+            (_, Message::Response(response)) => {
+                if let Some(e) = response.error {
+                    warn!("Request {} failed: {}", response.id, e.message);
+                }
+                Step::Ignore
+            }
+            (Lifecycle::Uninitialized, Message::Request(req))
+                if req.method == Initialize::METHOD =>
+            {
+                match serde_json::from_value::<InitializeParams>(req.params) {
+                    Ok(params) => {
+                        *self = Lifecycle::Running;
+                        Step::Initialize(req.id, Box::new(params))
+                    }
+                    Err(e) => {
+                        error!("Invalid initialize params {e}");
+                        error(req.id, ErrorCode::InvalidParams, &e.to_string())
+                    }
+                }
+            }
+            (Lifecycle::Uninitialized, Message::Request(req)) => error(
+                req.id,
+                ErrorCode::ServerNotInitialized,
+                "Server is not initialized",
+            ),
+            (Lifecycle::Running, Message::Request(req)) if req.method == Initialize::METHOD => {
+                error(
+                    req.id,
+                    ErrorCode::InvalidRequest,
+                    "Server is already initialized",
+                )
+            }
+            (Lifecycle::Running, Message::Request(req)) if req.method == Shutdown::METHOD => {
+                info!("Shutting down");
+                *self = Lifecycle::ShuttingDown;
+                Step::Reply(Message::Response(Response::new_ok(req.id, ())))
+            }
+            (Lifecycle::ShuttingDown, Message::Request(req)) => {
+                error(req.id, ErrorCode::InvalidRequest, "Server is shutting down")
+            }
+            //Notifications before initialize and after shutdown are dropped
+            (Lifecycle::Uninitialized | Lifecycle::ShuttingDown, Message::Notification(not)) => {
+                info!("Dropping {} while {self:?}", not.method);
+                Step::Ignore
+            }
+            (Lifecycle::Running, msg) => Step::Handle(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lsp_server::{ErrorCode, Message, Notification, Request, RequestId, Response};
+    use serde_json::json;
+
+    use super::{Lifecycle, Step};
+
+    fn request(id: i32, method: &str, params: serde_json::Value) -> Message {
+        Message::Request(Request::new(
+            RequestId::from(id),
+            String::from(method),
+            params,
+        ))
+    }
+
+    fn notification(method: &str) -> Message {
+        Message::Notification(Notification::new(String::from(method), json!(null)))
+    }
+
+    fn error_code(step: Step) -> i32 {
+        match step {
+            Step::Reply(Message::Response(response)) => response.error.unwrap().code,
+            _ => panic!("Expected an error response, got {step:?}"),
+        }
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let mut lifecycle = Lifecycle::default();
+        let hover = || request(2, "textDocument/hover", json!({}));
+        assert_eq!(
+            error_code(lifecycle.step(hover())),
+            ErrorCode::ServerNotInitialized as i32
+        );
+        assert!(matches!(
+            lifecycle.step(notification("textDocument/didOpen")),
+            Step::Ignore
+        ));
+        assert_eq!(
+            error_code(lifecycle.step(request(1, "initialize", json!({"capabilities": 1})))),
+            ErrorCode::InvalidParams as i32
+        );
+        assert_eq!(lifecycle, Lifecycle::Uninitialized);
+        assert!(matches!(
+            lifecycle.step(request(1, "initialize", json!({"capabilities": {}}))),
+            Step::Initialize(..)
+        ));
+        assert_eq!(
+            error_code(lifecycle.step(request(3, "initialize", json!({"capabilities": {}})))),
+            ErrorCode::InvalidRequest as i32
+        );
+        assert!(matches!(lifecycle.step(hover()), Step::Handle(_)));
+        assert!(matches!(
+            lifecycle.step(Message::Response(Response::new_ok(
+                RequestId::from(1),
+                json!([{}])
+            ))),
+            Step::Handle(Message::Response(_))
+        ));
+
+        let Step::Reply(Message::Response(response)) =
+            lifecycle.step(request(4, "shutdown", json!(null)))
+        else {
+            panic!("Expected the shutdown response");
+        };
+        assert!(response.error.is_none());
+        assert_eq!(response.result, Some(json!(null)));
+        assert_eq!(
+            error_code(lifecycle.step(hover())),
+            ErrorCode::InvalidRequest as i32
+        );
+        assert!(matches!(
+            lifecycle.step(notification("exit")),
+            Step::Exit(0)
+        ));
+    }
+
+    #[test]
+    fn test_exit_without_shutdown() {
+        let mut lifecycle = Lifecycle::Running;
+        assert!(matches!(
+            lifecycle.step(notification("exit")),
+            Step::Exit(1)
+        ));
+        assert!(matches!(
+            Lifecycle::default().step(notification("exit")),
+            Step::Exit(1)
+        ));
+    }
+}
@@ -0,0 +1,271 @@
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crossbeam_channel::Sender;
+use log::{LevelFilter, Log, Metadata, Record};
+use lsp_server::Message;
+use serde::Deserialize;
+
+use self::backends::{Backend, FileBackend, LspBackend, SocketBackend, StderrBackend};
+pub use self::protocol::SocketProtocol;
+pub use self::span::request_span;
+mod backends;
+mod protocol;
+mod span;
+
+//Where log records go. Stderr is safe next to the protocol on stdout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    #[default]
+    Stderr,
+    //A log file that is rotated when it gets too big
+    File,
+    //window/logMessage, shown in the output panel of the client
+    Lsp,
+    //debug_tools/log.py
+    Socket,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "stderr" => Ok(BackendKind::Stderr),
+            "file" => Ok(BackendKind::File),
+            "lsp" => Ok(BackendKind::Lsp),
+            "socket" => Ok(BackendKind::Socket),
+            _ => Err(format!("Unknown log backend {s:?}")),
+        }
+    }
+}
+
+//The "log" object of initializationOptions. The IEF_LSP_LOG variables win over
+//it so logging can be changed without touching the client configuration
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct LogSettings {
+    pub backend: BackendKind,
+    //A default level and levels per module, like "info,ief_lsp::workspace=debug"
+    pub level: String,
+    pub file: PathBuf,
+    //Bytes written to the log file before it is rotated
+    pub max_file_size: u64,
+    pub socket: PathBuf,
+    //1 for readers that only know the message lines
+    pub socket_protocol: SocketProtocol,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            backend: BackendKind::default(),
+            level: String::from("info"),
+            file: env::temp_dir().join("ief_lsp.log"),
+            max_file_size: 10 * 1024 * 1024,
+            socket: PathBuf::from("/tmp/debug.socket"),
+            socket_protocol: SocketProtocol::default(),
+        }
+    }
+}
+
+impl LogSettings {
+    pub fn with_env(mut self) -> Self {
+        if let Ok(level) = env::var("IEF_LSP_LOG") {
+            self.level = level;
+        }
+        if let Ok(backend) = env::var("IEF_LSP_LOG_BACKEND") {
+            match backend.parse() {
+                Ok(backend) => self.backend = backend,
+                Err(e) => eprintln!("IEF_LSP_LOG_BACKEND: {e}"),
+            }
+        }
+        if let Ok(file) = env::var("IEF_LSP_LOG_FILE") {
+            self.file = PathBuf::from(file);
+        }
+        if let Ok(version) = env::var("IEF_LSP_LOG_PROTOCOL") {
+            match version.parse::<u8>().map_err(|e| e.to_string()) {
+                Ok(version) => match SocketProtocol::try_from(version) {
+                    Ok(protocol) => self.socket_protocol = protocol,
+                    Err(e) => eprintln!("IEF_LSP_LOG_PROTOCOL: {e}"),
+                },
+                Err(e) => eprintln!("IEF_LSP_LOG_PROTOCOL: {e}"),
+            }
+        }
+        self
+    }
+}
+
+//Levels per module, the most specific module wins
+#[derive(Debug, Clone, PartialEq)]
+struct LevelFilters {
+    default: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LevelFilters {
+    //Unknown levels are reported and skipped, logging is not set up yet
+    fn parse(spec: &str) -> Self {
+        let mut filters = LevelFilters {
+            default: LevelFilter::Info,
+            modules: vec![],
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let (module, level) = match directive.split_once('=') {
+                Some((module, level)) => (Some(module.trim()), level.trim()),
+                None => (None, directive),
+            };
+            let Ok(level) = LevelFilter::from_str(level) else {
+                eprintln!("Ignoring log level {directive:?}");
+                continue;
+            };
+            match module {
+                Some(module) => filters.modules.push((String::from(module), level)),
+                None => filters.default = level,
+            }
+        }
+        filters
+    }
+
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target == module
+                    || target
+                        .strip_prefix(module.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+struct Logger {
+    state: RwLock<Option<(LevelFilters, Box<dyn Backend>)>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match &*self.state.read().unwrap() {
+            Some((filters, _)) => metadata.level() <= filters.level_for(metadata.target()),
+            None => false,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some((filters, backend)) = &*self.state.read().unwrap() {
+            if record.level() <= filters.level_for(record.target()) {
+                backend.write(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some((_, backend)) = &*self.state.read().unwrap() {
+            backend.flush();
+        }
+    }
+}
+
+static LOGGER: Logger = Logger {
+    state: RwLock::new(None),
+};
+
+fn backend(settings: &LogSettings, client: Option<Sender<Message>>) -> Box<dyn Backend> {
+    match (settings.backend, client) {
+        (BackendKind::Stderr, _) => Box::new(StderrBackend),
+        (BackendKind::File, _) => match FileBackend::open(&settings.file, settings.max_file_size) {
+            Ok(backend) => Box::new(backend),
+            Err(e) => {
+                eprintln!("Could not open log file {:?}: {e}", settings.file);
+                Box::new(StderrBackend)
+            }
+        },
+        (BackendKind::Lsp, Some(client)) => Box::new(LspBackend::new(client)),
+        //Nothing to send window/logMessage through
+        (BackendKind::Lsp, None) => Box::new(StderrBackend),
+        (BackendKind::Socket, _) => Box::new(SocketBackend::new(
+            settings.socket.clone(),
+            settings.socket_protocol,
+            Duration::from_secs(5),
+        )),
+    }
+}
+
+//Replaces where records go and which are kept. The first call installs the
+//logger, later ones apply what the client asked for in initialize
+pub fn configure(settings: &LogSettings, client: Option<Sender<Message>>) {
+    let filters = LevelFilters::parse(&settings.level);
+    log::set_max_level(filters.max());
+    let backend = backend(settings, client);
+    if let Some((_, old)) = LOGGER.state.write().unwrap().replace((filters, backend)) {
+        old.flush();
+    }
+    let _ = log::set_logger(&LOGGER);
+}
+
+//Lets go of the client connection so its writer thread can finish
+pub fn shutdown() {
+    if let Some((_, backend)) = &mut *LOGGER.state.write().unwrap() {
+        backend.flush();
+        *backend = Box::new(StderrBackend);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use log::LevelFilter;
+
+    use super::{BackendKind, LevelFilters, LogSettings, SocketProtocol};
+
+    #[test]
+    fn test_level_filters() {
+        let filters = LevelFilters::parse(
+            "warn, ief_lsp::workspace=debug,ief_lsp::workspace::sync=off,lsp_server=nonsense",
+        );
+        assert_eq!(filters.level_for("ief_lsp"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("ief_lsp::workspace"), LevelFilter::Debug);
+        assert_eq!(
+            filters.level_for("ief_lsp::workspace::index"),
+            LevelFilter::Debug
+        );
+        assert_eq!(
+            filters.level_for("ief_lsp::workspace::sync"),
+            LevelFilter::Off
+        );
+        //Module boundaries, not string prefixes
+        assert_eq!(filters.level_for("ief_lsp::workspaces"), LevelFilter::Warn);
+        assert_eq!(filters.level_for("lsp_server"), LevelFilter::Warn);
+        assert_eq!(filters.max(), LevelFilter::Debug);
+        assert_eq!(LevelFilters::parse("").level_for("any"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn test_settings_from_initialization_options() {
+        let settings: LogSettings = serde_json::from_value(serde_json::json!({
+            "backend": "file",
+            "level": "debug",
+            "maxFileSize": 1024,
+            "socketProtocol": 1,
+        }))
+        .unwrap();
+        assert_eq!(settings.backend, BackendKind::File);
+        assert_eq!(settings.max_file_size, 1024);
+        assert_eq!(settings.socket, LogSettings::default().socket);
+        assert_eq!(settings.socket_protocol, SocketProtocol::V1);
+        assert_eq!("Socket".parse(), Ok(BackendKind::Socket));
+        assert!("syslog".parse::<BackendKind>().is_err());
+    }
+}
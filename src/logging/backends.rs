@@ -0,0 +1,280 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::Sender;
+use log::{Level, Record};
+use lsp_server::{Message, Notification};
+use lsp_types::notification::{LogMessage, Notification as _};
+use lsp_types::{LogMessageParams, MessageType};
+
+use super::protocol::{encode, SocketProtocol};
+
+//Backends can not log themselves, problems go to stderr
+pub trait Backend: Send + Sync {
+    fn write(&self, record: &Record);
+    fn flush(&self) {}
+}
+
+fn line(record: &Record) -> String {
+    format!("[{} {}] {}", record.level(), record.target(), record.args())
+}
+
+pub struct StderrBackend;
+
+impl Backend for StderrBackend {
+    fn write(&self, record: &Record) {
+        eprintln!("{}", line(record));
+    }
+}
+
+//Rotated log files kept next to the current one, <file>.1 is the newest
+const ROTATED_FILES: usize = 3;
+
+pub struct FileBackend {
+    path: PathBuf,
+    max_size: u64,
+    //The open file and how much is in it
+    file: Mutex<(File, u64)>,
+}
+
+impl FileBackend {
+    pub fn open(path: &Path, max_size: u64) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(FileBackend {
+            path: path.to_path_buf(),
+            max_size,
+            file: Mutex::new((file, size)),
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&self) -> io::Result<File> {
+        for n in (1..ROTATED_FILES).rev() {
+            let from = self.rotated(n);
+            if from.exists() {
+                fs::rename(from, self.rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+    }
+}
+
+impl Backend for FileBackend {
+    fn write(&self, record: &Record) {
+        let line = line(record) + "\n";
+        let mut file = self.file.lock().unwrap();
+        if file.1 > 0 && file.1 + line.len() as u64 > self.max_size {
+            match self.rotate() {
+                Ok(rotated) => *file = (rotated, 0),
+                Err(e) => eprintln!("Could not rotate {:?}: {e}", self.path),
+            }
+        }
+        match file.0.write_all(line.as_bytes()) {
+            Ok(()) => file.1 += line.len() as u64,
+            Err(e) => eprintln!("Could not write to {:?}: {e}", self.path),
+        }
+    }
+
+    fn flush(&self) {
+        let _ = self.file.lock().unwrap().0.flush();
+    }
+}
+
+pub struct LspBackend {
+    client: Sender<Message>,
+}
+
+impl LspBackend {
+    pub fn new(client: Sender<Message>) -> Self {
+        LspBackend { client }
+    }
+}
+
+impl Backend for LspBackend {
+    fn write(&self, record: &Record) {
+        //lsp-server logs every message it writes, sending those would never end
+        if record.target().starts_with("lsp_server") {
+            return;
+        }
+        let typ = match record.level() {
+            Level::Error => MessageType::ERROR,
+            Level::Warn => MessageType::WARNING,
+            Level::Info => MessageType::INFO,
+            Level::Debug | Level::Trace => MessageType::LOG,
+        };
+        let params = LogMessageParams {
+            typ,
+            message: format!("[{}] {}", record.target(), record.args()),
+        };
+        let _ = self.client.send(Message::Notification(Notification::new(
+            String::from(LogMessage::METHOD),
+            params,
+        )));
+    }
+}
+
+struct Connection {
+    stream: Option<UnixStream>,
+    //When to try connecting again after the listener was not there
+    retry_at: Option<Instant>,
+    //Whether it was reported that the listener is gone
+    reported: bool,
+}
+
+//debug_tools/log.py over one connection. When the listener is not
+//running records are dropped and connecting is retried now and then
+pub struct SocketBackend {
+    path: PathBuf,
+    protocol: SocketProtocol,
+    retry: Duration,
+    connection: Mutex<Connection>,
+}
+
+impl SocketBackend {
+    pub fn new(path: PathBuf, protocol: SocketProtocol, retry: Duration) -> Self {
+        SocketBackend {
+            path,
+            protocol,
+            retry,
+            connection: Mutex::new(Connection {
+                stream: None,
+                retry_at: None,
+                reported: false,
+            }),
+        }
+    }
+
+    fn disconnected(&self, connection: &mut Connection, e: io::Error) {
+        if !connection.reported {
+            eprintln!("Logging to {:?} stopped: {e}", self.path);
+            connection.reported = true;
+        }
+        connection.stream = None;
+        connection.retry_at = Some(Instant::now() + self.retry);
+    }
+}
+
+impl Backend for SocketBackend {
+    fn write(&self, record: &Record) {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.stream.is_none() {
+            if connection.retry_at.is_some_and(|at| Instant::now() < at) {
+                return;
+            }
+            match UnixStream::connect(&self.path) {
+                Ok(stream) => {
+                    //A stuck listener must not stall the server
+                    let _ = stream.set_write_timeout(Some(Duration::from_millis(100)));
+                    connection.stream = Some(stream);
+                    connection.reported = false;
+                }
+                Err(e) => return self.disconnected(&mut connection, e),
+            }
+        }
+        let message = encode(self.protocol, record);
+        if let Some(stream) = &mut connection.stream {
+            if let Err(e) = stream.write_all(message.as_bytes()) {
+                self.disconnected(&mut connection, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+    use std::time::Duration;
+
+    use crossbeam_channel::unbounded;
+    use log::{Level, Record};
+    use lsp_server::Message;
+
+    use super::{Backend, FileBackend, LspBackend, SocketBackend, SocketProtocol};
+
+    fn write(backend: &dyn Backend, target: &str, message: &str) {
+        backend.write(
+            &Record::builder()
+                .args(format_args!("{message}"))
+                .level(Level::Info)
+                .target(target)
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_file_rotation() {
+        let dir = std::env::temp_dir().join(format!("ief_lsp_log_{}", std::process::id()));
+        let path = dir.join("ief_lsp.log");
+        let backend = FileBackend::open(&path, 64).unwrap();
+        for n in 0..10 {
+            write(&backend, "ief_lsp", &format!("record {n}"));
+        }
+        backend.flush();
+        let current = fs::read_to_string(&path).unwrap();
+        assert!(current.ends_with("[INFO ief_lsp] record 9\n"));
+        assert!(current.len() <= 64);
+        assert!(fs::read_to_string(dir.join("ief_lsp.log.1"))
+            .unwrap()
+            .contains("record 7"));
+        assert!(dir.join("ief_lsp.log.3").exists());
+        assert!(!dir.join("ief_lsp.log.4").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lsp_log_message() {
+        let (client, received) = unbounded();
+        let backend = LspBackend::new(client);
+        write(&backend, "lsp_server::msg", "> {}");
+        write(&backend, "ief_lsp::workspace", "Loaded");
+        let Ok(Message::Notification(notification)) = received.try_recv() else {
+            panic!("Expected window/logMessage");
+        };
+        assert_eq!(notification.method, "window/logMessage");
+        assert_eq!(
+            notification.params["message"],
+            "[ief_lsp::workspace] Loaded"
+        );
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_socket_reconnects() {
+        let path = std::env::temp_dir().join(format!("ief_lsp_socket_{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let backend = SocketBackend::new(path.clone(), SocketProtocol::V1, Duration::ZERO);
+        //Nobody listening yet, dropped without a panic
+        write(&backend, "ief_lsp", "lost");
+
+        let listener = UnixListener::bind(&path).unwrap();
+        write(&backend, "ief_lsp", "first");
+        write(&backend, "ief_lsp", "second");
+        let (stream, _) = listener.accept().unwrap();
+        let lines: Vec<String> = BufReader::new(stream)
+            .lines()
+            .take(4)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(lines, vec!["1", "first", "1", "second"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
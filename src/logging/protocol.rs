@@ -0,0 +1,143 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::kv::{self, Key, VisitSource};
+use log::Record;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use super::span;
+
+//What goes over the debug socket. Both versions send a line with the version
+//and then one line for the record, so a reader can tell them apart per record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Default)]
+#[serde(try_from = "u8")]
+pub enum SocketProtocol {
+    //The message only, with line breaks escaped so it stays on its line
+    V1,
+    //A JSON object with the level, target, timestamp in milliseconds since the
+    //epoch, message, the request being handled and any key-values
+    #[default]
+    V2,
+}
+
+impl TryFrom<u8> for SocketProtocol {
+    type Error = String;
+
+    fn try_from(version: u8) -> Result<Self, Self::Error> {
+        match version {
+            1 => Ok(SocketProtocol::V1),
+            2 => Ok(SocketProtocol::V2),
+            _ => Err(format!("Unknown log protocol version {version}")),
+        }
+    }
+}
+
+//Key-values of a record, like the timings of a request span
+struct Fields(Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let value = if let Some(n) = value.to_u64() {
+            json!(n)
+        } else if let Some(n) = value.to_i64() {
+            json!(n)
+        } else if let Some(n) = value.to_f64() {
+            json!(n)
+        } else if let Some(b) = value.to_bool() {
+            json!(b)
+        } else {
+            json!(value.to_string())
+        };
+        self.0.insert(key.to_string(), value);
+        Ok(())
+    }
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
+
+pub fn encode(protocol: SocketProtocol, record: &Record) -> String {
+    match protocol {
+        SocketProtocol::V1 => {
+            let message = record.args().to_string().replace('\n', "\\n");
+            format!("1\n{message}\n")
+        }
+        SocketProtocol::V2 => {
+            let mut object = json!({
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "timestamp": timestamp(),
+                "message": record.args().to_string(),
+            });
+            if let Some(span) = span::current() {
+                object["request"] = json!(span.request);
+                object["method"] = json!(span.method);
+            }
+            let mut fields = Fields(Map::new());
+            let _ = record.key_values().visit(&mut fields);
+            if !fields.0.is_empty() {
+                object["fields"] = Value::Object(fields.0);
+            }
+            format!("2\n{object}\n")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use log::{Level, Record};
+    use serde_json::Value;
+
+    use super::{encode, SocketProtocol};
+    use crate::logging::span::request_span;
+
+    #[test]
+    fn test_encode() {
+        let message = "TextSync {\n    rope: \"<TrustFrameworkPolicy />\"\n}";
+        let record = |protocol| {
+            encode(
+                protocol,
+                &Record::builder()
+                    .args(format_args!("{message}"))
+                    .level(Level::Debug)
+                    .target("ief_lsp::workspace::sync")
+                    .key_values(&[("version", 3)])
+                    .build(),
+            )
+        };
+        let v1 = record(SocketProtocol::V1);
+        assert_eq!(v1.lines().count(), 2);
+        assert_eq!(
+            v1,
+            "1\nTextSync {\\n    rope: \"<TrustFrameworkPolicy />\"\\n}\n"
+        );
+
+        let _span = request_span(&12, "textDocument/hover", Instant::now());
+        let v2 = record(SocketProtocol::V2);
+        let (version, json) = v2.split_once('\n').unwrap();
+        assert_eq!(version, "2");
+        assert_eq!(json.lines().count(), 1);
+        let object: Value = serde_json::from_str(json).unwrap();
+        assert_eq!(object["message"], message);
+        assert_eq!(object["level"], "DEBUG");
+        assert_eq!(object["target"], "ief_lsp::workspace::sync");
+        assert_eq!(object["request"], "12");
+        assert_eq!(object["method"], "textDocument/hover");
+        assert_eq!(object["fields"]["version"], 3);
+        assert!(object["timestamp"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn test_protocol_setting() {
+        assert_eq!(
+            serde_json::from_value::<SocketProtocol>(serde_json::json!(1)).unwrap(),
+            SocketProtocol::V1
+        );
+        assert!(serde_json::from_value::<SocketProtocol>(serde_json::json!(3)).is_err());
+    }
+}
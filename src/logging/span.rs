@@ -0,0 +1,85 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::time::Instant;
+
+use log::info;
+
+//The request a thread is working on. Records logged meanwhile carry its id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub request: String,
+    pub method: String,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Span>> = const { RefCell::new(None) };
+}
+
+pub fn current() -> Option<Span> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+//Logs how long the request waited and ran when it is dropped
+pub struct SpanGuard {
+    queued: Instant,
+    started: Instant,
+    previous: Option<Span>,
+}
+
+pub fn request_span(request: &impl Display, method: &str, queued: Instant) -> SpanGuard {
+    let span = Span {
+        request: request.to_string(),
+        method: String::from(method),
+    };
+    SpanGuard {
+        queued,
+        started: Instant::now(),
+        previous: CURRENT.with(|current| current.replace(Some(span))),
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        let elapsed_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        let queued_ms = (self.started - self.queued).as_secs_f64() * 1000.0;
+        if let Some(span) = current() {
+            info!(
+                elapsed_ms = elapsed_ms, queued_ms = queued_ms;
+                "{} {} took {elapsed_ms:.1} ms", span.method, span.request
+            );
+        }
+        CURRENT.with(|current| *current.borrow_mut() = self.previous.take());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Instant;
+
+    use lsp_server::RequestId;
+
+    use super::{current, request_span, Span};
+
+    #[test]
+    fn test_nested_spans() {
+        assert_eq!(current(), None);
+        let outer = request_span(&RequestId::from(7), "textDocument/hover", Instant::now());
+        {
+            let _inner = request_span(
+                &RequestId::from(String::from("inner")),
+                "workspace/symbol",
+                Instant::now(),
+            );
+            assert_eq!(current().unwrap().method, "workspace/symbol");
+        }
+        assert_eq!(
+            current(),
+            Some(Span {
+                request: String::from("7"),
+                method: String::from("textDocument/hover"),
+            })
+        );
+        drop(outer);
+        assert_eq!(current(), None);
+    }
+}
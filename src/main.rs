@@ -1,71 +1,79 @@
-use log::{error, info, warn, LevelFilter, Log};
+use error::ServerError;
+use lifecycle::{Lifecycle, Step};
+use log::{error, info, warn};
+use logging::LogSettings;
 use lsp_server::{
-    Connection, ExtractError, Message, Notification, Request, RequestId, Response, ResponseError,
+    Connection, ErrorCode, ExtractError, Message, Notification, Request, RequestId, Response,
+};
+use lsp_types::notification::{
+    Cancel, DidChangeConfiguration, DidChangeTextDocument, DidChangeWatchedFiles,
+    DidChangeWorkspaceFolders, DidCloseTextDocument, DidOpenTextDocument, Initialized,
+    Notification as _, PublishDiagnostics,
+};
+use lsp_types::request::{
+    Completion, DocumentDiagnosticRequest, DocumentSymbolRequest, GotoDeclaration, GotoDefinition,
+    GotoImplementation, HoverRequest, PrepareRenameRequest, References, RegisterCapability, Rename,
+    Request as _, WorkspaceConfiguration, WorkspaceSymbolRequest,
 };
 use lsp_types::{
-    notification, Diagnostic, DiagnosticOptions, DiagnosticServerCapabilities, OneOf, Position,
-    TextDocumentIdentifier,
+    ClientCapabilities, CompletionOptions, CompletionResponse, ConfigurationItem,
+    ConfigurationParams, DiagnosticOptions, DiagnosticServerCapabilities,
+    DidChangeWatchedFilesRegistrationOptions, DocumentSymbolResponse, FileSystemWatcher,
+    GlobPattern, GotoDefinitionResponse, HoverProviderCapability, ImplementationProviderCapability,
+    NumberOrString, OneOf, PrepareRenameResponse, Registration, RegistrationParams, RenameOptions,
+    WorkspaceSymbolResponse,
 };
 use lsp_types::{
-    DocumentDiagnosticReport, DocumentDiagnosticReportKind, FullDocumentDiagnosticReport,
-    InitializeParams, PublishDiagnosticsParams, RelatedFullDocumentDiagnosticReport,
-    ServerCapabilities, TextDocumentContentChangeEvent, TextDocumentSyncCapability,
-    TextDocumentSyncKind, Url, VersionedTextDocumentIdentifier,
+    DocumentDiagnosticReport, DocumentDiagnosticReportKind, DocumentDiagnosticReportResult,
+    FullDocumentDiagnosticReport, InitializeParams, InitializeResult, Position,
+    PublishDiagnosticsParams, RelatedFullDocumentDiagnosticReport, ServerCapabilities,
+    TextDocumentSyncCapability, TextDocumentSyncKind, Url, WorkspaceFoldersServerCapabilities,
+    WorkspaceServerCapabilities,
 };
-use serde::{Deserialize, Serialize};
-use serde_json::json;
-use std::collections::HashMap;
-use std::error::Error;
-use std::fmt::{Debug, Display};
-use std::io::Write;
-use std::os::unix::net::UnixStream;
-use std::str::FromStr;
-use workspace::IEF_Workspace;
+use scheduler::Scheduler;
+use serde::de::DeserializeOwned;
+use settings::Settings;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::thread;
+use workspace::{IEF_Workspace, PositionEncoding, WorkspaceFolders};
+mod error;
+mod lifecycle;
+mod logging;
+mod scheduler;
+mod settings;
 mod workspace;
 
-struct SocketLogger {}
-
-impl SocketLogger {
-    fn socket(&self) -> UnixStream {
-        return UnixStream::connect("/tmp/debug.socket").unwrap();
-    }
-}
-
-impl Log for SocketLogger {
-    fn enabled(&self, metadata: &log::Metadata) -> bool {
-        true
-    }
-    fn log(&self, record: &log::Record) {
-        let protocol_version = 1;
-        let s = format!("{}\n{}\n", protocol_version, record.args());
-        let mut sock = self.socket();
-        sock.write_all(s.as_bytes());
-        sock.flush();
-    }
-    fn flush(&self) {}
-}
-
-const fn init_logger() -> SocketLogger {
-    SocketLogger {}
-}
-
-#[derive(Debug)]
-struct ServerError {
-    msg: String,
-}
-impl Error for ServerError {}
-impl Display for ServerError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str("Server Error: ");
-        f.write_str(self.msg.as_str())
+fn main() -> ExitCode {
+    let (connection, io_threads) = Connection::stdio();
+    logging::configure(
+        &LogSettings::default().with_env(),
+        Some(connection.sender.clone()),
+    );
+    info!("Starting IEF_LSP V2! :)");
+    let mut lifecycle = Lifecycle::default();
+    let code = match initialize(&connection, &mut lifecycle) {
+        Some((params, encoding, settings)) => {
+            main_loop(connection, lifecycle, *params, encoding, settings)
+        }
+        None => {
+            drop(connection);
+            1
+        }
+    };
+    logging::shutdown();
+    if io_threads.join().is_err() {
+        error!("Threads are frayed");
     }
+    //SHut down
+    info!("IEF_LSP V2 Stopped with {code} :(");
+    ExitCode::from(code)
 }
 
-const LOGGER: SocketLogger = init_logger();
-fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
-    let (connection, io_threads) = Connection::stdio();
-    log::set_logger(&LOGGER).map(|()| log::set_max_level(LevelFilter::Info));
-    let server_capabilities = serde_json::to_value(&ServerCapabilities {
+fn capabilities(encoding: PositionEncoding) -> ServerCapabilities {
+    ServerCapabilities {
+        position_encoding: Some(encoding.kind()),
         definition_provider: Some(OneOf::Left(true)),
         diagnostic_provider: Some(DiagnosticServerCapabilities::Options(DiagnosticOptions {
             identifier: None,
@@ -77,160 +85,780 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
         })),
         declaration_provider: Some(lsp_types::DeclarationCapability::Simple(true)),
         references_provider: Some(OneOf::Left(true)),
+        implementation_provider: Some(ImplementationProviderCapability::Simple(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![String::from("\""), String::from("<")]),
+            ..Default::default()
+        }),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        workspace_symbol_provider: Some(OneOf::Left(true)),
+        rename_provider: Some(OneOf::Right(RenameOptions {
+            prepare_provider: Some(true),
+            work_done_progress_options: Default::default(),
+        })),
         text_document_sync: Some(TextDocumentSyncCapability::Kind(
             TextDocumentSyncKind::INCREMENTAL,
         )),
+        workspace: Some(WorkspaceServerCapabilities {
+            workspace_folders: Some(WorkspaceFoldersServerCapabilities {
+                supported: Some(true),
+                change_notifications: Some(OneOf::Left(true)),
+            }),
+            file_operations: None,
+        }),
         ..Default::default()
+    }
+}
+
+//Waits for initialize and answers it. None when the client exits or goes away
+//before that
+fn initialize(
+    connection: &Connection,
+    lifecycle: &mut Lifecycle,
+) -> Option<(Box<InitializeParams>, PositionEncoding, Settings)> {
+    for msg in &connection.receiver {
+        match lifecycle.step(msg) {
+            Step::Initialize(id, params) => {
+                //Shown once initialize is answered, the defaults are used till then
+                let (settings, problem) =
+                    match Settings::parse(params.initialization_options.clone()) {
+                        Ok(settings) => (settings.unwrap_or_default(), None),
+                        Err(e) => (Settings::default(), Some(e)),
+                    };
+                logging::configure(
+                    &settings.log.clone().with_env(),
+                    Some(connection.sender.clone()),
+                );
+                let encoding = PositionEncoding::negotiate(
+                    params
+                        .capabilities
+                        .general
+                        .as_ref()
+                        .and_then(|general| general.position_encodings.as_deref()),
+                );
+                info!("Using {encoding:?} positions");
+                let result = InitializeResult {
+                    capabilities: capabilities(encoding),
+                    server_info: None,
+                };
+                send(connection, Message::Response(Response::new_ok(id, result)));
+                if let Some(e) = problem {
+                    error!("{e}");
+                    send(connection, e.show_message());
+                }
+                return Some((params, encoding, settings));
+            }
+            Step::Reply(msg) => send(connection, msg),
+            Step::Exit(_) => return None,
+            Step::Handle(_) | Step::Ignore => {}
+        }
+    }
+    error!("Client went away before initialize");
+    None
+}
+
+fn send(connection: &Connection, msg: Message) {
+    if let Err(e) = connection.sender.send(msg) {
+        error!("Could not send message to client {e}");
+    }
+}
+
+fn cast<R>(req: Request) -> Result<(RequestId, R::Params), Message>
+where
+    R: lsp_types::request::Request,
+    R::Params: DeserializeOwned,
+{
+    let id = req.id.clone();
+    req.extract(R::METHOD).map_err(|e| {
+        let message = match e {
+            ExtractError::MethodMismatch(req) => format!("Unexpected method {}", req.method),
+            ExtractError::JsonError { method, error } => {
+                format!("Invalid params for {method}: {error}")
+            }
+        };
+        error!("{message}");
+        Message::Response(Response::new_err(
+            id,
+            ErrorCode::InvalidParams as i32,
+            message,
+        ))
     })
-    .unwrap();
-    info!("Starting IEF_LSP V2! :)");
-    let init_params = connection.initialize(server_capabilities).unwrap();
-    let _ = main_loop(connection, init_params);
-    io_threads.join().expect("Threads are frayed");
-    //SHut down
-    info!("IEF_LSP V2 Stopped :(");
-    Ok(())
 }
 
-fn handle_request(workspace: &mut IEF_Workspace, req: Request) -> Vec<Message> {
+//Whether the document of a request is loaded and its position is in it
+fn check_request(workspace: &IEF_Workspace, params: &serde_json::Value) -> Result<(), ServerError> {
+    let Some(document) = document_uri(params) else {
+        return Ok(());
+    };
+    let position = params
+        .get("position")
+        .and_then(|pos| serde_json::from_value::<Position>(pos.clone()).ok());
+    match position {
+        Some(pos) => workspace.check_position(&document, pos).map(|_| ()),
+        None => workspace.check_document(&document).map(|_| ()),
+    }
+}
+
+fn handle_request(workspace: &IEF_Workspace, req: Request) -> Vec<Message> {
     info!("Got request {:?}", req);
+    if let Err(e) = check_request(workspace, &req.params) {
+        error!("{} failed: {e}", req.method);
+        return vec![e.response(req.id)];
+    }
     match req.method.as_str() {
-        //This bit is ugly and I dont like it
+        "textDocument/definition" => {
+            let (id, params) = match cast::<GotoDefinition>(req) {
+                Ok(r) => r,
+                Err(mess) => return vec![mess],
+            };
+            let doc_pos = params.text_document_position_params;
+            let result = workspace
+                .find_definition(&doc_pos.text_document.uri, doc_pos.position)
+                .map(GotoDefinitionResponse::Scalar);
+            info!("Definition result {:?}", result);
+            return vec![Message::Response(Response::new_ok(id, result))];
+        }
+        "textDocument/declaration" => {
+            let (id, params) = match cast::<GotoDeclaration>(req) {
+                Ok(r) => r,
+                Err(mess) => return vec![mess],
+            };
+            let doc_pos = params.text_document_position_params;
+            let result = workspace
+                .find_declaration(&doc_pos.text_document.uri, doc_pos.position)
+                .map(GotoDefinitionResponse::Scalar);
+            info!("Declaration result {:?}", result);
+            return vec![Message::Response(Response::new_ok(id, result))];
+        }
+        "textDocument/implementation" => {
+            let (id, params) = match cast::<GotoImplementation>(req) {
+                Ok(r) => r,
+                Err(mess) => return vec![mess],
+            };
+            let doc_pos = params.text_document_position_params;
+            let result =
+                workspace.find_implementations(&doc_pos.text_document.uri, doc_pos.position);
+            info!("Implementation result {:?}", result);
+            return vec![Message::Response(Response::new_ok(
+                id,
+                Some(GotoDefinitionResponse::Array(result)),
+            ))];
+        }
+        "textDocument/completion" => {
+            let (id, params) = match cast::<Completion>(req) {
+                Ok(r) => r,
+                Err(mess) => return vec![mess],
+            };
+            let doc_pos = params.text_document_position;
+            let result = workspace.completions(&doc_pos.text_document.uri, doc_pos.position);
+            info!("Completion result {:?}", result);
+            return vec![Message::Response(Response::new_ok(
+                id,
+                Some(CompletionResponse::Array(result)),
+            ))];
+        }
+        "textDocument/hover" => {
+            let (id, params) = match cast::<HoverRequest>(req) {
+                Ok(r) => r,
+                Err(mess) => return vec![mess],
+            };
+            let doc_pos = params.text_document_position_params;
+            let result = workspace.hover(&doc_pos.text_document.uri, doc_pos.position);
+            info!("Hover result {:?}", result);
+            return vec![Message::Response(Response::new_ok(id, result))];
+        }
+        "textDocument/references" => {
+            let (id, params) = match cast::<References>(req) {
+                Ok(r) => r,
+                Err(mess) => return vec![mess],
+            };
+            let doc_pos = params.text_document_position;
+            let result = workspace.find_references(
+                &doc_pos.text_document.uri,
+                doc_pos.position,
+                params.context.include_declaration,
+            );
+            info!("References result {:?}", result);
+            return vec![Message::Response(Response::new_ok(id, Some(result)))];
+        }
+        "textDocument/documentSymbol" => {
+            let (id, params) = match cast::<DocumentSymbolRequest>(req) {
+                Ok(r) => r,
+                Err(mess) => return vec![mess],
+            };
+            let result = workspace.document_symbols(&params.text_document.uri);
+            info!("Document symbol result {:?}", result);
+            return vec![Message::Response(Response::new_ok(
+                id,
+                Some(DocumentSymbolResponse::Nested(result)),
+            ))];
+        }
+        "textDocument/prepareRename" => {
+            let (id, params) = match cast::<PrepareRenameRequest>(req) {
+                Ok(r) => r,
+                Err(mess) => return vec![mess],
+            };
+            let result = workspace
+                .prepare_rename(&params.text_document.uri, params.position)
+                .map(
+                    |(range, placeholder)| PrepareRenameResponse::RangeWithPlaceholder {
+                        range,
+                        placeholder,
+                    },
+                );
+            info!("Prepare rename result {:?}", result);
+            return vec![Message::Response(Response::new_ok(id, result))];
+        }
+        "textDocument/rename" => {
+            let (id, params) = match cast::<Rename>(req) {
+                Ok(r) => r,
+                Err(mess) => return vec![mess],
+            };
+            let doc_pos = params.text_document_position;
+            let result = workspace.rename(
+                &doc_pos.text_document.uri,
+                doc_pos.position,
+                &params.new_name,
+            );
+            info!("Rename result {:?}", result);
+            return match result {
+                Ok(edit) => vec![Message::Response(Response::new_ok(id, edit))],
+                Err(e) => vec![e.response(id)],
+            };
+        }
         "textDocument/diagnostic" => {
-            let doc_uri = match req.params.get("textDocument") {
-                Some(doc) => doc.get("uri"),
-                None => None,
+            let (id, params) = match cast::<DocumentDiagnosticRequest>(req) {
+                Ok(r) => r,
+                Err(mess) => return vec![mess],
             };
-            if doc_uri.is_none() {
-                return vec![Message::Response(Response {
-                    id: req.id,
-                    result: None,
-                    error: Some(ResponseError {
-                        code: 400,
-                        message: String::from("document uri is not defined"),
-                        data: None,
-                    }),
-                })];
+            let mut diagnostics = workspace.get_diagnostics();
+            let items = diagnostics
+                .remove(&params.text_document.uri)
+                .unwrap_or_default();
+            let report = |items| FullDocumentDiagnosticReport {
+                result_id: Some(id.to_string()),
+                items,
             };
-            let doc_uri = doc_uri.unwrap().as_str().unwrap().to_string();
-            let mut diagnostic_req_res = workspace.get_diagnostics();
-            let doc_diagnostics = diagnostic_req_res.remove(&doc_uri).unwrap_or(vec![]);
-            let mess = Message::Response(Response {
-                id: req.id.clone(),
-                result: Some(
-                    serde_json::to_value(DocumentDiagnosticReport::Full(
-                        RelatedFullDocumentDiagnosticReport {
-                            full_document_diagnostic_report: FullDocumentDiagnosticReport {
-                                result_id: Some(req.id.to_string().clone()),
-                                items: doc_diagnostics,
-                            },
-                            related_documents: Some(HashMap::from_iter(
-                                diagnostic_req_res.iter().map(|(uri_str, diag_vec)| {
-                                    (
-                                        Url::from_str(uri_str).unwrap(),
-                                        DocumentDiagnosticReportKind::Full(
-                                            FullDocumentDiagnosticReport {
-                                                result_id: Some(req.id.to_string().clone()),
-                                                items: diag_vec.to_owned(),
-                                            },
-                                        ),
-                                    )
-                                }),
-                            )),
-                        },
-                    ))
-                    .unwrap(),
-                ),
-                error: None,
-            });
-
-            info!("Diagnoistics req result {:?}", mess);
-            return vec![mess];
+            let related_documents = diagnostics
+                .into_iter()
+                .map(|(uri, items)| (uri, DocumentDiagnosticReportKind::Full(report(items))))
+                .collect();
+            let result = DocumentDiagnosticReportResult::Report(DocumentDiagnosticReport::Full(
+                RelatedFullDocumentDiagnosticReport {
+                    related_documents: Some(related_documents),
+                    full_document_diagnostic_report: report(items),
+                },
+            ));
+            info!("Diagnoistics req result {:?}", result);
+            return vec![Message::Response(Response::new_ok(id, result))];
         }
         _ => {
             info!("Unsupported method! {req:?}");
         }
     };
-    return vec![];
+    vec![Message::Response(Response::new_err(
+        req.id,
+        ErrorCode::MethodNotFound as i32,
+        format!("Unsupported method {}", req.method),
+    ))]
+}
+
+fn cast_notification<N>(not: Notification) -> Option<N::Params>
+where
+    N: lsp_types::notification::Notification,
+    N::Params: DeserializeOwned,
+{
+    not.extract(N::METHOD)
+        .map_err(|e| error!("Invalid params for {}: {e}", N::METHOD))
+        .ok()
+}
+
+//Diagnostics for every policy, tagged with the version of its open buffer
+fn publish_diagnostics(worksp: &IEF_Workspace) -> Vec<Message> {
+    worksp
+        .get_diagnostics()
+        .into_iter()
+        .map(|(uri, diagnostics)| PublishDiagnosticsParams {
+            version: worksp.document_version(&uri),
+            uri,
+            diagnostics,
+        })
+        .map(publish)
+        .collect()
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, Deserialize, Serialize)]
-#[serde(rename_all = "camelCase")]
-struct DocumentChangeNotification {
-    content_changes: Vec<TextDocumentContentChangeEvent>,
-    text_document: TextDocumentIdentifier,
+fn publish(diag_params: PublishDiagnosticsParams) -> Message {
+    Message::Notification(Notification::new(
+        String::from(PublishDiagnostics::METHOD),
+        diag_params,
+    ))
 }
+
 fn handle_notification(worksp: &mut IEF_Workspace, not: Notification) -> Vec<Message> {
     match not.method.as_str() {
         "textDocument/didSave" => {
-            let results: Vec<_> = worksp
-                .get_diagnostics()
-                .iter()
-                .map(|(uri, diags)| PublishDiagnosticsParams {
-                    uri: Url::from_str(uri).unwrap(),
-                    diagnostics: diags.to_owned(),
-                    version: None,
-                })
-                .map(|diag_params| {
-                    Message::Notification(Notification {
-                        method: String::from("textDocument/publishDiagnostics"),
-                        params: serde_json::to_value(diag_params).unwrap(),
-                    })
-                })
-                .collect();
+            let results = publish_diagnostics(worksp);
             info!("Save diagnostics results: {:?}", results);
             return results;
         }
-        "textDocument/didClose" => info!("{:?}", not.method),
-        "textDocument/didOpen" => info!("{:?}", not.method),
+        "textDocument/didOpen" => {
+            let Some(params) = cast_notification::<DidOpenTextDocument>(not) else {
+                return vec![];
+            };
+            let document = params.text_document;
+            let mut messages = vec![];
+            if let Err(e) = worksp.open_document(document.uri, document.text, document.version) {
+                error!("Could not open document {e}");
+                messages.push(e.show_message());
+            }
+            messages.extend(publish_diagnostics(worksp));
+            return messages;
+        }
+        "textDocument/didClose" => {
+            let Some(params) = cast_notification::<DidCloseTextDocument>(not) else {
+                return vec![];
+            };
+            let uri = params.text_document.uri;
+            let tracked = worksp.close_document(&uri);
+            let mut messages = publish_diagnostics(worksp);
+            if !tracked {
+                //Nothing left to report on, clear what the client still shows
+                messages.push(publish(PublishDiagnosticsParams {
+                    uri,
+                    diagnostics: vec![],
+                    version: None,
+                }));
+            }
+            return messages;
+        }
         "textDocument/didChange" => {
             info!("{:?}", not);
-            let edit_param: DocumentChangeNotification =
-                serde_json::from_value(not.params).unwrap();
-            worksp.update_document(edit_param.text_document.uri, edit_param.content_changes);
+            let Some(edit_param) = cast_notification::<DidChangeTextDocument>(not) else {
+                return vec![];
+            };
+            if let Err(e) = worksp.update_document(
+                edit_param.text_document.uri,
+                edit_param.text_document.version,
+                edit_param.content_changes,
+            ) {
+                error!("Could not update document {e}");
+                return vec![e.show_message()];
+            }
         }
         _ => info!("Method not implemented {:?}", not.method),
     }
-    return vec![];
+    vec![]
+}
+
+//The textDocument.uri of a request or notification, used to pick its folder
+fn document_uri(params: &serde_json::Value) -> Option<Url> {
+    params
+        .pointer("/textDocument/uri")
+        .and_then(|uri| uri.as_str())
+        .and_then(|uri| Url::parse(uri).ok())
+}
+
+fn workspace_symbols(folders: &[Arc<IEF_Workspace>], req: Request) -> Vec<Message> {
+    let (id, params) = match cast::<WorkspaceSymbolRequest>(req) {
+        Ok(r) => r,
+        Err(mess) => return vec![mess],
+    };
+    let result: Vec<_> = folders
+        .iter()
+        .flat_map(|workspace| workspace.workspace_symbols(&params.query))
+        .collect();
+    info!("Workspace symbol result {:?}", result);
+    vec![Message::Response(Response::new_ok(
+        id,
+        Some(WorkspaceSymbolResponse::Flat(result)),
+    ))]
+}
+
+//Diagnostics and load problems of the added folders, with the documents of the
+//removed folders cleared unless another folder still has them
+fn change_folders(folders: &mut WorkspaceFolders, not: Notification) -> Vec<Message> {
+    let Some(params) = cast_notification::<DidChangeWorkspaceFolders>(not) else {
+        return vec![];
+    };
+    let mut removed: Vec<Url> = vec![];
+    for folder in params.event.removed {
+        match folder.uri.to_file_path() {
+            Ok(path) => removed.extend(folders.remove(&path)),
+            Err(_) => error!("Not a folder {}", folder.uri),
+        }
+    }
+    let mut messages: Vec<Message> = vec![];
+    for folder in params.event.added {
+        match folder.uri.to_file_path() {
+            Ok(path) => {
+                let workspace = folders.add(&path.to_string_lossy());
+                messages.extend(publish_diagnostics(workspace));
+                messages.extend(
+                    workspace
+                        .take_problems()
+                        .iter()
+                        .map(ServerError::show_message),
+                );
+            }
+            Err(_) => error!("Not a folder {}", folder.uri),
+        }
+    }
+    let kept: Vec<Url> = folders.iter().flat_map(IEF_Workspace::documents).collect();
+    messages.extend(
+        removed
+            .into_iter()
+            .filter(|uri| !kept.contains(uri))
+            .map(clear_diagnostics),
+    );
+    messages
+}
+
+fn clear_diagnostics(uri: Url) -> Message {
+    publish(PublishDiagnosticsParams {
+        uri,
+        diagnostics: vec![],
+        version: None,
+    })
+}
+
+//What the client shows for a folder after files changed on disk, with the
+//documents that are gone cleared
+fn watched_files_changed(
+    folders: &mut WorkspaceFolders,
+    settings: &Settings,
+    not: Notification,
+) -> Vec<Message> {
+    let Some(params) = cast_notification::<DidChangeWatchedFiles>(not) else {
+        return vec![];
+    };
+    let mut touched: Vec<PathBuf> = vec![];
+    let mut removed: Vec<Url> = vec![];
+    let mut problems: Vec<ServerError> = vec![];
+    for change in params.changes {
+        let path = change.uri.to_file_path().unwrap_or_default();
+        if let Some(workspace) = folders
+            .iter()
+            .find(|workspace| settings.is_app_settings(workspace.root(), &path))
+        {
+            info!("Settings changed {}", change.uri);
+            problems.extend(settings.check_environment(workspace.root()).err());
+            continue;
+        }
+        let Some(workspace) = folders.for_document(Some(&change.uri)) else {
+            continue;
+        };
+        removed.extend(workspace.file_changed(&change.uri));
+        if !touched.iter().any(|root| root == workspace.root()) {
+            touched.push(workspace.root().to_path_buf());
+        }
+    }
+    let mut messages: Vec<Message> = folders
+        .iter()
+        .filter(|workspace| touched.iter().any(|root| root == workspace.root()))
+        .flat_map(publish_diagnostics)
+        .collect();
+    messages.extend(removed.into_iter().map(clear_diagnostics));
+    messages.extend(problems.iter().map(ServerError::show_message));
+    messages
+}
+
+//Asks the client to report changes to policies and settings made outside the
+//editor, like a git checkout, and changes to the configuration
+fn register_capabilities(
+    connection: &Connection,
+    capabilities: &ClientCapabilities,
+    settings: &Settings,
+) {
+    let workspace = capabilities.workspace.as_ref();
+    let mut registrations = vec![];
+    if workspace
+        .and_then(|workspace| workspace.did_change_watched_files)
+        .and_then(|watched| watched.dynamic_registration)
+        .unwrap_or(false)
+    {
+        let app_settings = settings
+            .app_settings
+            .file_name()
+            .map_or(String::from("appsettings.json"), |name| {
+                name.to_string_lossy().into_owned()
+            });
+        let watchers = [String::from("**/*.xml"), format!("**/{app_settings}")]
+            .map(|glob| FileSystemWatcher {
+                glob_pattern: GlobPattern::String(glob),
+                kind: None,
+            })
+            .to_vec();
+        registrations.push(Registration {
+            id: String::from(DidChangeWatchedFiles::METHOD),
+            method: String::from(DidChangeWatchedFiles::METHOD),
+            register_options: serde_json::to_value(DidChangeWatchedFilesRegistrationOptions {
+                watchers,
+            })
+            .ok(),
+        });
+    } else {
+        warn!("Client can not watch files for us");
+    }
+    if workspace
+        .and_then(|workspace| workspace.did_change_configuration)
+        .and_then(|configuration| configuration.dynamic_registration)
+        .unwrap_or(false)
+    {
+        registrations.push(Registration {
+            id: String::from(DidChangeConfiguration::METHOD),
+            method: String::from(DidChangeConfiguration::METHOD),
+            register_options: Some(serde_json::json!({ "section": settings::SECTION })),
+        });
+    }
+    if registrations.is_empty() {
+        return;
+    }
+    let request = Request::new(
+        RequestId::from(String::from("register-capabilities")),
+        String::from(RegisterCapability::METHOD),
+        RegistrationParams { registrations },
+    );
+    if let Err(e) = connection.sender.send(Message::Request(request)) {
+        error!("Could not register capabilities {e}");
+    }
+}
+
+//Asks for the settings with workspace/configuration if the client has them.
+//The answer is matched by the returned id
+fn pull_settings(
+    connection: &Connection,
+    capabilities: &ClientCapabilities,
+    count: &mut u32,
+) -> Option<RequestId> {
+    let supported = capabilities
+        .workspace
+        .as_ref()
+        .and_then(|workspace| workspace.configuration)
+        .unwrap_or(false);
+    if !supported {
+        return None;
+    }
+    *count += 1;
+    let id = RequestId::from(format!("workspace-configuration-{count}"));
+    let params = ConfigurationParams {
+        items: vec![ConfigurationItem {
+            scope_uri: None,
+            section: Some(String::from(settings::SECTION)),
+        }],
+    };
+    let request = Request::new(
+        id.clone(),
+        String::from(WorkspaceConfiguration::METHOD),
+        params,
+    );
+    send(connection, Message::Request(request));
+    Some(id)
+}
+
+//Problems with the environment of every folder
+fn check_environment(folders: &WorkspaceFolders, settings: &Settings) -> Vec<Message> {
+    folders
+        .iter()
+        .filter_map(|workspace| settings.check_environment(workspace.root()).err())
+        .map(|e| e.show_message())
+        .collect()
+}
+
+//Takes new settings without a restart, only what changed is done again
+fn apply_settings(
+    connection: &Connection,
+    folders: &mut WorkspaceFolders,
+    current: &mut Settings,
+    settings: Settings,
+) -> Vec<Message> {
+    if settings == *current {
+        return vec![];
+    }
+    info!("Applying {settings:?}");
+    if settings.log != current.log {
+        logging::configure(
+            &settings.log.clone().with_env(),
+            Some(connection.sender.clone()),
+        );
+    }
+    let mut removed = vec![];
+    if settings.discovery != current.discovery {
+        removed = folders.set_discovery(settings.discovery.clone());
+    }
+    if settings.rules != current.rules {
+        folders.set_rules(settings.rules.clone());
+    }
+    *current = settings;
+    let mut messages: Vec<Message> = folders.iter().flat_map(publish_diagnostics).collect();
+    messages.extend(removed.into_iter().map(clear_diagnostics));
+    messages.extend(check_environment(folders, current));
+    messages
+}
+
+//The ieflsp section the client sent. The settings in use stay when there is
+//none, or when it is invalid and the user is told so
+fn settings_changed(
+    connection: &Connection,
+    folders: &mut WorkspaceFolders,
+    current: &mut Settings,
+    section: Option<serde_json::Value>,
+) -> Vec<Message> {
+    match Settings::parse(section) {
+        Ok(Some(settings)) => apply_settings(connection, folders, current, settings),
+        Ok(None) => vec![],
+        Err(e) => {
+            error!("{e}");
+            vec![e.show_message()]
+        }
+    }
 }
 
 fn main_loop(
     connection: Connection,
-    params: serde_json::Value,
-) -> Result<(), Box<dyn Error + Sync + Send>> {
-    let params: InitializeParams = serde_json::from_value(params).unwrap();
-    let root_uri = match params.root_uri {
-        Some(url) => String::from(url.as_str()),
-        None => {
-            error!("Root URI is None!");
-            return Err(Box::new(ServerError {
-                msg: String::from("Root URI is NonE!"),
-            }));
+    mut lifecycle: Lifecycle,
+    params: InitializeParams,
+    encoding: PositionEncoding,
+    mut settings: Settings,
+) -> u8 {
+    let folder_uris: Vec<Url> = match (params.workspace_folders, params.root_uri) {
+        (Some(folders), _) if !folders.is_empty() => {
+            folders.into_iter().map(|folder| folder.uri).collect()
+        }
+        (_, Some(url)) => vec![url],
+        _ => {
+            warn!("No workspace folders, waiting for some to be added");
+            vec![]
         }
     };
-    let mut workspace = workspace::new_workspace(root_uri.as_str());
+    let roots: Vec<String> = folder_uris
+        .iter()
+        .filter_map(|uri| uri.to_file_path().ok())
+        .map(|path| path.to_string_lossy().into_owned())
+        .collect();
+    info!("Starting with {settings:?}");
+    let mut folders = WorkspaceFolders::new(&roots, encoding, settings.discovery.clone());
+    folders.set_rules(settings.rules.clone());
     info!("Created workspace representation");
+    for problem in folders.take_problems() {
+        send(&connection, problem.show_message());
+    }
+    for msg in check_environment(&folders, &settings) {
+        send(&connection, msg);
+    }
+    //The workspace/configuration request that is waiting for its answer
+    let mut pulls = 0;
+    let mut pending_settings: Option<RequestId> = None;
+    let threads = thread::available_parallelism().map_or(2, |n| n.get().min(4));
+    let scheduler = Scheduler::new(threads, connection.sender.clone());
+    //The client went away without exit unless this changes
+    let mut code = 1;
     info!("Starting Main loop!");
     for msg in &connection.receiver {
+        let msg = match lifecycle.step(msg) {
+            Step::Handle(msg) => msg,
+            Step::Reply(msg) => {
+                send(&connection, msg);
+                continue;
+            }
+            Step::Exit(exit_code) => {
+                code = exit_code;
+                break;
+            }
+            Step::Initialize(..) | Step::Ignore => continue,
+        };
         let result = match msg {
             Message::Request(req) => {
-                if req.method == "shutdown" {
-                    break;
+                let method = req.method.clone();
+                if req.method == WorkspaceSymbolRequest::METHOD {
+                    let snapshots = folders.snapshots();
+                    scheduler.spawn(req.id.clone(), &method, move || {
+                        workspace_symbols(&snapshots, req)
+                    });
+                    vec![]
+                } else {
+                    match folders.snapshot(document_uri(&req.params).as_ref()) {
+                        Some(workspace) => {
+                            scheduler.spawn(req.id.clone(), &method, move || {
+                                handle_request(&workspace, req)
+                            });
+                            vec![]
+                        }
+                        None => vec![Message::Response(Response::new_err(
+                            req.id,
+                            ErrorCode::RequestFailed as i32,
+                            String::from("No workspace folder is open"),
+                        ))],
+                    }
                 }
-                handle_request(&mut workspace, req)
             }
-            Message::Notification(not) => handle_notification(&mut workspace, not),
-            _ => {
-                warn!("This must be a response");
-                vec![]
+            Message::Notification(not) => {
+                if not.method == Initialized::METHOD {
+                    register_capabilities(&connection, &params.capabilities, &settings);
+                    pending_settings = pull_settings(&connection, &params.capabilities, &mut pulls);
+                    vec![]
+                } else if not.method == DidChangeConfiguration::METHOD {
+                    //Clients that can be asked only say that something changed
+                    pending_settings = pull_settings(&connection, &params.capabilities, &mut pulls);
+                    match (
+                        &pending_settings,
+                        cast_notification::<DidChangeConfiguration>(not),
+                    ) {
+                        (None, Some(changed)) => settings_changed(
+                            &connection,
+                            &mut folders,
+                            &mut settings,
+                            changed.settings.get(settings::SECTION).cloned(),
+                        ),
+                        _ => vec![],
+                    }
+                } else if not.method == Cancel::METHOD {
+                    if let Some(params) = cast_notification::<Cancel>(not) {
+                        scheduler.cancel(&match params.id {
+                            NumberOrString::Number(id) => RequestId::from(id),
+                            NumberOrString::String(id) => RequestId::from(id),
+                        });
+                    }
+                    vec![]
+                } else if not.method == DidChangeWorkspaceFolders::METHOD {
+                    change_folders(&mut folders, not)
+                } else if not.method == DidChangeWatchedFiles::METHOD {
+                    watched_files_changed(&mut folders, &settings, not)
+                } else {
+                    match folders.for_document(document_uri(&not.params).as_ref()) {
+                        Some(workspace) => handle_notification(workspace, not),
+                        None => {
+                            warn!("No workspace folder for {:?}", not.method);
+                            vec![]
+                        }
+                    }
+                }
             }
+            Message::Response(response) => match response.error {
+                Some(e) => {
+                    warn!("Request {} failed: {}", response.id, e.message);
+                    vec![]
+                }
+                None if pending_settings.as_ref() == Some(&response.id) => {
+                    pending_settings = None;
+                    //One result per item, the only item is the ieflsp section
+                    let new = response.result.and_then(|result| result.get(0).cloned());
+                    settings_changed(&connection, &mut folders, &mut settings, new)
+                }
+                None => vec![],
+            },
         };
-        for msg in result {
+        let problems = folders.take_problems();
+        for msg in result
+            .into_iter()
+            .chain(problems.iter().map(ServerError::show_message))
+        {
             let res = connection.sender.send(msg);
             info!("Sent message to client {:?}", res);
         }
     }
 
+    scheduler.join();
     info!("Main loop over");
-    Ok(())
+    code
 }
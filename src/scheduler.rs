@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use crossbeam_channel::{unbounded, Sender};
+use log::{error, info};
+use lsp_server::{ErrorCode, Message, RequestId, Response};
+
+use crate::logging::request_span;
+
+type Work = Box<dyn FnOnce() -> Vec<Message> + Send>;
+
+struct Job {
+    id: RequestId,
+    method: String,
+    queued: Instant,
+    cancelled: Arc<AtomicBool>,
+    work: Work,
+}
+
+//Runs read-only requests on worker threads so a slow one does not hold up
+//typing. Document changes stay on the main loop, the jobs only get snapshots
+pub struct Scheduler {
+    jobs: Sender<Job>,
+    //Requests that have not been answered yet
+    pending: Arc<Mutex<HashMap<RequestId, Arc<AtomicBool>>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+fn cancelled(id: RequestId) -> Message {
+    Message::Response(Response::new_err(
+        id,
+        ErrorCode::RequestCanceled as i32,
+        String::from("Request cancelled"),
+    ))
+}
+
+impl Scheduler {
+    pub fn new(threads: usize, responses: Sender<Message>) -> Self {
+        let (jobs, queue) = unbounded::<Job>();
+        let pending: Arc<Mutex<HashMap<RequestId, Arc<AtomicBool>>>> = Arc::default();
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let queue = queue.clone();
+                let pending = pending.clone();
+                let responses = responses.clone();
+                thread::spawn(move || {
+                    for job in queue {
+                        let messages = if job.cancelled.load(Ordering::Relaxed) {
+                            vec![cancelled(job.id.clone())]
+                        } else {
+                            let _span = request_span(&job.id, &job.method, job.queued);
+                            Self::run(&job.id, job.work)
+                        };
+                        //A cancel that comes in while the job runs still wins
+                        let messages = if job.cancelled.load(Ordering::Relaxed) {
+                            vec![cancelled(job.id.clone())]
+                        } else {
+                            messages
+                        };
+                        pending.lock().unwrap().remove(&job.id);
+                        for message in messages {
+                            if let Err(e) = responses.send(message) {
+                                error!("Could not send response {e}");
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        Scheduler {
+            jobs,
+            pending,
+            workers,
+        }
+    }
+
+    //A panicking request answers with an error instead of taking a worker down
+    fn run(id: &RequestId, work: Work) -> Vec<Message> {
+        panic::catch_unwind(AssertUnwindSafe(work)).unwrap_or_else(|_| {
+            error!("Request {id} panicked");
+            vec![Message::Response(Response::new_err(
+                id.clone(),
+                ErrorCode::InternalError as i32,
+                format!("Request {id} failed"),
+            ))]
+        })
+    }
+
+    pub fn spawn(
+        &self,
+        id: RequestId,
+        method: &str,
+        work: impl FnOnce() -> Vec<Message> + Send + 'static,
+    ) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(id.clone(), cancelled.clone());
+        let job = Job {
+            id,
+            method: String::from(method),
+            queued: Instant::now(),
+            cancelled,
+            work: Box::new(work),
+        };
+        if let Err(e) = self.jobs.send(job) {
+            error!("Could not schedule request {}", e.0.id);
+        }
+    }
+
+    //$/cancelRequest, requests that were already answered are left alone
+    pub fn cancel(&self, id: &RequestId) {
+        match self.pending.lock().unwrap().get(id) {
+            Some(cancelled) => {
+                info!("Cancelling request {id}");
+                cancelled.store(true, Ordering::Relaxed);
+            }
+            None => info!("Request {id} is already done"),
+        }
+    }
+
+    //Lets the workers finish what was scheduled
+    pub fn join(self) {
+        drop(self.jobs);
+        for worker in self.workers {
+            if worker.join().is_err() {
+                error!("Worker thread panicked");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use crossbeam_channel::unbounded;
+    use lsp_server::{ErrorCode, Message, RequestId, Response};
+
+    use super::Scheduler;
+
+    fn response(message: Message) -> Response {
+        match message {
+            Message::Response(response) => response,
+            _ => panic!("Expected a response"),
+        }
+    }
+
+    #[test]
+    fn test_cancel_queued_request() {
+        let (responses, received) = unbounded();
+        let scheduler = Scheduler::new(1, responses);
+        //Keep the only worker busy until the second request is cancelled
+        let (release, blocked) = mpsc::channel::<()>();
+        scheduler.spawn(RequestId::from(1), "test/blocked", move || {
+            blocked.recv().unwrap();
+            vec![Message::Response(Response::new_ok(RequestId::from(1), 1))]
+        });
+        scheduler.spawn(RequestId::from(2), "test/cancelled", || {
+            vec![Message::Response(Response::new_ok(RequestId::from(2), 2))]
+        });
+        scheduler.cancel(&RequestId::from(2));
+        release.send(()).unwrap();
+
+        let first = response(received.recv_timeout(Duration::from_secs(5)).unwrap());
+        assert_eq!(first.id, RequestId::from(1));
+        assert!(first.error.is_none());
+        let second = response(received.recv_timeout(Duration::from_secs(5)).unwrap());
+        assert_eq!(second.id, RequestId::from(2));
+        assert_eq!(
+            second.error.unwrap().code,
+            ErrorCode::RequestCanceled as i32
+        );
+        //Cancelling after the answer does nothing
+        scheduler.cancel(&RequestId::from(1));
+        scheduler.join();
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_panicking_request() {
+        let (responses, received) = unbounded();
+        let scheduler = Scheduler::new(1, responses);
+        scheduler.spawn(
+            RequestId::from(String::from("broken")),
+            "test/broken",
+            || panic!("Broken request"),
+        );
+        scheduler.spawn(RequestId::from(3), "test/ok", || {
+            vec![Message::Response(Response::new_ok(RequestId::from(3), 3))]
+        });
+        scheduler.join();
+        let broken = response(received.recv().unwrap());
+        assert_eq!(broken.error.unwrap().code, ErrorCode::InternalError as i32);
+        assert!(response(received.recv().unwrap()).error.is_none());
+    }
+}
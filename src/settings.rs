@@ -0,0 +1,171 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::error::ServerError;
+use crate::logging::LogSettings;
+use crate::workspace::{Discovery, RuleSeverities};
+
+//The section asked for with workspace/configuration
+pub const SECTION: &str = "ieflsp";
+
+//What the client can configure. initializationOptions has the first version,
+//workspace/configuration the later ones. Missing fields keep their defaults
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Settings {
+    //include, exclude and policySets at the top level
+    #[serde(flatten)]
+    pub discovery: Discovery,
+    //Severities of the diagnostics by code
+    pub rules: RuleSeverities,
+    pub log: LogSettings,
+    //appsettings.json of the B2C extension, relative to each workspace folder
+    pub app_settings: PathBuf,
+    //One of the Environments in appsettings.json
+    pub environment: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            discovery: Discovery::default(),
+            rules: RuleSeverities::default(),
+            log: LogSettings::default(),
+            app_settings: PathBuf::from("appsettings.json"),
+            environment: None,
+        }
+    }
+}
+
+impl Settings {
+    //None when the client has no settings for the server, then whatever is in
+    //use stays
+    pub fn parse(value: Option<Value>) -> Result<Option<Self>, ServerError> {
+        match value {
+            None | Some(Value::Null) => Ok(None),
+            Some(value) => serde_json::from_value(value)
+                .map(Some)
+                .map_err(|e| ServerError::Settings(format!("Invalid {SECTION} settings: {e}"))),
+        }
+    }
+
+    //Whether the file is the appsettings.json of the folder at root
+    pub fn is_app_settings(&self, root: &Path, path: &Path) -> bool {
+        root.join(&self.app_settings) == path
+    }
+
+    //The configured environment has to be one appsettings.json of the folder
+    //at root knows
+    pub fn check_environment(&self, root: &Path) -> Result<(), ServerError> {
+        let Some(environment) = &self.environment else {
+            return Ok(());
+        };
+        let path = root.join(&self.app_settings);
+        let text = fs::read_to_string(&path).map_err(|e| ServerError::Io(path.clone(), e))?;
+        let app_settings: Value = serde_json::from_str(text.trim_start_matches('\u{feff}'))
+            .map_err(|e| {
+                ServerError::Settings(format!("{} is not valid JSON: {e}", path.display()))
+            })?;
+        let known = app_settings
+            .get("Environments")
+            .and_then(Value::as_array)
+            .is_some_and(|environments| {
+                environments
+                    .iter()
+                    .any(|env| env.get("Name").and_then(Value::as_str) == Some(environment))
+            });
+        match known {
+            true => Ok(()),
+            false => Err(ServerError::Settings(format!(
+                "Environment {environment:?} is not in {}",
+                path.display()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use lsp_types::DiagnosticSeverity;
+    use serde_json::json;
+
+    use super::Settings;
+    use crate::error::ServerError;
+    use crate::logging::BackendKind;
+    use crate::workspace::Discovery;
+
+    #[test]
+    fn test_parse_settings() {
+        //What initializationOptions looked like before settings had a type
+        let settings = Settings::parse(Some(json!({
+            "exclude": ["Build/**"],
+            "log": {"backend": "file"},
+        })))
+        .unwrap()
+        .unwrap();
+        assert_eq!(settings.discovery.exclude, vec!["Build/**"]);
+        assert_eq!(settings.discovery.include, Discovery::default().include);
+        assert_eq!(settings.log.backend, BackendKind::File);
+
+        let settings = Settings::parse(Some(json!({
+            "policySets": ["LocalAccounts"],
+            "rules": {"missing-policy-id": "hint"},
+            "appSettings": "config/appsettings.json",
+            "environment": "Test",
+        })))
+        .unwrap()
+        .unwrap();
+        assert_eq!(settings.discovery.policy_sets, vec!["LocalAccounts"]);
+        assert_eq!(
+            settings
+                .rules
+                .severity(crate::workspace::Rule::MissingPolicyId),
+            Some(DiagnosticSeverity::HINT)
+        );
+        assert_eq!(settings.environment.as_deref(), Some("Test"));
+
+        assert_eq!(Settings::parse(None).unwrap(), None);
+        assert_eq!(Settings::parse(Some(json!(null))).unwrap(), None);
+        assert_eq!(
+            Settings::parse(Some(json!({}))).unwrap(),
+            Some(Settings::default())
+        );
+        assert!(matches!(
+            Settings::parse(Some(json!({"rules": []}))),
+            Err(ServerError::Settings(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_environment() {
+        let root = std::env::temp_dir().join(format!("ief_lsp_settings_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let mut settings = Settings::default();
+        assert!(settings.check_environment(&root).is_ok());
+
+        settings.environment = Some(String::from("Test"));
+        assert!(matches!(
+            settings.check_environment(&root),
+            Err(ServerError::Io(..))
+        ));
+        fs::write(
+            root.join("appsettings.json"),
+            "\u{feff}{\"Environments\": [{\"Name\": \"Development\"}, {\"Name\": \"Test\"}]}",
+        )
+        .unwrap();
+        assert!(settings.check_environment(&root).is_ok());
+        assert!(settings.is_app_settings(&root, &root.join("appsettings.json")));
+
+        settings.environment = Some(String::from("Production"));
+        assert!(matches!(
+            settings.check_environment(&root),
+            Err(ServerError::Settings(_))
+        ));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,295 +1,2288 @@
-use log::{debug, error, info, warn};
+use log::{error, info, warn};
 use lsp_types::{
-    Diagnostic, DiagnosticSeverity, DocumentChanges, Location, OneOf,
-    TextDocumentContentChangeEvent, TextEdit, Url,
+    CompletionItem, CompletionTextEdit, Diagnostic, DiagnosticRelatedInformation, DocumentSymbol,
+    Hover, HoverContents, Location, MarkupContent, MarkupKind, NumberOrString, Position, Range,
+    SymbolInformation, TextDocumentContentChangeEvent, TextEdit, Url, WorkspaceEdit,
 };
-use std::ffi::OsStr;
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
-use std::{collections::HashMap, io::Error};
-use tree_sitter::{InputEdit, Node, Parser, Point, Query, QueryCursor, Tree};
-use tree_sitter_xml;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tree_sitter::{Parser, Tree};
 
-use self::queries::{base_policy_query, id_query, null_range, IEFQuery, IEFQueryMatch};
+use self::completion::{
+    completion_kind, reference_context, schema_completions, schema_context, SchemaContext,
+};
+pub use self::discovery::Discovery;
+use self::discovery::FileFilter;
+use self::document_kind::{metadata_item_at, names_metadata, root_element, DocumentKind};
+use self::encoding::FileEncoding;
+pub use self::folders::WorkspaceFolders;
+use self::index::SymbolIndex;
+use self::inheritance::{find_element, find_merge_keys, merge_key_at, MergeKey};
+use self::merge::{profile_layer, EffectiveProfile, ProfileLayer};
+use self::outline::{outline, symbol_kind};
+use self::queries::{base_policy_query, get_range, id_query, null_range, start_tag, IEFQueryMatch};
+pub use self::rules::{Rule, RuleSeverities};
+use self::symbols::{find_symbols, symbol_at, ElementKind, IEFSymbol};
+pub use self::sync::PositionEncoding;
 use self::sync::TextSync;
+use crate::error::ServerError;
+mod completion;
+mod discovery;
+mod document_kind;
+mod encoding;
+#[cfg(test)]
+mod fixtures;
+mod folders;
+mod index;
+mod inheritance;
+mod merge;
+mod outline;
 mod queries;
-mod sync;
+mod rules;
+mod schema;
+mod symbols;
+//pub(crate) for benches/text_sync.rs
+pub(crate) mod sync;
 
+#[allow(non_camel_case_types)]
+#[derive(Clone)]
 pub struct IEF_Policy {
     text: TextSync,
     tree: Tree,
     pub id: String,
+    id_range: Range,
     pub base_id: Option<IEFQueryMatch>,
+    pub kind: DocumentKind,
+    file_encoding: FileEncoding,
 }
 
 impl IEF_Policy {
-    fn new(sitter: &mut Parser, path: &String) -> Option<Self> {
-        let path = path.clone();
-        let text = match fs::read_to_string(&path).ok() {
-            Some(text) => TextSync::new(text),
-            None => return None,
-        };
-        let tree = match sitter.parse(text.text(), None) {
-            None => return None,
-            Some(tree) => tree,
-        };
+    //Bytes that do not fit the encoding of the file are replaced and reported
+    //in problems, the rest of the file is still of use
+    fn new(
+        sitter: &mut Parser,
+        path: &str,
+        problems: &mut Vec<ServerError>,
+    ) -> Result<Self, ServerError> {
+        let bytes = fs::read(path).map_err(|e| ServerError::Io(PathBuf::from(path), e))?;
+        let file_encoding = FileEncoding::detect(&bytes);
+        let (text, malformed) = file_encoding.decode(&bytes);
+        if malformed {
+            warn!("{path} is not valid {}", file_encoding.encoding.name());
+            problems.push(ServerError::Encoding(
+                PathBuf::from(path),
+                file_encoding.encoding.name(),
+            ));
+        }
+        let mut policy = IEF_Policy::from_text(sitter, text)
+            .ok_or_else(|| ServerError::Parse(String::from(path)))?;
+        policy.file_encoding = file_encoding;
+        Ok(policy)
+    }
+
+    fn from_text(sitter: &mut Parser, text: String) -> Option<Self> {
+        let text = TextSync::new(text);
+        let tree = text.parse(sitter, None)?;
         let mut new_policy = IEF_Policy {
             tree,
             text,
             id: String::from(""),
+            id_range: null_range(),
             base_id: None,
+            kind: DocumentKind::Other,
+            file_encoding: FileEncoding::default(),
         };
         new_policy.compute_ids();
-        return Some(new_policy);
+        Some(new_policy)
+    }
+
+    //The current text in the encoding the file was read with
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.file_encoding.encode(&self.text.rope().to_string())
     }
 
+    //The lines of the edit are expected to exist, see IEF_Workspace::apply_change
     pub fn handle_edit(
         &mut self,
         parser: &mut Parser,
         edit: &TextEdit,
-    ) -> Result<(), UpdateDocError> {
-        let start_line = edit.range.start.line.try_into().unwrap();
-        let start_char = edit.range.start.character.try_into().unwrap();
-        let end_line = edit.range.end.line.try_into().unwrap();
-        let end_char = edit.range.end.character.try_into().unwrap();
-        if edit.new_text == "" {
-            self.tree.edit(&InputEdit {
-                start_byte: self.text.byte_pos(start_line, start_char),
-                start_position: Point::new(start_line, start_char),
-                old_end_byte: self.text.byte_pos(end_line, end_char),
-                old_end_position: Point::new(end_line, end_char),
-                new_end_byte: self.text.byte_pos(start_line, start_char),
-                new_end_position: Point::new(start_line, start_char),
-            });
-        } else {
-            let new_lines = edit.new_text.lines().count();
-            let mut new_chars = edit.new_text.lines().last().unwrap().len();
-            if new_lines == 0 {
-                new_chars += start_char;
-            }
-            let new_bytes = edit.new_text.len();
-            self.tree.edit(&InputEdit {
-                start_byte: self.text.byte_pos(start_line, start_char),
-                start_position: Point::new(start_line, start_char),
-                old_end_byte: self.text.byte_pos(end_line, end_char),
-                old_end_position: Point::new(end_line, end_char),
-                new_end_byte: self.text.byte_pos(start_line + new_lines, new_chars) + new_bytes,
-                new_end_position: Point::new(start_line + new_lines, new_chars),
-            });
-        }
-        self.text.edit(edit);
-        self.tree = parser
-            .parse(self.text.text(), Some(&self.tree))
+        encoding: PositionEncoding,
+    ) {
+        //TextSync works on byte columns, like tree-sitter
+        let edit = &TextEdit {
+            range: self.text.to_byte_range(edit.range, encoding),
+            new_text: edit.new_text.clone(),
+        };
+        let input_edit = self.text.edit(edit);
+        self.tree.edit(&input_edit);
+        self.tree = self
+            .text
+            .parse(parser, Some(&self.tree))
             .unwrap_or(self.tree.clone());
         self.compute_ids();
-        return Ok(());
+    }
+
+    //Full document sync, the old tree is of no use here. Returns whether the
+    //new text could be parsed
+    pub fn replace_text(&mut self, parser: &mut Parser, text: String) -> bool {
+        self.text.replace(text);
+        let Some(tree) = self.text.parse(parser, None) else {
+            return false;
+        };
+        self.tree = tree;
+        self.compute_ids();
+        true
     }
 
     pub fn compute_ids(&mut self) {
-        let id_query = id_query();
-        let base_query = base_policy_query();
-        let id = id_query
-            .first(self.tree.root_node(), self.text.text())
+        let root = self.tree.root_node();
+        let id = id_query()
+            .and_then(|query| query.first(root, self.text.rope()))
             .unwrap_or(queries::IEFQueryMatch {
                 txt: String::from(""),
                 range: null_range(),
-            })
-            .txt;
-        let base_id = base_query.first(self.tree.root_node(), self.text.text());
-        self.id = id;
+            });
+        let base_id = base_policy_query().and_then(|query| query.first(root, self.text.rope()));
+        self.id = id.txt;
+        self.id_range = id.range;
         self.base_id = base_id;
+        self.kind = DocumentKind::classify(self.tree.root_node(), self.text.rope());
     }
-}
-struct UpdateDocError {
-    msg: String,
-}
-impl UpdateDocError {
-    fn new(msg: &str) -> Self {
-        return Self {
-            msg: String::from(msg),
-        };
+
+    pub fn symbols(&self) -> Vec<IEFSymbol> {
+        find_symbols(self.tree.root_node(), self.text.rope())
+    }
+
+    pub fn symbol_at(&self, pos: Position) -> Option<IEFSymbol> {
+        symbol_at(&self.tree.root_node(), self.text.rope(), pos)
+    }
+
+    pub fn metadata_item_at(&self, pos: Position) -> Option<String> {
+        metadata_item_at(&self.tree.root_node(), self.text.rope(), pos)
+    }
+
+    //Whether this SAML metadata, kept at path, is what a metadata item points at
+    pub fn names_metadata(&self, value: &str, path: &str) -> bool {
+        names_metadata(
+            value,
+            self.tree.root_node(),
+            self.text.rope(),
+            &file_name(path),
+        )
+    }
+
+    //Start tag of the root element, where links to the whole document go
+    pub fn root_range(&self) -> Range {
+        root_element(self.tree.root_node())
+            .and_then(|root| start_tag(&root))
+            .map(|tag| get_range(&tag))
+            .unwrap_or_else(null_range)
+    }
+
+    pub fn outline(&self) -> Vec<DocumentSymbol> {
+        outline(self.tree.root_node(), self.text.rope())
+    }
+
+    pub fn merge_key_at(&self, pos: Position) -> Option<MergeKey> {
+        merge_key_at(&self.tree.root_node(), self.text.rope(), pos)
+    }
+
+    pub fn merge_keys(&self) -> Vec<(MergeKey, Range)> {
+        find_merge_keys(self.tree.root_node(), self.text.rope())
+    }
+
+    pub fn reference_context(&self, pos: Position) -> Option<(ElementKind, Range)> {
+        reference_context(&self.tree.root_node(), self.text.rope(), pos)
+    }
+
+    pub fn schema_context(&self, pos: Position) -> Option<SchemaContext> {
+        schema_context(&self.tree.root_node(), self.text.rope(), pos)
+    }
+
+    //What this policy's TechnicalProfile with the given Id contributes
+    pub fn profile_layer(&self, id: &str) -> Option<ProfileLayer> {
+        let key = MergeKey::Element(ElementKind::TechnicalProfile, String::from(id));
+        let element = find_element(self.tree.root_node(), self.text.rope(), &key)?;
+        Some(profile_layer(&element, self.text.rope()))
+    }
+
+    //This policy's definition of whatever `symbol` points to
+    pub fn definition_of(&self, symbol: &IEFSymbol) -> Option<IEFSymbol> {
+        self.symbols()
+            .into_iter()
+            .find(|s| s.is_definition() && s.same_target(symbol))
     }
 }
-pub struct IEF_Workspace<'a> {
-    root_path: &'a str,
+#[allow(non_camel_case_types)]
+pub struct IEF_Workspace {
+    files: FileFilter,
     //appsettings: Option<Tree>,
     //app_settings_path: Option<Path>,
-    policies: HashMap<String, IEF_Policy>,
+    //What the editor has open, otherwise what is on disk. Auxiliary XML
+    //documents are kept here too, see DocumentKind. Shared with the snapshots
+    //until the document is changed
+    policies: HashMap<String, Arc<IEF_Policy>>,
+    //Versions of the open buffers
+    versions: HashMap<String, i32>,
+    symbol_index: SymbolIndex,
     parser: Parser,
+    encoding: PositionEncoding,
+    rules: RuleSeverities,
+    //Failures nobody asked about, like files that could not be loaded. The
+    //main loop takes them to show them to the user
+    problems: Vec<ServerError>,
 }
-impl IEF_Workspace<'_> {
-    pub fn find_policy_by_id(&self, id: &str) -> Option<&IEF_Policy> {
+//Snapshots for requests running on other threads. A Parser can not be cloned,
+//the copy gets its own
+impl Clone for IEF_Workspace {
+    fn clone(&self) -> Self {
+        IEF_Workspace {
+            files: self.files.clone(),
+            policies: self.policies.clone(),
+            versions: self.versions.clone(),
+            symbol_index: self.symbol_index.clone(),
+            parser: xml_parser(),
+            encoding: self.encoding,
+            rules: self.rules.clone(),
+            problems: vec![],
+        }
+    }
+}
+
+impl IEF_Workspace {
+    //The documents that are TrustFrameworkPolicy files
+    fn trust_framework_policies(&self) -> impl Iterator<Item = (&String, &IEF_Policy)> {
         self.policies
-            .values()
-            .find(|p| p.id.as_str().to_lowercase() == id.to_lowercase())
+            .iter()
+            .map(|(path, p)| (path, p.as_ref()))
+            .filter(|(_, p)| p.kind == DocumentKind::Policy)
+    }
+
+    fn policy_set(&self, path: &str) -> PathBuf {
+        self.files.policy_set(Path::new(path))
+    }
+
+    //The policy `from` means by PolicyId `id`. Starter packs side by side share
+    //their PolicyIds, so one from the same policy set wins, then the nearest folder
+    pub fn find_policy_path_by_id(&self, from: &str, id: &str) -> Option<&String> {
+        let set = self.policy_set(from);
+        self.trust_framework_policies()
+            .filter(|(_, p)| p.id.as_str().to_lowercase() == id.to_lowercase())
+            .map(|(path, _)| path)
+            .max_by_key(|path| {
+                (
+                    self.policy_set(path) == set,
+                    shared_folders(from, path),
+                    Reverse(*path),
+                )
+            })
+    }
+
+    //Other policies in the same policy set with the PolicyId of the one at path
+    fn same_id_in_set(&self, path: &str) -> Vec<&String> {
+        let Some(policy) = self.policies.get(path) else {
+            return vec![];
+        };
+        let set = self.policy_set(path);
+        let mut duplicates: Vec<&String> = self
+            .trust_framework_policies()
+            .filter(|(p, other)| {
+                *p != path
+                    && other.id.to_lowercase() == policy.id.to_lowercase()
+                    && self.policy_set(p) == set
+            })
+            .map(|(p, _)| p)
+            .collect();
+        duplicates.sort();
+        duplicates
+    }
+
+    //The policy at path followed by its base policies, nearest first
+    pub fn policy_chain(&self, path: &str) -> Vec<&String> {
+        let mut chain: Vec<&String> = vec![];
+        let mut current = self.policies.get_key_value(path);
+        while let Some((path, policy)) = current {
+            if chain.contains(&path) {
+                error!("Policy {:?} inherits from itself", policy.id);
+                break;
+            }
+            chain.push(path);
+            current = policy
+                .base_id
+                .as_ref()
+                .and_then(|base_id| self.find_policy_path_by_id(path, base_id.txt.as_str()))
+                .and_then(|base_path| self.policies.get_key_value(base_path.as_str()));
+        }
+        chain
+    }
+
+    //Policies that name each policy as their BasePolicy
+    fn derived_index(&self) -> HashMap<&String, Vec<&String>> {
+        let mut index: HashMap<&String, Vec<&String>> = HashMap::new();
+        for (path, policy) in self.trust_framework_policies() {
+            let base_path = policy
+                .base_id
+                .as_ref()
+                .and_then(|base_id| self.find_policy_path_by_id(path, base_id.txt.as_str()));
+            if let Some(base_path) = base_path {
+                index.entry(base_path).or_default().push(path);
+            }
+        }
+        for derived in index.values_mut() {
+            derived.sort();
+        }
+        index
+    }
+
+    //Every policy that inherits from path, directly or through other policies
+    pub fn derived_policies(&self, path: &str) -> Vec<&String> {
+        let index = self.derived_index();
+        let mut derived: Vec<&String> = vec![];
+        let mut queue: Vec<&String> = index.get(&String::from(path)).cloned().unwrap_or_default();
+        while !queue.is_empty() {
+            let next = queue.remove(0);
+            if next == path || derived.contains(&next) {
+                continue;
+            }
+            derived.push(next);
+            queue.extend(index.get(next).cloned().unwrap_or_default());
+        }
+        derived
+    }
+
+    //Policies path can see: its policy set, what it inherits from and what
+    //inherits from it, sorted
+    fn policy_scope(&self, path: &str) -> Vec<&String> {
+        let set = self.policy_set(path);
+        let chain = self.policy_chain(path);
+        let mut scope: Vec<&String> = self
+            .trust_framework_policies()
+            .map(|(p, _)| p)
+            .filter(|p| {
+                self.policy_set(p) == set
+                    || chain.contains(p)
+                    || self.policy_chain(p).iter().any(|base| *base == path)
+            })
+            .collect();
+        scope.sort();
+        scope
+    }
+
+    //The inheritance chain of path, then the rest of its scope so lookups still
+    //work for files that are not linked through BasePolicy
+    fn resolution_order(&self, path: &str) -> Vec<&String> {
+        let mut order = self.policy_chain(path);
+        let mut rest: Vec<&String> = self
+            .policy_scope(path)
+            .into_iter()
+            .filter(|p| !order.contains(p))
+            .collect();
+        order.append(&mut rest);
+        order
+    }
+
+    //Path of a document the workspace has loaded
+    pub fn check_document(&self, document: &Url) -> Result<String, ServerError> {
+        policy_path(document)
+            .filter(|path| self.policies.contains_key(path))
+            .ok_or_else(|| ServerError::UnknownDocument(document.clone()))
+    }
+
+    //Lines past the end are an error, columns past the end of a line are
+    //clamped to it
+    pub fn check_position(&self, document: &Url, pos: Position) -> Result<String, ServerError> {
+        let path = self.check_document(document)?;
+        if !self.policies[&path].text.has_line(pos.line) {
+            return Err(ServerError::PositionOutOfRange(document.clone(), pos));
+        }
+        Ok(path)
+    }
+
+    //Path of the document and the client position converted to a byte column
+    fn resolve(&self, document: &Url, pos: Position) -> Option<(String, Position)> {
+        let path = self.check_position(document, pos).ok()?;
+        let pos = self.policies[&path]
+            .text
+            .to_byte_position(pos, self.encoding);
+        Some((path, pos))
+    }
+
+    //Range with byte columns in the policy at path to what the client expects
+    fn encode_range(&self, path: &str, range: Range) -> Range {
+        match self.policies.get(path) {
+            Some(policy) => policy.text.to_encoded_range(range, self.encoding),
+            None => range,
+        }
     }
 
-    fn handle_edit(&mut self, uri: Url, edit: &TextEdit) -> Result<(), UpdateDocError> {
-        let policy = match self
+    fn location(&self, path: &str, range: Range) -> Option<Location> {
+        Url::from_file_path(path).ok().map(|uri| Location {
+            uri,
+            range: self.encode_range(path, range),
+        })
+    }
+
+    //Path of the document and the definition or reference under the cursor
+    fn symbol_at(&self, document: &Url, pos: Position) -> Option<(String, IEFSymbol)> {
+        let (path, pos) = self.resolve(document, pos)?;
+        let symbol = self.policies.get(&path)?.symbol_at(pos)?;
+        Some((path, symbol))
+    }
+
+    pub fn find_definition(&self, document: &Url, pos: Position) -> Option<Location> {
+        let (path, pos) = self.resolve(document, pos)?;
+        let policy = self.policies.get(&path)?;
+        if let Some(value) = policy.metadata_item_at(pos) {
+            info!("Finding SAML metadata {value}");
+            return self
+                .auxiliary_documents(DocumentKind::SamlMetadata)
+                .find_map(|(p, doc)| {
+                    doc.names_metadata(&value, p)
+                        .then(|| self.location(p, doc.root_range()))?
+                });
+        }
+        let symbol = policy.symbol_at(pos)?;
+        info!("Finding definition of {symbol:?}");
+        let in_policies = self.resolution_order(&path).into_iter().find_map(|p| {
+            let definition = self.policies.get(p)?.definition_of(&symbol)?;
+            self.location(p, definition.range)
+        });
+        if in_policies.is_some() || symbol.kind != ElementKind::LocalizedResources {
+            return in_policies;
+        }
+        //Resources exported from the Localization of a policy to their own file
+        self.auxiliary_documents(DocumentKind::Localization)
+            .find_map(|(p, doc)| self.location(p, doc.definition_of(&symbol)?.range))
+    }
+
+    //Loaded documents of a kind other than Policy, in path order
+    fn auxiliary_documents(
+        &self,
+        kind: DocumentKind,
+    ) -> impl Iterator<Item = (&String, &IEF_Policy)> + '_ {
+        let mut documents: Vec<_> = self
             .policies
-            .get_mut(uri.to_file_path().unwrap().to_str().unwrap())
+            .iter()
+            .filter(|(_, doc)| doc.kind == kind)
+            .map(|(p, doc)| (p, doc.as_ref()))
+            .collect();
+        documents.sort_by_key(|(p, _)| *p);
+        documents.into_iter()
+    }
+
+    //The definition from the policy with the shortest BasePolicy chain
+    fn base_most<'a>(
+        definitions: impl Iterator<Item = (&'a String, IEFSymbol, usize)>,
+    ) -> Option<(&'a String, IEFSymbol)> {
+        definitions
+            .min_by_key(|(_, _, depth)| *depth)
+            .map(|(path, s, _)| (path, s))
+    }
+
+    //Follows the BasePolicy chain up to the original declaration, which is
+    //usually in TrustFrameworkBase.xml, rather than the nearest override
+    pub fn find_declaration(&self, document: &Url, pos: Position) -> Option<Location> {
+        let (path, symbol) = self.symbol_at(document, pos)?;
+        info!("Finding declaration of {symbol:?}");
+        let chain = self.policy_chain(&path);
+        let in_chain = chain.iter().rev().find_map(|p| {
+            let definition = self.policies.get(p.as_str())?.definition_of(&symbol)?;
+            Some((*p, definition))
+        });
+        let (p, declaration) = match in_chain {
+            Some(d) => d,
+            //Not declared anywhere this policy inherits from
+            None => Self::base_most(self.resolution_order(&path).into_iter().filter_map(|p| {
+                let definition = self.policies.get(p.as_str())?.definition_of(&symbol)?;
+                Some((p, definition, self.policy_chain(p).len()))
+            }))?,
+        };
+        self.location(p, declaration.range)
+    }
+
+    //Elements in derived policies that override or extend the element under the cursor
+    pub fn find_implementations(&self, document: &Url, pos: Position) -> Vec<Location> {
+        let Some((path, pos)) = self.resolve(document, pos) else {
+            return vec![];
+        };
+        let Some(key) = self.policies.get(&path).and_then(|p| p.merge_key_at(pos)) else {
+            return vec![];
+        };
+        info!("Finding implementations of {key:?}");
+        self.derived_policies(&path)
+            .into_iter()
+            .flat_map(|p| {
+                self.policies[p]
+                    .merge_keys()
+                    .into_iter()
+                    .filter(|(k, _)| *k == key)
+                    .filter_map(move |(_, range)| self.location(p, range))
+            })
+            .collect()
+    }
+
+    //The TechnicalProfile seen from path after merging it from the base policy down
+    //to the nearest override, with IncludeTechnicalProfile contents merged in first
+    pub fn effective_profile(&self, path: &str, id: &str) -> Option<EffectiveProfile> {
+        self.effective_profile_inner(path, id, &mut vec![])
+    }
+
+    fn effective_profile_inner(
+        &self,
+        path: &str,
+        id: &str,
+        //The profiles being included right now, a profile on it includes itself
+        stack: &mut Vec<String>,
+    ) -> Option<EffectiveProfile> {
+        let nearest = self
+            .resolution_order(path)
+            .into_iter()
+            .find(|p| self.policies[p.as_str()].profile_layer(id).is_some())?;
+        let layers: Vec<(String, ProfileLayer)> = self
+            .policy_chain(nearest)
+            .into_iter()
+            .rev()
+            .filter_map(|p| {
+                let layer = self.policies[p.as_str()].profile_layer(id)?;
+                Some((self.relative_path(p), layer))
+            })
+            .collect();
+        let mut profile = EffectiveProfile {
+            id: String::from(id),
+            ..Default::default()
+        };
+        stack.push(String::from(id));
+        for (_, layer) in &layers {
+            for include in &layer.includes {
+                if stack.contains(include) {
+                    continue;
+                }
+                if let Some(included) = self.effective_profile_inner(nearest, include, stack) {
+                    profile.merge(&included);
+                }
+            }
+        }
+        stack.pop();
+        for (source, layer) in &layers {
+            profile.merge(&EffectiveProfile::from_layer(id, layer, source));
+        }
+        Some(profile)
+    }
+
+    //Ids of the kind a reference attribute expects, taken from the current policy
+    //and everything it inherits from. Anywhere else the schema decides
+    pub fn completions(&self, document: &Url, pos: Position) -> Vec<CompletionItem> {
+        let Some((path, pos)) = self.resolve(document, pos) else {
+            return vec![];
+        };
+        let Some(policy) = self.policies.get(&path) else {
+            return vec![];
+        };
+        let items = match policy.reference_context(pos) {
+            Some((kind, range)) => self.id_completions(&path, kind, range),
+            None => policy
+                .schema_context(pos)
+                .map(|context| schema_completions(&context))
+                .unwrap_or_default(),
+        };
+        items
+            .into_iter()
+            .map(|mut item| {
+                if let Some(CompletionTextEdit::Edit(edit)) = &mut item.text_edit {
+                    edit.range = self.encode_range(&path, edit.range);
+                }
+                item
+            })
+            .collect()
+    }
+
+    fn id_completions(&self, path: &str, kind: ElementKind, range: Range) -> Vec<CompletionItem> {
+        info!("Completing {kind:?} ids");
+        let mut items: Vec<CompletionItem> = vec![];
+        for p in self.policy_chain(path) {
+            for symbol in self.policies[p.as_str()].symbols() {
+                if !symbol.is_definition()
+                    || symbol.kind != kind
+                    || items.iter().any(|item| item.label == symbol.id)
+                {
+                    continue;
+                }
+                items.push(CompletionItem {
+                    label: symbol.id.clone(),
+                    kind: Some(completion_kind(kind)),
+                    detail: Some(format!("{} in {}", kind.name(), file_name(p))),
+                    text_edit: Some(CompletionTextEdit::Edit(TextEdit {
+                        range,
+                        new_text: symbol.id,
+                    })),
+                    ..Default::default()
+                });
+            }
+        }
+        items
+    }
+
+    pub fn hover(&self, document: &Url, pos: Position) -> Option<Hover> {
+        let (path, symbol) = self.symbol_at(document, pos)?;
+        if symbol.kind != ElementKind::TechnicalProfile {
+            return None;
+        }
+        let profile = self.effective_profile(&path, &symbol.id)?;
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: profile.to_markdown(),
+            }),
+            range: Some(self.encode_range(&path, symbol.range)),
+        })
+    }
+
+    //Every occurrence of whatever symbol points to in the policies path can see,
+    //sorted by file
+    fn occurrences(&self, path: &str, symbol: &IEFSymbol) -> Vec<(&String, IEFSymbol)> {
+        self.policy_scope(path)
+            .into_iter()
+            .flat_map(|path| {
+                self.policies[path]
+                    .symbols()
+                    .into_iter()
+                    .filter(|s| s.same_target(symbol))
+                    .map(move |s| (path, s))
+            })
+            .collect()
+    }
+
+    pub fn find_references(
+        &self,
+        document: &Url,
+        pos: Position,
+        include_declaration: bool,
+    ) -> Vec<Location> {
+        let Some((path, symbol)) = self.symbol_at(document, pos) else {
+            return vec![];
+        };
+        info!("Finding references of {symbol:?}");
+        let occurrences = self.occurrences(&path, &symbol);
+        //Overrides in derived policies count as references, only the original
+        //declaration is left out
+        let declaration = Self::base_most(
+            occurrences
+                .iter()
+                .filter(|(_, s)| s.is_definition())
+                .map(|(path, s)| (*path, s.clone(), self.policy_chain(path).len())),
+        );
+        occurrences
+            .into_iter()
+            .filter(|occurrence| include_declaration || Some(occurrence) != declaration.as_ref())
+            .filter_map(|(path, s)| self.location(path, s.range))
+            .collect()
+    }
+
+    fn reindex(&mut self, path: &str) {
+        match self.policies.get(path) {
+            Some(policy) if policy.kind == DocumentKind::Policy => {
+                self.symbol_index.invalidate(path);
+            }
+            _ => self.symbol_index.remove(path),
+        }
+    }
+
+    fn reindex_all(&mut self) {
+        let paths: Vec<String> = self.policies.keys().cloned().collect();
+        for path in paths {
+            self.reindex(&path);
+        }
+    }
+
+    //Id-bearing elements in every loaded policy that fuzzy match the query
+    #[allow(deprecated)]
+    pub fn workspace_symbols(&self, query: &str) -> Vec<SymbolInformation> {
+        self.symbol_index
+            .search(query, |path| {
+                self.policies
+                    .get(path)
+                    .map(|policy| policy.symbols())
+                    .unwrap_or_default()
+            })
+            .into_iter()
+            .filter_map(|(path, symbol)| {
+                Some(SymbolInformation {
+                    name: symbol.id.clone(),
+                    kind: symbol_kind(symbol.kind),
+                    tags: None,
+                    deprecated: None,
+                    location: self.location(path, symbol.range)?,
+                    container_name: Some(format!("{} in {}", symbol.kind.name(), file_name(path))),
+                })
+            })
+            .collect()
+    }
+
+    pub fn document_symbols(&self, document: &Url) -> Vec<DocumentSymbol> {
+        let Some(path) = policy_path(document) else {
+            return vec![];
+        };
+        let Some(policy) = self.policies.get(&path) else {
+            return vec![];
+        };
+        let mut symbols = policy.outline();
+        self.encode_outline(&path, &mut symbols);
+        symbols
+    }
+
+    fn encode_outline(&self, path: &str, symbols: &mut [DocumentSymbol]) {
+        for symbol in symbols {
+            symbol.range = self.encode_range(path, symbol.range);
+            symbol.selection_range = self.encode_range(path, symbol.selection_range);
+            if let Some(children) = &mut symbol.children {
+                self.encode_outline(path, children);
+            }
+        }
+    }
+
+    //Range and current text of the Id under the cursor, if it can be renamed
+    pub fn prepare_rename(&self, document: &Url, pos: Position) -> Option<(Range, String)> {
+        let (path, symbol) = self.symbol_at(document, pos)?;
+        Some((self.encode_range(&path, symbol.range), symbol.id))
+    }
+
+    //One edit for the definition, every override and every reference of the Id
+    //under the cursor in every loaded policy
+    pub fn rename(
+        &self,
+        document: &Url,
+        pos: Position,
+        new_name: &str,
+    ) -> Result<Option<WorkspaceEdit>, ServerError> {
+        let Some((document_path, symbol)) = self.symbol_at(document, pos) else {
+            return Ok(None);
+        };
+        if new_name.is_empty()
+            || new_name
+                .chars()
+                .any(|c| c.is_whitespace() || matches!(c, '"' | '<' | '>' | '&' | '\''))
         {
-            Some(p) => p,
-            None => return Err(UpdateDocError::new("Document not found")),
+            return Err(ServerError::InvalidName(format!(
+                "{new_name:?} is not a valid Id"
+            )));
+        }
+        let renamed = IEFSymbol {
+            id: String::from(new_name),
+            ..symbol.clone()
+        };
+        if !renamed.same_target(&symbol)
+            && self
+                .occurrences(&document_path, &renamed)
+                .iter()
+                .any(|(_, s)| s.is_definition())
+        {
+            return Err(ServerError::InvalidName(format!(
+                "A {} with Id {new_name:?} already exists",
+                symbol.kind.name()
+            )));
+        }
+        info!("Renaming {symbol:?} to {new_name}");
+        let mut changes: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        for (path, s) in self.occurrences(&document_path, &symbol) {
+            if let Some(loc) = self.location(path, s.range) {
+                changes.entry(loc.uri).or_default().push(TextEdit {
+                    range: loc.range,
+                    new_text: String::from(new_name),
+                });
+            }
+        }
+        Ok(Some(WorkspaceEdit {
+            changes: Some(changes),
+            ..Default::default()
+        }))
+    }
+
+    fn apply_change(
+        &mut self,
+        document: &Url,
+        path: &str,
+        change: &TextDocumentContentChangeEvent,
+    ) -> Result<(), ServerError> {
+        let Some(policy) = self.policies.get_mut(path).map(Arc::make_mut) else {
+            return Err(ServerError::UnknownDocument(document.clone()));
+        };
+        match change.range {
+            Some(range) => {
+                if let Some(pos) = [range.start, range.end]
+                    .into_iter()
+                    .find(|pos| !policy.text.has_line(pos.line))
+                {
+                    return Err(ServerError::PositionOutOfRange(document.clone(), pos));
+                }
+                policy.handle_edit(
+                    &mut self.parser,
+                    &TextEdit {
+                        range,
+                        new_text: change.text.clone(),
+                    },
+                    self.encoding,
+                );
+                Ok(())
+            }
+            None => match policy.replace_text(&mut self.parser, change.text.clone()) {
+                true => Ok(()),
+                false => Err(ServerError::Parse(document.to_string())),
+            },
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        self.files.root()
+    }
+
+    //Path of a document from the root, so files with the same name in different
+    //folders are told apart
+    fn relative_path(&self, path: &str) -> String {
+        Path::new(path)
+            .strip_prefix(self.root())
+            .map(|relative| relative.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| String::from(path))
+    }
+
+    //Every document the client may have diagnostics for
+    pub fn documents(&self) -> Vec<Url> {
+        self.policies
+            .keys()
+            .filter_map(|path| Url::from_file_path(path).ok())
+            .collect()
+    }
+
+    //The buffer replaces whatever was read from disk until it is closed. Files
+    //from outside the root are tracked for as long as they are open
+    pub fn open_document(
+        &mut self,
+        document: Url,
+        text: String,
+        version: i32,
+    ) -> Result<(), ServerError> {
+        info!("Opened {document} at version {version}");
+        let Some(path) = policy_path(&document) else {
+            return Err(ServerError::UnknownDocument(document));
+        };
+        let parsed = match self.policies.get_mut(&path).map(Arc::make_mut) {
+            Some(policy) => policy.replace_text(&mut self.parser, text),
+            None => match IEF_Policy::from_text(&mut self.parser, text) {
+                Some(policy) => {
+                    self.policies.insert(path.clone(), Arc::new(policy));
+                    true
+                }
+                None => false,
+            },
+        };
+        self.versions.insert(path.clone(), version);
+        self.reindex(&path);
+        match parsed {
+            true => Ok(()),
+            false => Err(ServerError::Parse(document.to_string())),
+        }
+    }
+
+    //Back to the file on disk, or forgotten when it is not part of the workspace.
+    //Returns whether the document is still tracked
+    pub fn close_document(&mut self, document: &Url) -> bool {
+        info!("Closed {document}");
+        let Some(path) = policy_path(document) else {
+            return false;
+        };
+        self.versions.remove(&path);
+        self.load_from_disk(&path)
+    }
+
+    //Reads path again if it is part of the workspace, otherwise forgets it.
+    //Returns whether it is still tracked
+    fn load_from_disk(&mut self, path: &str) -> bool {
+        if self.files.matches(Path::new(path)) {
+            //Saves from the editor and checkouts often leave the file as it was
+            if let (Some(policy), Ok(bytes)) = (self.policies.get(path), fs::read(path)) {
+                if policy.to_bytes() == bytes {
+                    return true;
+                }
+            }
+            match IEF_Policy::new(&mut self.parser, path, &mut self.problems) {
+                Ok(policy) => {
+                    self.policies.insert(String::from(path), Arc::new(policy));
+                    self.reindex(path);
+                    return true;
+                }
+                //Deleted files end up here after a change on disk
+                Err(ServerError::Io(_, e)) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    error!("{e}");
+                    self.problems.push(e);
+                }
+            }
+        }
+        self.policies.remove(path);
+        self.symbol_index.remove(path);
+        false
+    }
+
+    //A file or directory was created, changed or deleted on disk. Open buffers
+    //win over what is underneath them. Returns the documents that are gone
+    pub fn file_changed(&mut self, document: &Url) -> Vec<Url> {
+        let Ok(changed) = document.to_file_path() else {
+            error!("Not a file {document}");
+            return vec![];
         };
-        policy.handle_edit(&mut self.parser, edit)
+        info!("{document} changed on disk");
+        //A directory stands for everything that was or is now under it
+        let mut paths: Vec<PathBuf> = self
+            .policies
+            .keys()
+            .map(PathBuf::from)
+            .filter(|path| path.starts_with(&changed))
+            .collect();
+        if changed.is_dir() {
+            paths.extend(self.files.find_files_in(&changed));
+        } else {
+            paths.push(changed);
+        }
+        self.reload(paths)
     }
 
+    //Reads the paths from disk again, except for open buffers. Returns the
+    //documents that are gone
+    fn reload(&mut self, mut paths: Vec<PathBuf>) -> Vec<Url> {
+        paths.sort();
+        paths.dedup();
+        let mut removed = vec![];
+        for path in paths {
+            let Some(path) = path.to_str() else {
+                continue;
+            };
+            if self.versions.contains_key(path) {
+                continue;
+            }
+            let tracked = self.policies.contains_key(path);
+            if !self.load_from_disk(path) && tracked {
+                removed.extend(Url::from_file_path(path));
+            }
+        }
+        removed
+    }
+
+    //Version of the open buffer, None when the document comes from disk
+    pub fn document_version(&self, document: &Url) -> Option<i32> {
+        policy_path(document).and_then(|path| self.versions.get(&path).copied())
+    }
+
+    //Changes are applied in order, each one against the result of the last
     pub fn update_document(
         &mut self,
         document: Url,
+        version: i32,
         changes: Vec<TextDocumentContentChangeEvent>,
-    ) -> Result<(), Error> {
-        info!("{changes:?}");
-        let edits: Vec<TextEdit> = changes
+    ) -> Result<(), ServerError> {
+        info!(
+            "{} changes to {document} for version {version}",
+            changes.len()
+        );
+        let path = self.check_document(&document)?;
+        //The changes after one that failed were made against a different text
+        let result = changes
             .iter()
-            .filter_map(|change| match change.range {
-                Some(range) => Some(TextEdit {
-                    range: range,
-                    new_text: change.text.clone(),
-                }),
-                None => None,
-            })
-            .collect();
-        for edit in edits {
-            self.handle_edit(document.clone(), &edit);
+            .try_for_each(|change| self.apply_change(&document, &path, change));
+        self.reindex(&path);
+        //The text is not what the client has for this version when a change failed
+        if result.is_ok() {
+            self.versions.insert(path, version);
         }
-        Ok(())
+        result
+    }
+
+    pub fn take_problems(&mut self) -> Vec<ServerError> {
+        std::mem::take(&mut self.problems)
+    }
+
+    pub fn set_rules(&mut self, rules: RuleSeverities) {
+        self.rules = rules;
+    }
+
+    //Loads what the new settings pick and forgets what they leave out, open
+    //buffers stay. Returns the documents that are gone
+    pub fn set_discovery(&mut self, discovery: &Discovery) -> Vec<Url> {
+        self.files = FileFilter::new(self.root(), discovery);
+        info!("Reloading policies under {:?}", self.root());
+        let mut paths: Vec<PathBuf> = self.policies.keys().map(PathBuf::from).collect();
+        paths.extend(self.files.find_files());
+        self.reload(paths)
+    }
+
+    //A diagnostic of rule, None when the settings turn the rule off
+    fn diagnostic(&self, rule: Rule, range: Range, message: String) -> Option<Diagnostic> {
+        Some(Diagnostic {
+            range,
+            severity: Some(self.rules.severity(rule)?),
+            code: Some(NumberOrString::String(String::from(rule.code()))),
+            source: Some(String::from("IEF_LSP")),
+            message,
+            ..Default::default()
+        })
     }
 
-    pub fn get_diagnostics(&self) -> HashMap<String, Vec<Diagnostic>> {
+    pub fn get_diagnostics(&self) -> HashMap<Url, Vec<Diagnostic>> {
         self.policies
             .iter()
             .filter_map(|(path, policy)| {
+                let uri = Url::from_file_path(path).ok()?;
                 let mut diagnostics = vec![];
-                match &policy.base_id {
-                    None => {}
-                    Some(base_id) => match self.find_policy_by_id(base_id.txt.as_str()) {
-                        Some(p) => {
-                            info!("Calculated diagnostics {diagnostics:?} for file {path:?}");
-                        }
-                        None => {
-                            diagnostics.push(Diagnostic {
-                                range: base_id.range,
-                                severity: Some(DiagnosticSeverity::ERROR),
-                                code: None,
-                                code_description: None,
-                                source: Some(String::from("IEF_LSP")),
-                                related_information: None,
-                                tags: None,
-                                data: None,
-                                message: format!(
-                                    "Policy with ID {:?} does not exist!",
-                                    base_id.txt
+                if policy.kind != DocumentKind::Policy {
+                    return Some((uri, diagnostics));
+                }
+                if let Some(base_id) = &policy.base_id {
+                    let range = self.encode_range(path, base_id.range);
+                    match self.find_policy_path_by_id(path, base_id.txt.as_str()) {
+                        None => diagnostics.extend(self.diagnostic(
+                            Rule::MissingBasePolicy,
+                            range,
+                            format!("Policy with ID {:?} does not exist!", base_id.txt),
+                        )),
+                        //Only found by looking outside the policy set
+                        Some(base) if self.policy_set(base) != self.policy_set(path) => {
+                            let warning = self.diagnostic(
+                                Rule::BasePolicyOutsideSet,
+                                range,
+                                format!(
+                                    "Policy with ID {:?} is not in this policy set, using the one in policy set {}",
+                                    base_id.txt,
+                                    self.policy_set(base).display()
                                 ),
-                            });
-                            info!("Calculated diagnostics {diagnostics:?} for file {path:?}");
+                            );
+                            diagnostics.extend(warning.map(|warning| Diagnostic {
+                                related_information: self
+                                    .location(base, self.policies[base].id_range)
+                                    .map(|location| {
+                                        vec![DiagnosticRelatedInformation {
+                                            location,
+                                            message: String::from("BasePolicy used"),
+                                        }]
+                                    }),
+                                ..warning
+                            }));
                         }
-                    },
+                        Some(_) => {}
+                    }
                 }
                 info!("Policy id {:?}", policy.id);
                 if policy.id.as_str() == "" {
-                    diagnostics.push(Diagnostic {
+                    diagnostics.extend(self.diagnostic(
+                        Rule::MissingPolicyId,
                         //TODO search for TrustFramework base tag
-                        range: null_range(),
-                        severity: Some(DiagnosticSeverity::ERROR),
-                        code: None,
-                        code_description: None,
-                        source: Some(String::from("IEF_LSP")),
-                        related_information: None,
-                        tags: None,
-                        data: None,
+                        null_range(),
                         //TODO liven this message up
-                        message: format!("Policy requires a Policy ID"),
-                    });
+                        String::from("Policy requires a Policy ID"),
+                    ));
+                }
+                let duplicates = self.same_id_in_set(path);
+                if !policy.id.is_empty() && !duplicates.is_empty() {
+                    let warning = self.diagnostic(
+                        Rule::DuplicatePolicyId,
+                        self.encode_range(path, policy.id_range),
+                        format!(
+                            "Policy ID {:?} is also used by {}, BasePolicy references to it are ambiguous",
+                            policy.id,
+                            duplicates
+                                .iter()
+                                .map(|p| file_name(p))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                    );
+                    diagnostics.extend(warning.map(|warning| Diagnostic {
+                        related_information: Some(
+                            duplicates
+                                .iter()
+                                .filter_map(|p| {
+                                    Some(DiagnosticRelatedInformation {
+                                        location: self.location(p, self.policies[*p].id_range)?,
+                                        message: String::from("Same Policy ID"),
+                                    })
+                                })
+                                .collect(),
+                        ),
+                        ..warning
+                    }));
                 }
-                return Some((to_uri(path), diagnostics));
+                info!("Calculated diagnostics {diagnostics:?} for file {path:?}");
+                Some((uri, diagnostics))
             })
             .collect()
     }
 }
 
-pub fn find_ief_files(path: &str) -> Vec<String> {
-    let mut path = path;
-    if path.starts_with("file://") {
-        path = &path[7..];
-    }
-    let path = Path::new(OsStr::new(path));
-    info!("{:?}", path);
-    if !path.exists() {
-        error!("Find IEF Files path does not exist! {:?}", path);
-        return vec![];
-    }
-    let metadata = match fs::metadata(path) {
-        Err(e) => return vec![],
-        Ok(meta) => meta,
+//The folder a root URI or path stands for, the parent when it names a file
+fn root_dir(root_path: &str) -> PathBuf {
+    let path = match Url::parse(root_path).map(|uri| uri.to_file_path()) {
+        Ok(Ok(path)) => path,
+        _ => PathBuf::from(root_path),
     };
-    let is_dir = metadata.file_type().is_dir();
-    let mut dir_path: &Path;
-    if is_dir {
-        dir_path = path;
-    } else {
-        dir_path = match path.parent() {
-            Some(p) => p,
-            None => {
-                error!("Non-directory with no parent! I'm not sure how this is possible");
-                return vec![];
-            }
+    if path.is_file() {
+        if let Some(parent) = path.parent() {
+            return parent.to_path_buf();
         }
     }
-    info!(
-        "Dir path is {} ",
-        dir_path.to_str().unwrap_or("no path str")
-    );
-    //Just in case
-    if !dir_path.exists() {
-        error!("Find IEF Files path does not exist! {:?}", path);
-        return vec![];
-    }
-    return match dir_path.read_dir() {
-        Err(e) => {
-            error!("Error reading directory {:?} \n {:?}", dir_path, e);
-            return vec![];
-        }
-        Ok(dir_res) => dir_res
-            .filter_map(|dir_entry_res| dir_entry_res.ok())
-            .map(|dir_entry| dir_entry.path())
-            .filter(|path_buf| path_buf.extension() == Some(&OsStr::new("xml")))
-            .filter_map(|path_buf| match path_buf.to_str() {
-                None => None,
-                Some(s) => Some(String::from(s)),
-            })
-            .collect(),
-    };
+    path.to_path_buf()
 }
 
-//FYI Url has thesse fns built in and I am a dummy
-//For now, we will just assume a local file system
-//
-fn to_uri(path: &str) -> String {
-    return format!("file://{}", path);
+//Key of a document in IEF_Workspace::policies
+fn policy_path(uri: &Url) -> Option<String> {
+    uri.to_file_path()
+        .ok()
+        .and_then(|p| p.to_str().map(String::from))
 }
-//Remove file prefix if it exists
-fn from_uri(path: &str) -> String {
-    let mut p = String::from(path);
-    p.replace("file://", "")
+
+//How many folders two paths have in common
+fn shared_folders(a: &str, b: &str) -> usize {
+    Path::new(a)
+        .components()
+        .zip(Path::new(b).components())
+        .take_while(|(a, b)| a == b)
+        .count()
 }
-//fn parse_app_settings(path: Option<String>) -> Option<String> {}
-pub fn new_workspace<'a>(root_path: &'a str) -> IEF_Workspace<'a> {
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(String::from)
+        .unwrap_or_else(|| String::from(path))
+}
+
+fn xml_parser() -> Parser {
     let mut parser = Parser::new();
     parser
         .set_language(&tree_sitter_xml::language_xml())
         .unwrap();
-    let policy_paths = find_ief_files(root_path);
+    parser
+}
+
+//fn parse_app_settings(path: Option<String>) -> Option<String> {}
+pub fn new_workspace(
+    root_path: &str,
+    encoding: PositionEncoding,
+    discovery: &Discovery,
+) -> IEF_Workspace {
+    let mut parser = xml_parser();
+    let files = FileFilter::new(&root_dir(root_path), discovery);
+    info!("Loading policies under {:?}", files.root());
+    let policy_paths: Vec<String> = files
+        .find_files()
+        .into_iter()
+        .filter_map(|path| path.to_str().map(String::from))
+        .collect();
+    let mut problems = vec![];
     let policies = HashMap::from_iter(policy_paths.iter().filter_map(|p| {
-        match IEF_Policy::new(&mut parser, p) {
-            None => None,
-            Some(pol) => Some((String::from(p), pol)),
+        match IEF_Policy::new(&mut parser, p, &mut problems) {
+            Ok(policy) => Some((String::from(p), Arc::new(policy))),
+            Err(e) => {
+                error!("{e}");
+                problems.push(e);
+                None
+            }
         }
     }));
-    return IEF_Workspace {
-        root_path,
+    let mut workspace = IEF_Workspace {
+        files,
         policies,
+        versions: HashMap::new(),
+        symbol_index: SymbolIndex::default(),
         parser,
+        encoding,
+        rules: RuleSeverities::default(),
+        problems,
+    };
+    workspace.reindex_all();
+    info!(
+        "Loaded {} policies and {} other XML documents",
+        workspace.trust_framework_policies().count(),
+        workspace.policies.len() - workspace.trust_framework_policies().count()
+    );
+    workspace
+}
+
+#[cfg(test)]
+mod test {
+    use lsp_types::{
+        DiagnosticSeverity, HoverContents, NumberOrString, Position, Range,
+        TextDocumentContentChangeEvent, TextEdit, Url,
+    };
+
+    use super::{Discovery, DocumentKind, IEF_Policy, PositionEncoding};
+    use crate::error::ServerError;
+
+    use super::fixtures::{
+        position_of, test_workspace, uri, BASE, BASE_PATH, EXT, EXT_PATH, RP, RP_PATH,
     };
+
+    #[test]
+    fn test_definition_of_claim_type_in_base() {
+        let ws = test_workspace();
+        let pos = position_of(RP, "ClaimTypeReferenceId=\"email", 0, 23);
+        let loc = ws.find_definition(&uri(RP_PATH), pos).unwrap();
+        assert_eq!(loc.uri, uri(BASE_PATH));
+        assert_eq!(loc.range.start, position_of(BASE, "email", 0, 0));
+    }
+
+    #[test]
+    fn test_definition_follows_inheritance_chain() {
+        let ws = test_workspace();
+        let pos = position_of(BASE, "TechnicalProfileReferenceId=", 0, 30);
+        let loc = ws.find_definition(&uri(BASE_PATH), pos).unwrap();
+        assert_eq!(loc.uri, uri(BASE_PATH));
+
+        let pos = position_of(RP, "SignUpOrSignIn", 0, 2);
+        let loc = ws.find_definition(&uri(RP_PATH), pos).unwrap();
+        assert_eq!(loc.uri, uri(BASE_PATH));
+        assert_eq!(loc.range.start, position_of(BASE, "SignUpOrSignIn", 0, 0));
+    }
+
+    #[test]
+    fn test_definition_of_base_policy() {
+        let ws = test_workspace();
+        let pos = position_of(RP, "B2C_1A_TrustFrameworkExtensions", 0, 4);
+        let loc = ws.find_definition(&uri(RP_PATH), pos).unwrap();
+        assert_eq!(loc.uri, uri(EXT_PATH));
+        assert_eq!(
+            loc.range.start,
+            position_of(EXT, "B2C_1A_TrustFrameworkExtensions", 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_definition_of_other_references() {
+        let ws = test_workspace();
+        let pos = position_of(BASE, "ReferenceId=\"CreateDisplayName", 0, 14);
+        let loc = ws.find_definition(&uri(BASE_PATH), pos).unwrap();
+        assert_eq!(
+            loc.range.start,
+            position_of(BASE, "CreateDisplayName", 0, 0)
+        );
+
+        let pos = position_of(BASE, "ContentDefinitionReferenceId", 0, 32);
+        let loc = ws.find_definition(&uri(BASE_PATH), pos).unwrap();
+        assert_eq!(
+            loc.range.start,
+            position_of(BASE, "api.signuporsignin", 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_declaration_is_base_most_definition() {
+        let ws = test_workspace();
+        let pos = position_of(EXT, "AAD-UserReadUsingObjectId", 0, 3);
+        let def = ws.find_definition(&uri(EXT_PATH), pos).unwrap();
+        assert_eq!(def.uri, uri(EXT_PATH));
+        let decl = ws.find_declaration(&uri(EXT_PATH), pos).unwrap();
+        assert_eq!(decl.uri, uri(BASE_PATH));
+        assert_eq!(
+            decl.range.start,
+            position_of(BASE, "AAD-UserReadUsingObjectId", 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_declaration_of_reference() {
+        let ws = test_workspace();
+        let pos = position_of(RP, "objectId", 0, 2);
+        let decl = ws.find_declaration(&uri(RP_PATH), pos).unwrap();
+        assert_eq!(decl.uri, uri(BASE_PATH));
+        assert_eq!(decl.range.start, position_of(BASE, "objectId", 0, 0));
+    }
+
+    #[test]
+    fn test_derived_policies() {
+        let ws = test_workspace();
+        assert_eq!(ws.derived_policies(BASE_PATH), vec![EXT_PATH, RP_PATH]);
+        assert!(ws.derived_policies(RP_PATH).is_empty());
+    }
+
+    #[test]
+    fn test_implementations_of_technical_profile() {
+        let ws = test_workspace();
+        let pos = position_of(BASE, "<Protocol", 0, 2);
+        let impls = ws.find_implementations(&uri(BASE_PATH), pos);
+        assert_eq!(impls.len(), 1);
+        assert_eq!(impls[0].uri, uri(EXT_PATH));
+        assert_eq!(
+            impls[0].range.start,
+            position_of(EXT, "AAD-UserReadUsingObjectId", 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_implementations_of_claims_provider() {
+        let ws = test_workspace();
+        let pos = position_of(BASE, "Azure Active Directory", 0, 2);
+        let impls = ws.find_implementations(&uri(BASE_PATH), pos);
+        assert_eq!(impls.len(), 1);
+        assert_eq!(impls[0].uri, uri(EXT_PATH));
+        assert_eq!(impls[0].range.start.line, 7);
+    }
+
+    #[test]
+    fn test_hover_merges_profile_across_chain() {
+        let ws = test_workspace();
+        let pos = position_of(BASE, "TechnicalProfileReferenceId=", 0, 30);
+        let hover = ws.hover(&uri(BASE_PATH), pos).unwrap();
+        let HoverContents::Markup(content) = hover.contents else {
+            panic!("Expected markdown hover");
+        };
+        //Seen from the base policy only the base layer applies
+        assert!(content
+            .value
+            .contains("Merged from TrustFrameworkBase.xml\n"));
+
+        let pos = position_of(EXT, "AAD-UserReadUsingObjectId", 0, 3);
+        let hover = ws.hover(&uri(EXT_PATH), pos).unwrap();
+        let HoverContents::Markup(content) = hover.contents else {
+            panic!("Expected markdown hover");
+        };
+        assert!(content
+            .value
+            .contains("Merged from TrustFrameworkBase.xml → TrustFrameworkExtensions.xml"));
+        assert!(content.value.contains(
+            "| RaiseErrorIfClaimsPrincipalDoesNotExist | false | TrustFrameworkExtensions.xml |"
+        ));
+        assert!(content
+            .value
+            .contains("- `displayName` - *TrustFrameworkExtensions.xml*"));
+
+        //A second starter pack in the same workspace, with the same file names
+        let mut ws = ws;
+        let social_base = uri("/policies/Social/TrustFrameworkBase.xml");
+        let social_ext = uri("/policies/Social/TrustFrameworkExtensions.xml");
+        ws.open_document(
+            social_base,
+            BASE.replace("B2C_1A_TrustFrameworkBase", "B2C_1A_SocialBase"),
+            1,
+        )
+        .unwrap();
+        let ext = EXT
+            .replace("B2C_1A_TrustFrameworkBase", "B2C_1A_SocialBase")
+            .replace("B2C_1A_TrustFrameworkExtensions", "B2C_1A_SocialExtensions");
+        ws.open_document(social_ext.clone(), ext.clone(), 1)
+            .unwrap();
+        let pos = position_of(&ext, "AAD-UserReadUsingObjectId", 0, 3);
+        let hover = ws.hover(&social_ext, pos).unwrap();
+        let HoverContents::Markup(content) = hover.contents else {
+            panic!("Expected markdown hover");
+        };
+        assert!(content.value.contains(
+            "Merged from Social/TrustFrameworkBase.xml → Social/TrustFrameworkExtensions.xml"
+        ));
+    }
+
+    #[test]
+    fn test_diamond_includes() {
+        let mut ws = test_workspace();
+        let profile = |id: &str, body: &str| {
+            format!("<TechnicalProfile Id=\"{id}\">{body}</TechnicalProfile>")
+        };
+        let include = |id: &str| format!("<IncludeTechnicalProfile ReferenceId=\"{id}\" />");
+        let profiles = [
+            profile("A", &(include("B") + &include("C"))),
+            profile("B", &(include("D") + "<DisplayName>b</DisplayName>")),
+            profile("C", &include("D")),
+            profile("D", "<DisplayName>d</DisplayName>"),
+            profile("E", &include("F")),
+            profile("F", &(include("E") + "<DisplayName>f</DisplayName>")),
+        ]
+        .concat();
+        let text = format!(
+            "<TrustFrameworkPolicy PolicyId=\"B2C_1A_Diamond\"><ClaimsProviders><ClaimsProvider><TechnicalProfiles>{profiles}</TechnicalProfiles></ClaimsProvider></ClaimsProviders></TrustFrameworkPolicy>"
+        );
+        let path = "/policies/Diamond.xml";
+        ws.open_document(uri(path), text, 1).unwrap();
+
+        //C reaches D again after B did, so D is merged again and wins over B
+        let a = ws.effective_profile(path, "A").unwrap();
+        assert_eq!(a.display_name.unwrap().value, "d");
+        //A real cycle still ends
+        let e = ws.effective_profile(path, "E").unwrap();
+        assert_eq!(e.display_name.unwrap().value, "f");
+    }
+
+    #[test]
+    fn test_hover_only_for_technical_profiles() {
+        let ws = test_workspace();
+        let pos = position_of(RP, "objectId", 0, 2);
+        assert!(ws.hover(&uri(RP_PATH), pos).is_none());
+    }
+
+    #[test]
+    fn test_completion_of_claim_types_through_chain() {
+        let ws = test_workspace();
+        let pos = position_of(RP, "ClaimTypeReferenceId=\"email", 0, 22);
+        let items = ws.completions(&uri(RP_PATH), pos);
+        let labels: Vec<_> = items.iter().map(|i| i.label.as_str()).collect();
+        assert_eq!(labels, vec!["objectId", "email", "displayName"]);
+        assert_eq!(
+            items[0].detail.as_deref(),
+            Some("ClaimType in TrustFrameworkBase.xml")
+        );
+    }
+
+    #[test]
+    fn test_completion_filters_by_kind() {
+        let ws = test_workspace();
+        let pos = position_of(RP, "ReferenceId=\"SignUpOrSignIn", 0, 13);
+        let items = ws.completions(&uri(RP_PATH), pos);
+        let labels: Vec<_> = items.iter().map(|i| i.label.as_str()).collect();
+        assert_eq!(labels, vec!["SignUpOrSignIn"]);
+    }
+
+    #[test]
+    fn test_references_across_policies() {
+        let ws = test_workspace();
+        let pos = position_of(BASE, "ClaimType Id=\"email", 0, 16);
+        let refs = ws.find_references(&uri(BASE_PATH), pos, true);
+        let found: Vec<_> = refs
+            .iter()
+            .map(|l| (l.uri.path().to_string(), l.range.start))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (RP_PATH.to_string(), position_of(RP, "email", 0, 0)),
+                (BASE_PATH.to_string(), position_of(BASE, "email", 0, 0)),
+                (BASE_PATH.to_string(), position_of(BASE, "email", 1, 0)),
+                (BASE_PATH.to_string(), position_of(BASE, "email", 2, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_references_include_overrides() {
+        let ws = test_workspace();
+        let pos = position_of(BASE, "AAD-UserReadUsingObjectId", 0, 3);
+        let refs = ws.find_references(&uri(BASE_PATH), pos, true);
+        assert_eq!(refs.len(), 3);
+        assert!(refs.iter().any(|l| l.uri == uri(EXT_PATH)));
+
+        let refs = ws.find_references(&uri(BASE_PATH), pos, false);
+        assert_eq!(refs.len(), 2);
+        assert!(!refs.iter().any(|l| l.uri == uri(BASE_PATH)
+            && l.range.start == position_of(BASE, "AAD-UserReadUsingObjectId", 0, 0)));
+    }
+
+    #[test]
+    fn test_references_of_policy_id() {
+        let ws = test_workspace();
+        let pos = position_of(BASE, "B2C_1A_TrustFrameworkBase", 0, 1);
+        let refs = ws.find_references(&uri(BASE_PATH), pos, false);
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].uri, uri(EXT_PATH));
+    }
+
+    #[test]
+    fn test_policy_chain() {
+        let ws = test_workspace();
+        assert_eq!(ws.policy_chain(RP_PATH), vec![RP_PATH, EXT_PATH, BASE_PATH]);
+    }
+
+    #[test]
+    fn test_rename_claim_type_across_policies() {
+        let ws = test_workspace();
+        let pos = position_of(RP, "email", 0, 2);
+        let edit = ws
+            .rename(&uri(RP_PATH), pos, "emailAddress")
+            .unwrap()
+            .unwrap();
+        let changes = edit.changes.unwrap();
+        assert_eq!(changes[&uri(BASE_PATH)].len(), 3);
+        assert_eq!(changes[&uri(RP_PATH)].len(), 1);
+        assert!(!changes.contains_key(&uri(EXT_PATH)));
+        assert!(changes[&uri(BASE_PATH)]
+            .iter()
+            .all(|e| e.new_text == "emailAddress"));
+    }
+
+    #[test]
+    fn test_rename_technical_profile_includes_overrides() {
+        let ws = test_workspace();
+        let pos = position_of(BASE, "TechnicalProfile Id=\"AAD", 0, 22);
+        let changes = ws
+            .rename(&uri(BASE_PATH), pos, "AAD-Read")
+            .unwrap()
+            .unwrap()
+            .changes
+            .unwrap();
+        assert_eq!(changes[&uri(BASE_PATH)].len(), 2);
+        assert_eq!(
+            changes[&uri(EXT_PATH)][0].range.start,
+            position_of(EXT, "AAD-UserReadUsingObjectId", 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_rename_policy_id() {
+        let ws = test_workspace();
+        let pos = position_of(BASE, "B2C_1A_TrustFrameworkBase", 0, 3);
+        let (range, id) = ws.prepare_rename(&uri(BASE_PATH), pos).unwrap();
+        assert_eq!(id, "B2C_1A_TrustFrameworkBase");
+        assert_eq!(
+            range.start,
+            position_of(BASE, "B2C_1A_TrustFrameworkBase", 0, 0)
+        );
+        let changes = ws
+            .rename(&uri(BASE_PATH), pos, "B2C_1A_Base")
+            .unwrap()
+            .unwrap()
+            .changes
+            .unwrap();
+        assert_eq!(changes[&uri(BASE_PATH)].len(), 1);
+        assert_eq!(changes[&uri(EXT_PATH)].len(), 1);
+    }
+
+    #[test]
+    fn test_rename_rejects_invalid_and_existing_ids() {
+        let ws = test_workspace();
+        let pos = position_of(BASE, "ClaimType Id=\"email", 0, 16);
+        assert!(ws.rename(&uri(BASE_PATH), pos, "e mail").is_err());
+        assert!(ws.rename(&uri(BASE_PATH), pos, "objectId").is_err());
+        let pos = position_of(BASE, "<ClaimsSchema>", 0, 3);
+        assert!(ws.prepare_rename(&uri(BASE_PATH), pos).is_none());
+        assert!(ws.rename(&uri(BASE_PATH), pos, "x").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_workspace_symbols() {
+        let ws = test_workspace();
+        let symbols = ws.workspace_symbols("AAD-UserRead");
+        let found: Vec<_> = symbols
+            .iter()
+            .map(|s| (s.name.as_str(), s.location.uri.path()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("AAD-UserReadUsingObjectId", BASE_PATH),
+                ("AAD-UserReadUsingObjectId", EXT_PATH)
+            ]
+        );
+        assert_eq!(
+            symbols[0].container_name.as_deref(),
+            Some("TechnicalProfile in TrustFrameworkBase.xml")
+        );
+    }
+
+    #[test]
+    fn test_workspace_symbols_follow_edits() {
+        let mut ws = test_workspace();
+        let start = position_of(RP, "PolicyProfile", 0, 0);
+        ws.update_document(
+            uri(RP_PATH),
+            2,
+            vec![TextDocumentContentChangeEvent {
+                range: Some(Range::new(start, start)),
+                range_length: None,
+                text: String::from("Main"),
+            }],
+        )
+        .unwrap();
+        let names: Vec<_> = ws
+            .workspace_symbols("PolicyProfile")
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        assert_eq!(names, vec!["MainPolicyProfile"]);
+    }
+
+    #[test]
+    fn test_utf16_positions_after_non_ascii_text() {
+        let mut ws = test_workspace();
+        let tag = position_of(RP, "<OutputClaim ClaimTypeReferenceId=\"email", 0, 0);
+        //4 UTF-16 units, 10 bytes
+        ws.update_document(
+            uri(RP_PATH),
+            2,
+            vec![TextDocumentContentChangeEvent {
+                range: Some(Range::new(tag, tag)),
+                range_length: None,
+                text: String::from("<!--é😀-->"),
+            }],
+        )
+        .unwrap();
+        let email = Position::new(tag.line, tag.character + 10 + 35);
+        let loc = ws.find_definition(&uri(RP_PATH), email).unwrap();
+        assert_eq!(loc.range.start, position_of(BASE, "email", 0, 0));
+        let pos = position_of(BASE, "ClaimType Id=\"email", 0, 16);
+        let refs = ws.find_references(&uri(BASE_PATH), pos, false);
+        let in_rp: Vec<_> = refs
+            .iter()
+            .filter(|l| l.uri == uri(RP_PATH))
+            .map(|l| l.range)
+            .collect();
+        assert_eq!(
+            in_rp,
+            vec![Range::new(
+                email,
+                Position::new(email.line, email.character + 5)
+            )]
+        );
+    }
+
+    #[test]
+    fn test_policies_saved_by_windows_tools() {
+        let dir = std::env::temp_dir().join(format!("ief_lsp_encodings_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let base = BASE.replace('\n', "\r\n");
+        let rp = RP.replace('\n', "\r\n");
+        let mut utf16 = vec![0xFF, 0xFE];
+        utf16.extend(base.encode_utf16().flat_map(u16::to_le_bytes));
+        let utf8_bom = [&[0xEF, 0xBB, 0xBF], rp.as_bytes()].concat();
+        let base_path = dir.join("TrustFrameworkBase.xml");
+        let rp_path = dir.join("SignUpOrSignin.xml");
+        std::fs::write(&base_path, &utf16).unwrap();
+        std::fs::write(&rp_path, &utf8_bom).unwrap();
+
+        let root = dir.to_str().unwrap().to_string();
+        let mut ws = super::new_workspace(&root, PositionEncoding::Utf16, &Discovery::default());
+        let base_uri = Url::from_file_path(&base_path).unwrap();
+        let base_path = base_path.to_str().unwrap();
+        let rp_path = rp_path.to_str().unwrap();
+        assert_eq!(ws.policies[base_path].id, "B2C_1A_TrustFrameworkBase");
+        assert_eq!(ws.policies[base_path].to_bytes(), utf16);
+        assert_eq!(ws.policies[rp_path].to_bytes(), utf8_bom);
+
+        //Line and column are the same with \r\n as with \n
+        let email = position_of(RP, "email", 0, 0);
+        let loc = ws
+            .find_definition(&Url::from_file_path(rp_path).unwrap(), email)
+            .unwrap();
+        assert_eq!(loc.uri, Url::from_file_path(base_path).unwrap());
+        assert_eq!(
+            loc.range,
+            Range::new(
+                position_of(BASE, "email", 0, 0),
+                position_of(BASE, "email", 0, 5)
+            )
+        );
+
+        //Written back byte for byte, nothing to parse again
+        let loaded = ws.policies[base_path].clone();
+        ws.file_changed(&base_uri);
+        assert!(std::sync::Arc::ptr_eq(&loaded, &ws.policies[base_path]));
+        let mut renamed = vec![0xFF, 0xFE];
+        renamed.extend(
+            base.replace("B2C_1A_TrustFrameworkBase", "B2C_1A_Renamed")
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes),
+        );
+        std::fs::write(base_path, &renamed).unwrap();
+        ws.file_changed(&base_uri);
+        assert_eq!(ws.policies[base_path].id, "B2C_1A_Renamed");
+        assert_eq!(ws.policies[base_path].to_bytes(), renamed);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_document_opened_outside_root() {
+        let mut ws = test_workspace();
+        let outside = Url::from_file_path("/elsewhere/Scratch.xml").unwrap();
+        let text = RP.replace("B2C_1A_signup_signin", "B2C_1A_scratch");
+        ws.open_document(outside.clone(), text, 1).unwrap();
+        assert_eq!(ws.document_version(&outside), Some(1));
+        let email = position_of(RP, "email", 0, 0);
+        let loc = ws.find_definition(&outside, email).unwrap();
+        assert_eq!(loc.uri, uri(BASE_PATH));
+        assert!(ws
+            .workspace_symbols("PolicyProfile")
+            .iter()
+            .any(|symbol| symbol.location.uri == outside));
+
+        assert!(!ws.close_document(&outside));
+        assert_eq!(ws.document_version(&outside), None);
+        assert!(ws.find_definition(&outside, email).is_none());
+        assert!(ws
+            .workspace_symbols("PolicyProfile")
+            .iter()
+            .all(|symbol| symbol.location.uri != outside));
+    }
+
+    #[test]
+    fn test_policy_sets() {
+        let mut ws = test_workspace();
+        let social = |name: &str| format!("/policies/SocialAccounts/{name}");
+        let social_base = BASE.replace("\"email\"", "\"signInName\"");
+        for (path, text) in [
+            (social("TrustFrameworkBase.xml"), social_base.as_str()),
+            (social("TrustFrameworkExtensions.xml"), EXT),
+            (social("SignUpOrSignin.xml"), RP),
+        ] {
+            ws.open_document(uri(&path), String::from(text), 1).unwrap();
+        }
+        assert_eq!(
+            ws.policy_chain(&social("SignUpOrSignin.xml")),
+            vec![
+                &social("SignUpOrSignin.xml"),
+                &social("TrustFrameworkExtensions.xml"),
+                &social("TrustFrameworkBase.xml")
+            ]
+        );
+        assert_eq!(ws.policy_chain(RP_PATH), vec![RP_PATH, EXT_PATH, BASE_PATH]);
+        assert_eq!(
+            ws.find_policy_path_by_id(RP_PATH, "B2C_1A_TrustFrameworkBase"),
+            Some(&String::from(BASE_PATH))
+        );
+
+        //Each starter pack only sees its own claims
+        let email = position_of(BASE, "email", 0, 0);
+        let references = ws.find_references(&uri(BASE_PATH), email, true);
+        assert!(!references.is_empty());
+        assert!(references
+            .iter()
+            .all(|loc| !loc.uri.path().contains("SocialAccounts")));
+        let email = position_of(RP, "email", 0, 0);
+        assert!(ws
+            .find_definition(&uri(&social("SignUpOrSignin.xml")), email)
+            .is_none());
+        let diagnostics = ws.get_diagnostics();
+        assert!(diagnostics.values().all(|d| d.is_empty()));
+
+        //The same PolicyId twice in one set is a real problem
+        let copy = uri("/policies/TrustFrameworkBaseCopy.xml");
+        ws.open_document(copy.clone(), String::from(BASE), 1)
+            .unwrap();
+        let diagnostics = ws.get_diagnostics();
+        for document in [&copy, &uri(BASE_PATH)] {
+            let warning = &diagnostics[document];
+            assert_eq!(warning.len(), 1);
+            assert_eq!(warning[0].severity, Some(DiagnosticSeverity::WARNING));
+            assert_eq!(warning[0].range, ws.policies[BASE_PATH].id_range);
+            assert_eq!(warning[0].related_information.as_ref().unwrap().len(), 1);
+            assert_eq!(
+                warning[0].code,
+                Some(NumberOrString::String(String::from("duplicate-policy-id")))
+            );
+        }
+        assert!(diagnostics[&uri(&social("TrustFrameworkBase.xml"))].is_empty());
+
+        //Unless the settings say it is not
+        ws.set_rules(
+            serde_json::from_value(serde_json::json!({"duplicate-policy-id": "off"})).unwrap(),
+        );
+        assert!(ws.get_diagnostics().values().all(|d| d.is_empty()));
+    }
+
+    #[test]
+    fn test_base_policy_outside_set() {
+        let mut ws = test_workspace();
+        //Two sets with B2C_1A_TrustFrameworkBase, and one without it
+        let local_base = "/policies/LocalAccounts/TrustFrameworkBase.xml";
+        let social_ext = "/policies/SocialAccounts/TrustFrameworkExtensions.xml";
+        ws.open_document(uri(local_base), String::from(BASE), 1)
+            .unwrap();
+        ws.open_document(uri(social_ext), String::from(EXT), 1)
+            .unwrap();
+        assert_eq!(
+            ws.find_policy_path_by_id(EXT_PATH, "B2C_1A_TrustFrameworkBase"),
+            Some(&String::from(BASE_PATH))
+        );
+        assert_eq!(
+            ws.find_policy_path_by_id(local_base, "B2C_1A_TrustFrameworkBase"),
+            Some(&String::from(local_base))
+        );
+
+        let resolved = ws
+            .find_policy_path_by_id(social_ext, "B2C_1A_TrustFrameworkBase")
+            .unwrap()
+            .clone();
+        let resolved_set = ws.policy_set(&resolved);
+        assert_ne!(
+            resolved_set,
+            std::path::Path::new("/policies/SocialAccounts")
+        );
+        let diagnostics = ws.get_diagnostics();
+        let warning = &diagnostics[&uri(social_ext)];
+        assert_eq!(warning.len(), 1);
+        assert_eq!(warning[0].severity, Some(DiagnosticSeverity::WARNING));
+        assert_eq!(
+            warning[0].code,
+            Some(NumberOrString::String(String::from(
+                "base-policy-outside-set"
+            )))
+        );
+        assert!(warning[0]
+            .message
+            .ends_with(&format!("policy set {}", resolved_set.display())));
+        let related = warning[0].related_information.as_ref().unwrap();
+        assert_eq!(related[0].location.uri, uri(&resolved));
+        //Within their own sets nothing is ambiguous
+        for path in [EXT_PATH, RP_PATH, local_base] {
+            assert!(diagnostics[&uri(path)].is_empty());
+        }
+    }
+
+    #[test]
+    fn test_auxiliary_documents() {
+        let mut ws = test_workspace();
+        let metadata = uri("/policies/Metadata/idp.xml");
+        ws.open_document(
+            metadata.clone(),
+            String::from(
+                "<md:EntityDescriptor xmlns:md=\"urn:oasis:names:tc:SAML:2.0:metadata\" entityID=\"https://idp.example.com\" />",
+            ),
+            1,
+        )
+        .unwrap();
+        let project = uri("/policies/Policies.csproj.xml");
+        let text = "<Project><ItemGroup><ClaimType Id=\"email\" /></ItemGroup></Project>";
+        ws.open_document(project.clone(), String::from(text), 1)
+            .unwrap();
+        assert_eq!(
+            ws.policies["/policies/Metadata/idp.xml"].kind,
+            DocumentKind::SamlMetadata
+        );
+
+        //Known, but neither checked nor searched
+        let diagnostics = ws.get_diagnostics();
+        assert_eq!(diagnostics[&metadata], vec![]);
+        assert_eq!(diagnostics[&project], vec![]);
+        assert!(ws
+            .workspace_symbols("email")
+            .iter()
+            .all(|symbol| symbol.location.uri != project));
+        let email = position_of(BASE, "email", 0, 0);
+        assert!(ws
+            .find_references(&uri(BASE_PATH), email, true)
+            .iter()
+            .all(|loc| loc.uri != project));
+
+        //Becomes a policy as soon as the root element says so
+        ws.update_document(
+            project.clone(),
+            2,
+            vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: RP.replace("B2C_1A_signup_signin", "B2C_1A_project"),
+            }],
+        )
+        .unwrap();
+        assert!(ws
+            .find_policy_path_by_id(RP_PATH, "B2C_1A_project")
+            .is_some());
+        assert!(ws
+            .workspace_symbols("PolicyProfile")
+            .iter()
+            .any(|symbol| symbol.location.uri == project));
+    }
+
+    #[test]
+    fn test_links_to_auxiliary_documents() {
+        let mut ws = test_workspace();
+        let policy = "<TrustFrameworkPolicy PolicyId=\"B2C_1A_Saml\">
+  <BuildingBlocks>
+    <ContentDefinitions>
+      <ContentDefinition Id=\"api.signup\">
+        <LocalizationResourcesReferences>
+          <LocalizedResourcesReference Language=\"en\" LocalizedResourcesReferenceId=\"api.signup.en\" />
+        </LocalizationResourcesReferences>
+      </ContentDefinition>
+    </ContentDefinitions>
+  </BuildingBlocks>
+  <ClaimsProviders>
+    <ClaimsProvider>
+      <TechnicalProfiles>
+        <TechnicalProfile Id=\"Contoso-SAML2\">
+          <Metadata>
+            <Item Key=\"PartnerMetadata\">https://idp.example.com</Item>
+            <Item Key=\"PartnerEntity\">https://contoso.com/federation/IdP.xml</Item>
+            <Item Key=\"RedirectURI\">https://contoso.com/federation/other.xml</Item>
+          </Metadata>
+        </TechnicalProfile>
+      </TechnicalProfiles>
+    </ClaimsProvider>
+  </ClaimsProviders>
+</TrustFrameworkPolicy>";
+        let saml = uri("/policies/TrustFrameworkSaml.xml");
+        ws.open_document(saml.clone(), String::from(policy), 1)
+            .unwrap();
+        let by_entity = uri("/policies/Metadata/entity.xml");
+        let by_file = uri("/policies/Metadata/idp.xml");
+        let localization = uri("/policies/Localization/api.signup.en.xml");
+        for (document, text) in [
+            (
+                &by_entity,
+                "<md:EntityDescriptor xmlns:md=\"urn:oasis:names:tc:SAML:2.0:metadata\" entityID=\"https://idp.example.com\" />",
+            ),
+            (
+                &by_file,
+                "<md:EntityDescriptor xmlns:md=\"urn:oasis:names:tc:SAML:2.0:metadata\" entityID=\"https://other.example.com\" />",
+            ),
+            (
+                &localization,
+                "<LocalizedResources Id=\"api.signup.en\">\n  <LocalizedStrings />\n</LocalizedResources>",
+            ),
+        ] {
+            ws.open_document(document.clone(), String::from(text), 1)
+                .unwrap();
+        }
+
+        let definition = |needle, offset| {
+            ws.find_definition(&saml, position_of(policy, needle, 0, offset))
+                .map(|location| (location.uri, location.range.start))
+        };
+        assert_eq!(
+            definition("https://idp", 3),
+            Some((by_entity.clone(), Position::new(0, 0)))
+        );
+        assert_eq!(
+            definition("https://contoso.com/federation/IdP", 3),
+            Some((by_file.clone(), Position::new(0, 0)))
+        );
+        assert_eq!(definition("https://contoso.com/federation/other", 3), None);
+        assert_eq!(
+            definition("api.signup.en", 2),
+            Some((localization.clone(), Position::new(0, 24)))
+        );
+    }
+
+    #[test]
+    fn test_closing_falls_back_to_disk() {
+        let dir = std::env::temp_dir().join(format!("ief_lsp_overlay_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("TrustFrameworkBase.xml");
+        std::fs::write(&path, BASE).unwrap();
+        let root = dir.to_str().unwrap().to_string();
+        let mut ws = super::new_workspace(&root, PositionEncoding::Utf16, &Discovery::default());
+        let document = Url::from_file_path(&path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let buffer = BASE.replace("B2C_1A_TrustFrameworkBase", "B2C_1A_Unsaved");
+        ws.open_document(document.clone(), buffer, 4).unwrap();
+        assert_eq!(ws.policies[path].id, "B2C_1A_Unsaved");
+        ws.update_document(
+            document.clone(),
+            5,
+            vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: BASE.replace("B2C_1A_TrustFrameworkBase", "B2C_1A_StillUnsaved"),
+            }],
+        )
+        .unwrap();
+        assert_eq!(ws.document_version(&document), Some(5));
+        assert_eq!(ws.policies[path].id, "B2C_1A_StillUnsaved");
+
+        assert!(ws.close_document(&document));
+        assert_eq!(ws.document_version(&document), None);
+        assert_eq!(ws.policies[path].id, "B2C_1A_TrustFrameworkBase");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_paths_with_spaces() {
+        let dir = std::env::temp_dir().join(format!("ief_lsp spaces_{}", std::process::id()));
+        let folder = dir.join("My Policies");
+        std::fs::create_dir_all(&folder).unwrap();
+        let base = folder.join("TrustFrameworkBase.xml");
+        let rp = folder.join("SignUp Or Signin.xml");
+        std::fs::write(&base, BASE).unwrap();
+        std::fs::write(&rp, RP).unwrap();
+
+        //The root may come as a URI
+        let root = Url::from_directory_path(&dir).unwrap();
+        let ws = super::new_workspace(
+            root.as_str(),
+            PositionEncoding::Utf16,
+            &Discovery::default(),
+        );
+        let base_uri = Url::from_file_path(&base).unwrap();
+        let rp_uri = Url::from_file_path(&rp).unwrap();
+        assert!(rp_uri
+            .as_str()
+            .ends_with("/My%20Policies/SignUp%20Or%20Signin.xml"));
+
+        //RP's BasePolicy, TrustFrameworkExtensions, is missing
+        let diagnostics = ws.get_diagnostics();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[&rp_uri].len(), 1);
+        assert!(diagnostics[&base_uri].is_empty());
+
+        let email = position_of(RP, "email", 0, 0);
+        let loc = ws.find_definition(&rp_uri, email).unwrap();
+        assert_eq!(loc.uri, base_uri);
+        assert_eq!(loc.uri.to_file_path().unwrap(), base);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_changed_on_disk() {
+        let dir = std::env::temp_dir().join(format!("ief_lsp_watched_{}", std::process::id()));
+        let base = dir.join("TrustFrameworkBase.xml");
+        let rp = dir.join("SignUp").join("SignUpOrSignin.xml");
+        std::fs::create_dir_all(rp.parent().unwrap()).unwrap();
+        std::fs::write(&base, BASE).unwrap();
+        let root = dir.to_str().unwrap().to_string();
+        let mut ws = super::new_workspace(&root, PositionEncoding::Utf16, &Discovery::default());
+        let base_uri = Url::from_file_path(&base).unwrap();
+        let rp_uri = Url::from_file_path(&rp).unwrap();
+        assert_eq!(ws.get_diagnostics().len(), 1);
+
+        //Created, by a checkout say
+        let rp_text = RP.replace(
+            "B2C_1A_TrustFrameworkExtensions",
+            "B2C_1A_TrustFrameworkBase",
+        );
+        std::fs::write(&rp, rp_text).unwrap();
+        assert_eq!(ws.file_changed(&rp_uri), vec![]);
+        let email = position_of(RP, "email", 0, 0);
+        assert_eq!(ws.find_definition(&rp_uri, email).unwrap().uri, base_uri);
+
+        //Changed underneath an open buffer, the buffer wins
+        ws.open_document(base_uri.clone(), String::from(BASE), 1)
+            .unwrap();
+        std::fs::write(
+            &base,
+            BASE.replace("B2C_1A_TrustFrameworkBase", "B2C_1A_Other"),
+        )
+        .unwrap();
+        assert_eq!(ws.file_changed(&base_uri), vec![]);
+        assert!(ws
+            .find_policy_path_by_id(RP_PATH, "B2C_1A_TrustFrameworkBase")
+            .is_some());
+        //Changed while closed, and the relying party loses its base policy
+        ws.close_document(&base_uri);
+        assert!(ws.find_policy_path_by_id(RP_PATH, "B2C_1A_Other").is_some());
+        assert_eq!(ws.get_diagnostics()[&rp_uri].len(), 1);
+
+        //The whole directory is deleted
+        std::fs::remove_dir_all(rp.parent().unwrap()).unwrap();
+        let sign_up = Url::from_file_path(dir.join("SignUp")).unwrap();
+        assert_eq!(ws.file_changed(&sign_up), vec![rp_uri.clone()]);
+        assert!(!ws.get_diagnostics().contains_key(&rp_uri));
+        assert!(ws.workspace_symbols("PolicyProfile").is_empty());
+
+        //Excluded and outside files are not picked up
+        let built = dir.join("Environments").join("TrustFrameworkBase.xml");
+        std::fs::create_dir_all(built.parent().unwrap()).unwrap();
+        std::fs::write(&built, BASE).unwrap();
+        ws.file_changed(&Url::from_file_path(&built).unwrap());
+        ws.file_changed(&Url::from_file_path(dir.join("Environments")).unwrap());
+        assert_eq!(ws.policies.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_discovery_changed() {
+        let dir = std::env::temp_dir().join(format!("ief_lsp_discovery_{}", std::process::id()));
+        let base = dir.join("TrustFrameworkBase.xml");
+        let built = dir.join("Environments").join("TrustFrameworkBase.xml");
+        std::fs::create_dir_all(built.parent().unwrap()).unwrap();
+        std::fs::write(&base, BASE).unwrap();
+        std::fs::write(&built, BASE).unwrap();
+        let root = dir.to_str().unwrap().to_string();
+        let mut ws = super::new_workspace(&root, PositionEncoding::Utf16, &Discovery::default());
+        assert_eq!(ws.policies.len(), 1);
+
+        //Without the default excludes the built copy shows up
+        let discovery = Discovery {
+            exclude: vec![],
+            ..Discovery::default()
+        };
+        assert_eq!(ws.set_discovery(&discovery), vec![]);
+        assert_eq!(ws.policies.len(), 2);
+
+        //Leaving out the root policies forgets them, unless they are open
+        let built_uri = Url::from_file_path(&built).unwrap();
+        ws.open_document(built_uri.clone(), String::from(BASE), 1)
+            .unwrap();
+        let discovery = Discovery {
+            include: vec![String::from("SignUp/**/*.xml")],
+            ..discovery
+        };
+        assert_eq!(
+            ws.set_discovery(&discovery),
+            vec![Url::from_file_path(&base).unwrap()]
+        );
+        assert_eq!(ws.policies.len(), 1);
+        assert!(ws.document_version(&built_uri).is_some());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    //xorshift, so failures are reproducible without pulling in a crate
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    //Byte offset in text to an LSP position with a UTF-16 column
+    fn utf16_position(text: &str, byte: usize) -> Position {
+        let line_start = text[..byte].rfind('\n').map_or(0, |i| i + 1);
+        let line = text[..byte].matches('\n').count();
+        let character: usize = text[line_start..byte].chars().map(char::len_utf16).sum();
+        Position::new(line as u32, character as u32)
+    }
+
+    //Never between the \r and \n of a line break, a client cannot point there
+    fn char_boundary(text: &str, random: &mut Random) -> usize {
+        let mut byte = random.below(text.len() + 1);
+        while !text.is_char_boundary(byte) || text[..byte].ends_with('\r') {
+            byte -= 1;
+        }
+        byte
+    }
+
+    #[test]
+    fn test_random_edits_match_fresh_parse() {
+        const INSERTS: &[&str] = &[
+            "",
+            "x",
+            "é😀",
+            "\n",
+            "\r\n  ",
+            "<ClaimType Id=\"new\">\n</ClaimType>\n",
+            "\"",
+            "<",
+            "/>",
+            "Id=\"a\" ",
+            "<!-- ü -->",
+        ];
+        let mut parser = tree_sitter::Parser::new();
+        parser
+            .set_language(&tree_sitter_xml::language_xml())
+            .unwrap();
+        //Half of the edits are undone right away, which walks in and out of
+        //broken states instead of only piling up errors
+        for seed in 1..=20 {
+            let mut random = Random(seed * 7919);
+            let mut expected = String::from(BASE);
+            let mut policy = IEF_Policy::from_text(&mut parser, expected.clone()).unwrap();
+            for step in 0..40 {
+                let a = char_boundary(&expected, &mut random);
+                let b = char_boundary(&expected, &mut random);
+                let start = a.min(b);
+                let mut end = a.max(b).min(start + 40);
+                while !expected.is_char_boundary(end) || expected[..end].ends_with('\r') {
+                    end -= 1;
+                }
+                let new_text = INSERTS[random.below(INSERTS.len())];
+                let old_text = String::from(&expected[start..end]);
+                let mut edits = vec![(start, end, String::from(new_text))];
+                if random.below(2) == 0 {
+                    edits.push((start, start + new_text.len(), old_text));
+                }
+                for (start, end, new_text) in edits {
+                    let edit = TextEdit {
+                        range: Range::new(
+                            utf16_position(&expected, start),
+                            utf16_position(&expected, end),
+                        ),
+                        new_text: new_text.clone(),
+                    };
+                    expected.replace_range(start..end, &new_text);
+                    policy.handle_edit(&mut parser, &edit, PositionEncoding::Utf16);
+                    assert_eq!(policy.text.text(), expected, "seed {seed} step {step}");
+                    let fresh = parser.parse(&expected, None).unwrap();
+                    assert_eq!(
+                        policy.tree.root_node().to_sexp(),
+                        fresh.root_node().to_sexp(),
+                        "seed {seed} step {step}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_full_document_sync() {
+        let mut ws = test_workspace();
+        ws.update_document(
+            uri(RP_PATH),
+            2,
+            vec![
+                TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text: String::from(EXT),
+                },
+                TextDocumentContentChangeEvent {
+                    range: Some(Range::new(Position::new(0, 0), Position::new(0, 0))),
+                    range_length: None,
+                    text: String::from("<?xml version=\"1.0\"?>\n"),
+                },
+            ],
+        )
+        .unwrap();
+        let policy = &ws.policies[RP_PATH];
+        assert_eq!(policy.id, "B2C_1A_TrustFrameworkExtensions");
+        assert!(policy.text.text().starts_with("<?xml"));
+        assert!(!policy.tree.root_node().has_error());
+    }
+
+    #[test]
+    fn test_document_errors() {
+        let mut ws = test_workspace();
+        let past_end = Position::new(BASE.lines().count().max(RP.lines().count()) as u32 + 5, 0);
+        let change = |range| TextDocumentContentChangeEvent {
+            range: Some(range),
+            range_length: None,
+            text: String::from("<!-- -->"),
+        };
+        ws.update_document(uri(RP_PATH), 2, vec![]).unwrap();
+        let result = ws.update_document(
+            uri(RP_PATH),
+            3,
+            vec![
+                change(Range::new(Position::new(0, 0), Position::new(0, 0))),
+                change(Range::new(past_end, past_end)),
+                change(Range::new(Position::new(1, 0), Position::new(1, 0))),
+            ],
+        );
+        assert!(matches!(result, Err(ServerError::PositionOutOfRange(_, pos)) if pos == past_end));
+        //Diagnostics are not published for a version the text does not match
+        assert_eq!(ws.document_version(&uri(RP_PATH)), Some(2));
+        //Applied up to the change that failed
+        assert_eq!(
+            ws.policies[RP_PATH].text.text().matches("<!-- -->").count(),
+            1
+        );
+
+        let unknown = uri("/policies/Unknown.xml");
+        assert!(matches!(
+            ws.update_document(unknown.clone(), 1, vec![]),
+            Err(ServerError::UnknownDocument(_))
+        ));
+        assert!(ws.check_document(&unknown).is_err());
+        assert!(ws.check_position(&uri(BASE_PATH), past_end).is_err());
+        assert!(ws.find_definition(&uri(BASE_PATH), past_end).is_none());
+        //Columns past the end of a line are clamped
+        assert!(ws
+            .check_position(&uri(BASE_PATH), Position::new(0, 500))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_problems_loading_files() {
+        let dir = std::env::temp_dir().join(format!("ief_lsp_problems_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut latin = BASE.as_bytes().to_vec();
+        latin.extend([0xFF, 0xFE, 0x00]);
+        std::fs::write(dir.join("TrustFrameworkBase.xml"), &latin).unwrap();
+        let root = dir.to_str().unwrap().to_string();
+        let mut ws = super::new_workspace(&root, PositionEncoding::Utf16, &Discovery::default());
+        let problems = ws.take_problems();
+        assert!(matches!(problems[..], [ServerError::Encoding(_, "UTF-8")]));
+        assert!(ws.take_problems().is_empty());
+        //The file is still of use
+        assert_eq!(ws.trust_framework_policies().count(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
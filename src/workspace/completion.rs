@@ -0,0 +1,537 @@
+use lsp_types::{
+    CompletionItem, CompletionItemKind, CompletionTextEdit, InsertTextFormat, Position, Range,
+    TextEdit,
+};
+use tree_sitter::Node;
+
+use super::queries::{
+    attribute_parts, child_elements, get_attribute, get_tag_name, parse_tag, start_tag,
+    value_range, Source,
+};
+use super::schema::element_model;
+use super::symbols::ElementKind;
+
+pub fn completion_kind(kind: ElementKind) -> CompletionItemKind {
+    match kind {
+        ElementKind::ClaimType => CompletionItemKind::FIELD,
+        ElementKind::ClaimsTransformation => CompletionItemKind::FUNCTION,
+        ElementKind::TechnicalProfile => CompletionItemKind::CLASS,
+        ElementKind::UserJourney | ElementKind::SubJourney => CompletionItemKind::MODULE,
+        ElementKind::ContentDefinition | ElementKind::LocalizedResources => {
+            CompletionItemKind::FILE
+        }
+        ElementKind::DisplayControl => CompletionItemKind::INTERFACE,
+        ElementKind::Predicate | ElementKind::PredicateValidation => CompletionItemKind::ENUM,
+        ElementKind::Policy => CompletionItemKind::REFERENCE,
+    }
+}
+
+fn contains(range: &Range, pos: Position) -> bool {
+    range.start <= pos && pos <= range.end
+}
+
+//The kind of element the reference attribute under the cursor expects and the
+//range of its current value
+pub fn reference_context(
+    root_node: &Node,
+    text: &(impl Source + ?Sized),
+    pos: Position,
+) -> Option<(ElementKind, Range)> {
+    match tree_reference_context(root_node, text, pos) {
+        Some(context) => Some(context),
+        None => text_reference_context(text, pos),
+    }
+}
+
+fn tree_reference_context(
+    root_node: &Node,
+    text: &(impl Source + ?Sized),
+    pos: Position,
+) -> Option<(ElementKind, Range)> {
+    let element = get_tag_name(root_node, pos)?;
+    let tag = parse_tag(element, text)?;
+    let attr = get_attribute(root_node, pos)?;
+    let (name, value) = attribute_parts(&attr)?;
+    let kind = ElementKind::from_reference(&tag.name, &text.node_text(&name)?)?;
+    let range = value_range(&value);
+    if !contains(&range, pos) {
+        return None;
+    }
+    Some((kind, range))
+}
+
+//While an attribute value is being typed the closing quote is often missing and
+//the tree does not have an Attribute node yet, so look at the line itself
+fn text_reference_context(
+    text: &(impl Source + ?Sized),
+    pos: Position,
+) -> Option<(ElementKind, Range)> {
+    let offset = text.offset(pos)?;
+    let line_start = text.rfind(offset, b'\n').map_or(0, |i| i + 1);
+    let before = text.slice(line_start..offset)?;
+    let tag_start = before.rfind('<')?;
+    let tag_text = &before[tag_start + 1..];
+    let tag_name = tag_text.split_whitespace().next()?;
+    let value_start = tag_text.rfind("=\"")?;
+    let value = &tag_text[value_start + 2..];
+    if value.contains('"') {
+        return None;
+    }
+    let attr_name = tag_text[..value_start].split_whitespace().last()?;
+    let kind = ElementKind::from_reference(tag_name, attr_name)?;
+    let start = Position::new(pos.line, pos.character - value.len() as u32);
+    Some((kind, Range::new(start, pos)))
+}
+
+//Where the cursor is in terms of the schema content model
+#[derive(Debug, PartialEq, Eq)]
+pub enum SchemaContext {
+    //Typing an element name after `<`
+    ElementName {
+        parent: String,
+        previous: Option<String>,
+        range: Range,
+    },
+    //Typing an attribute name inside a start tag
+    AttributeName {
+        element: String,
+        existing: Vec<String>,
+        range: Range,
+    },
+    AttributeValue {
+        element: String,
+        attribute: String,
+        range: Range,
+    },
+    //Typing the text content of an element, e.g. <DataType>
+    ElementValue {
+        element: String,
+        range: Range,
+    },
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == ':' || c == '.'
+}
+
+//Range of `typed`, which ends at the cursor and may not span lines
+fn typed_range(typed: &str, pos: Position) -> Option<Range> {
+    if typed.contains('\n') {
+        return None;
+    }
+    let start = Position::new(pos.line, pos.character.checked_sub(typed.len() as u32)?);
+    Some(Range::new(start, pos))
+}
+
+//Names of the attributes already present in the text of a start tag
+fn attribute_names(tag_text: &str) -> Vec<String> {
+    tag_text
+        .split('"')
+        .step_by(2)
+        .flat_map(|outside| {
+            let mut parts: Vec<&str> = outside.split('=').collect();
+            parts.pop();
+            parts
+        })
+        .filter_map(|part| part.split_whitespace().last())
+        .map(String::from)
+        .collect()
+}
+
+//The element a new child would be inserted into and the name of its last
+//child before the cursor, from the tree if it has recovered well enough
+fn tree_parent(
+    root_node: &Node,
+    text: &(impl Source + ?Sized),
+    pos: Position,
+    tag_start: usize,
+) -> Option<(String, Option<String>)> {
+    let offset = text.offset(pos)?;
+    let element = get_tag_name(root_node, pos)?;
+    let tag = start_tag(&element)?;
+    if tag.end_byte() > tag_start || element.end_byte() < offset {
+        return None;
+    }
+    let name = parse_tag(element, text)?.name;
+    let previous = child_elements(&element)
+        .into_iter()
+        .rfind(|child| child.end_byte() <= tag_start)
+        .and_then(|child| parse_tag(child, text))
+        .map(|tag| tag.name);
+    Some((name, previous))
+}
+
+//Same as `tree_parent`, but by replaying the tags before the cursor. Used when the
+//partial tag turned the whole document into an ERROR node
+fn text_parent(before: &str) -> Option<(String, Option<String>)> {
+    //Open elements and the last child closed inside each of them
+    let mut stack: Vec<(String, Option<String>)> = vec![];
+    let mut rest = before;
+    while let Some(start) = rest.find('<') {
+        rest = &rest[start..];
+        let (skip, end) = if rest.starts_with("<!--") {
+            ("-->", None)
+        } else if rest.starts_with("<![CDATA[") {
+            ("]]>", None)
+        } else if rest.starts_with("<?") || rest.starts_with("<!") {
+            (">", None)
+        } else {
+            ("", tag_end(rest))
+        };
+        if !skip.is_empty() {
+            rest = &rest[rest.find(skip)? + skip.len()..];
+            continue;
+        }
+        let end = end?;
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| is_name_char(*c))
+            .collect();
+        if tag.starts_with('/') {
+            while let Some((open, _)) = stack.pop() {
+                if open == name {
+                    break;
+                }
+            }
+            if let Some(parent) = stack.last_mut() {
+                parent.1 = Some(name);
+            }
+        } else if tag.ends_with('/') {
+            if let Some(parent) = stack.last_mut() {
+                parent.1 = Some(name);
+            }
+        } else {
+            stack.push((name, None));
+        }
+    }
+    stack.pop()
+}
+
+//Index of the `>` closing the tag at the start of `s`, skipping quoted values
+fn tag_end(s: &str) -> Option<usize> {
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '>' if !quoted => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+//Only reads back to the tag the cursor is in, unless the tree is of no help
+pub fn schema_context(
+    root_node: &Node,
+    text: &(impl Source + ?Sized),
+    pos: Position,
+) -> Option<SchemaContext> {
+    let offset = text.offset(pos)?;
+    let tag_start = text.rfind(offset, b'<');
+    let tag_close = text.rfind(offset, b'>');
+    match (tag_start, tag_close) {
+        (Some(start), close) if close.is_none_or(|close| close < start) => {
+            let tag_text = text.slice(start + 1..offset)?;
+            let tag_text = tag_text.as_ref();
+            if tag_text.starts_with(['/', '!', '?']) {
+                return None;
+            }
+            let Some(name_end) = tag_text.find(char::is_whitespace) else {
+                let (parent, previous) = tree_parent(root_node, text, pos, start)
+                    .or_else(|| text_parent(&text.slice(0..start)?))?;
+                return Some(SchemaContext::ElementName {
+                    parent,
+                    previous,
+                    range: typed_range(tag_text, pos)?,
+                });
+            };
+            let element = String::from(&tag_text[..name_end]);
+            if tag_text.matches('"').count() % 2 == 1 {
+                let quote = tag_text.rfind('"')?;
+                let attribute = tag_text[..quote]
+                    .trim_end()
+                    .strip_suffix('=')?
+                    .split_whitespace()
+                    .last()?;
+                return Some(SchemaContext::AttributeValue {
+                    element,
+                    attribute: String::from(attribute),
+                    range: typed_range(&tag_text[quote + 1..], pos)?,
+                });
+            }
+            let typed_start = tag_text
+                .rfind(|c: char| !is_name_char(c))
+                .map_or(0, |i| i + 1);
+            let typed = &tag_text[typed_start..];
+            Some(SchemaContext::AttributeName {
+                element,
+                existing: attribute_names(&tag_text[..typed_start]),
+                range: typed_range(typed, pos)?,
+            })
+        }
+        (start, Some(close)) => {
+            let tag = text.slice(start?..close)?;
+            if tag.starts_with("</") || tag.ends_with('/') {
+                return None;
+            }
+            let element: String = tag[1..].chars().take_while(|c| is_name_char(*c)).collect();
+            let typed = text.slice(close + 1..offset)?;
+            let typed = typed.trim_start();
+            Some(SchemaContext::ElementValue {
+                element,
+                range: typed_range(typed, pos)?,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn completion_item(
+    label: &str,
+    kind: CompletionItemKind,
+    detail: String,
+    index: usize,
+    range: Range,
+    new_text: String,
+) -> CompletionItem {
+    CompletionItem {
+        label: String::from(label),
+        kind: Some(kind),
+        detail: Some(detail),
+        //Keep schema order instead of sorting alphabetically
+        sort_text: Some(format!("{index:03}")),
+        text_edit: Some(CompletionTextEdit::Edit(TextEdit { range, new_text })),
+        ..Default::default()
+    }
+}
+
+fn value_items(values: &[&str], detail: &str, range: Range) -> Vec<CompletionItem> {
+    values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| {
+            completion_item(
+                value,
+                CompletionItemKind::ENUM_MEMBER,
+                String::from(detail),
+                i,
+                range,
+                String::from(*value),
+            )
+        })
+        .collect()
+}
+
+pub fn schema_completions(context: &SchemaContext) -> Vec<CompletionItem> {
+    match context {
+        SchemaContext::ElementName {
+            parent,
+            previous,
+            range,
+        } => {
+            let Some(model) = element_model(parent) else {
+                return vec![];
+            };
+            model
+                .children_after(previous.as_deref())
+                .iter()
+                .enumerate()
+                .map(|(i, child)| {
+                    completion_item(
+                        child,
+                        CompletionItemKind::STRUCT,
+                        format!("Child of {parent}"),
+                        i,
+                        *range,
+                        String::from(*child),
+                    )
+                })
+                .collect()
+        }
+        SchemaContext::AttributeName {
+            element,
+            existing,
+            range,
+        } => {
+            let Some(model) = element_model(element) else {
+                return vec![];
+            };
+            model
+                .attributes
+                .iter()
+                .filter(|attr| !existing.iter().any(|e| e == attr.name))
+                .enumerate()
+                .map(|(i, attr)| {
+                    let required = if attr.required {
+                        "Required"
+                    } else {
+                        "Optional"
+                    };
+                    CompletionItem {
+                        insert_text_format: Some(InsertTextFormat::SNIPPET),
+                        ..completion_item(
+                            attr.name,
+                            CompletionItemKind::PROPERTY,
+                            format!("{required} attribute of {element}"),
+                            i,
+                            *range,
+                            format!("{}=\"$1\"", attr.name),
+                        )
+                    }
+                })
+                .collect()
+        }
+        SchemaContext::AttributeValue {
+            element,
+            attribute,
+            range,
+        } => element_model(element)
+            .and_then(|model| model.attribute(attribute))
+            .map(|attr| value_items(attr.values, &format!("{element} {attribute}"), *range))
+            .unwrap_or_default(),
+        SchemaContext::ElementValue { element, range } => element_model(element)
+            .map(|model| value_items(model.values, element, *range))
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lsp_types::{Position, Range};
+    use tree_sitter::Tree;
+
+    use super::{reference_context, schema_completions, schema_context, SchemaContext};
+    use crate::workspace::fixtures::{position_of, BASE};
+    use crate::workspace::symbols::ElementKind;
+
+    fn parse(s: &str) -> Tree {
+        let mut t = tree_sitter::Parser::new();
+        t.set_language(&tree_sitter_xml::language_xml()).unwrap();
+        t.parse(s, None).unwrap()
+    }
+
+    #[test]
+    fn test_context_in_attribute_value() {
+        let t = parse(BASE);
+        let pos = position_of(BASE, "ClaimTypeReferenceId=\"objectId", 0, 24);
+        let (kind, range) = reference_context(&t.root_node(), BASE, pos).unwrap();
+        assert_eq!(kind, ElementKind::ClaimType);
+        assert_eq!(range.start, position_of(BASE, "objectId\" Required", 0, 0));
+    }
+
+    #[test]
+    fn test_context_outside_value() {
+        let t = parse(BASE);
+        let pos = position_of(BASE, "ClaimTypeReferenceId=\"objectId", 0, 3);
+        assert!(reference_context(&t.root_node(), BASE, pos).is_none());
+        let pos = position_of(BASE, "Required=\"true", 0, 11);
+        assert!(reference_context(&t.root_node(), BASE, pos).is_none());
+    }
+
+    #[test]
+    fn test_context_in_unterminated_value() {
+        let text = "<TrustFrameworkPolicy>\n  <ValidationTechnicalProfile ReferenceId=\"AAD-\n</TrustFrameworkPolicy>\n";
+        let t = parse(text);
+        let pos = Position::new(1, 47);
+        let (kind, range) = reference_context(&t.root_node(), text, pos).unwrap();
+        assert_eq!(kind, ElementKind::TechnicalProfile);
+        assert_eq!(range, Range::new(Position::new(1, 43), pos));
+    }
+
+    #[test]
+    fn test_contexts_from_rope() {
+        //A partial tag at the end, so the text fallbacks are taken too
+        let text = format!(
+            "{BASE}<!-- {} -->\n<ClaimsSchema>\n  <Cla",
+            "-".repeat(2000)
+        );
+        let rope = ropey::Rope::from_str(&text);
+        assert!(rope.chunks().count() > 1);
+        let t = parse(&text);
+        for (line, chars) in text.split('\n').enumerate() {
+            for character in 0..=chars.len() as u32 + 1 {
+                let pos = Position::new(line as u32, character);
+                assert_eq!(
+                    reference_context(&t.root_node(), &rope, pos),
+                    reference_context(&t.root_node(), text.as_str(), pos)
+                );
+                assert_eq!(
+                    schema_context(&t.root_node(), &rope, pos),
+                    schema_context(&t.root_node(), text.as_str(), pos)
+                );
+            }
+        }
+    }
+
+    fn labels(text: &str, pos: Position) -> Vec<String> {
+        let t = parse(text);
+        let context = schema_context(&t.root_node(), text, pos).unwrap();
+        schema_completions(&context)
+            .into_iter()
+            .map(|item| item.label)
+            .collect()
+    }
+
+    #[test]
+    fn test_child_elements_in_schema_order() {
+        let text =
+            "<TechnicalProfile Id=\"x\">\n  <Protocol Name=\"None\" />\n  <\n</TechnicalProfile>\n";
+        let pos = Position::new(2, 3);
+        let t = parse(text);
+        assert_eq!(
+            schema_context(&t.root_node(), text, pos),
+            Some(SchemaContext::ElementName {
+                parent: String::from("TechnicalProfile"),
+                previous: Some(String::from("Protocol")),
+                range: Range::new(pos, pos),
+            })
+        );
+        let names = labels(text, pos);
+        assert_eq!(names[0], "Protocol");
+        assert_eq!(names[1], "InputTokenFormat");
+        assert!(!names.contains(&String::from("DisplayName")));
+    }
+
+    #[test]
+    fn test_child_elements_of_partial_tag() {
+        let text =
+            "<ClaimsSchema>\n  <ClaimType Id=\"a\">\n    <DisplayName>A</DisplayName>\n    <Dat";
+        let pos = Position::new(3, 8);
+        let t = parse(text);
+        let Some(SchemaContext::ElementName {
+            parent,
+            previous,
+            range,
+        }) = schema_context(&t.root_node(), text, pos)
+        else {
+            panic!("expected an element name context");
+        };
+        assert_eq!(parent, "ClaimType");
+        assert_eq!(previous.as_deref(), Some("DisplayName"));
+        assert_eq!(range.start, Position::new(3, 5));
+        assert_eq!(labels(text, pos)[1], "DataType");
+    }
+
+    #[test]
+    fn test_missing_attributes() {
+        let text = "<OrchestrationStep Order=\"1\" T";
+        let pos = Position::new(0, 30);
+        let names = labels(text, pos);
+        assert!(!names.contains(&String::from("Order")));
+        assert_eq!(names[0], "Type");
+    }
+
+    #[test]
+    fn test_enumerated_values() {
+        let text = "<OrchestrationStep Order=\"1\" Type=\"Claims";
+        let pos = Position::new(0, 41);
+        let names = labels(text, pos);
+        assert!(names.contains(&String::from("ClaimsExchange")));
+        let text = "<ClaimType Id=\"a\">\n  <DataType>";
+        let names = labels(text, Position::new(1, 12));
+        assert!(names.contains(&String::from("stringCollection")));
+    }
+}
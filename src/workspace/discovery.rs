@@ -0,0 +1,236 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{error, warn};
+use serde::Deserialize;
+
+//Which files of a workspace folder are loaded, as globs relative to the folder,
+//and how they group into policy sets
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct Discovery {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    //Folders relative to the workspace folder that each hold one policy set.
+    //Policies outside them form a set with the policies next to them
+    pub policy_sets: Vec<String>,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Discovery {
+            include: vec![String::from("**/*.xml")],
+            //Environments/ is where the B2C extension writes the built policies
+            exclude: vec![
+                String::from("**/Environments/**"),
+                String::from("**/node_modules/**"),
+                String::from("**/.git/**"),
+            ],
+            policy_sets: vec![],
+        }
+    }
+}
+
+fn glob_set(globs: &[String]) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        match Glob::new(glob) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(e) => warn!("Ignoring glob {glob}: {e}"),
+        }
+    }
+    builder.build().unwrap_or_else(|e| {
+        error!("Could not build globs {globs:?}: {e}");
+        GlobSet::empty()
+    })
+}
+
+//The files under one root that belong to its workspace
+#[derive(Clone)]
+pub struct FileFilter {
+    root: PathBuf,
+    include: GlobSet,
+    exclude: GlobSet,
+    policy_sets: Vec<PathBuf>,
+}
+
+impl FileFilter {
+    pub fn new(root: &Path, discovery: &Discovery) -> Self {
+        FileFilter {
+            root: root.to_path_buf(),
+            include: glob_set(&discovery.include),
+            exclude: glob_set(&discovery.exclude),
+            policy_sets: discovery
+                .policy_sets
+                .iter()
+                .map(|set| root.join(set))
+                .collect(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn matches(&self, path: &Path) -> bool {
+        match path.strip_prefix(&self.root) {
+            Ok(relative) => self.include.is_match(relative) && !self.exclude.is_match(relative),
+            Err(_) => false,
+        }
+    }
+
+    //The folder of the policy set path belongs to, the innermost configured one
+    //or else the folder it is in
+    pub fn policy_set(&self, path: &Path) -> PathBuf {
+        let configured = self
+            .policy_sets
+            .iter()
+            .filter(|set| path.starts_with(set))
+            .max_by_key(|set| set.components().count());
+        match configured {
+            Some(set) => set.clone(),
+            None => path.parent().unwrap_or(path).to_path_buf(),
+        }
+    }
+
+    //A directory is skipped when it is excluded itself, like **/bin, or when
+    //everything in it is, like **/bin/**
+    fn skips_dir(&self, relative: &Path) -> bool {
+        self.exclude.is_match(relative) || self.exclude.is_match(relative.join("_"))
+    }
+
+    //Every matching file under the root, sorted so loading is deterministic
+    pub fn find_files(&self) -> Vec<PathBuf> {
+        self.find_files_in(&self.root)
+    }
+
+    //Matching files under a directory of the root, like one a checkout created
+    pub fn find_files_in(&self, dir: &Path) -> Vec<PathBuf> {
+        let mut found = vec![];
+        match dir.strip_prefix(&self.root) {
+            Ok(relative) if relative.as_os_str().is_empty() || !self.skips_dir(relative) => {
+                self.walk(dir, &mut found)
+            }
+            _ => {}
+        }
+        found.sort();
+        found
+    }
+
+    fn walk(&self, dir: &Path, found: &mut Vec<PathBuf>) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Error reading directory {dir:?} {e:?}");
+                return;
+            }
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            //Symbolic links to directories are not followed, they can loop
+            let Ok(file_type) = entry.file_type() else {
+                continue;
+            };
+            let Ok(relative) = path.strip_prefix(&self.root) else {
+                continue;
+            };
+            if file_type.is_dir() {
+                if !self.skips_dir(relative) {
+                    self.walk(&path, found);
+                }
+            } else if self.matches(&path) {
+                found.push(path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::{Discovery, FileFilter};
+
+    fn repository(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("ief_lsp_{name}_{}", std::process::id()));
+        for file in [
+            "TrustFrameworkBase.xml",
+            "SocialAndLocalAccounts/TrustFrameworkBase.xml",
+            "SocialAndLocalAccounts/SignUpOrSignin.xml",
+            "Environments/Test/TrustFrameworkBase.xml",
+            "node_modules/package/sample.xml",
+            "appsettings.json",
+        ] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "<TrustFrameworkPolicy />").unwrap();
+        }
+        root
+    }
+
+    fn relative(root: &Path, found: Vec<PathBuf>) -> Vec<String> {
+        found
+            .iter()
+            .map(|path| {
+                let relative = path.strip_prefix(root).unwrap();
+                relative.to_str().unwrap().replace('\\', "/")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_recursive_discovery() {
+        let root = repository("discovery");
+        let filter = FileFilter::new(&root, &Discovery::default());
+        assert_eq!(
+            relative(&root, filter.find_files()),
+            vec![
+                "SocialAndLocalAccounts/SignUpOrSignin.xml",
+                "SocialAndLocalAccounts/TrustFrameworkBase.xml",
+                "TrustFrameworkBase.xml",
+            ]
+        );
+        assert!(!filter.matches(&root.join("Environments/Test/TrustFrameworkBase.xml")));
+        assert!(!filter.matches(Path::new("/elsewhere/TrustFrameworkBase.xml")));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_policy_sets() {
+        let discovery = Discovery {
+            policy_sets: vec![String::from("Starter"), String::from("Starter/Mfa")],
+            ..Default::default()
+        };
+        let filter = FileFilter::new(Path::new("/repo"), &discovery);
+        let set = |path: &str| filter.policy_set(Path::new(path));
+        assert_eq!(
+            set("/repo/Starter/Base/Base.xml"),
+            Path::new("/repo/Starter")
+        );
+        assert_eq!(
+            set("/repo/Starter/Mfa/SignIn.xml"),
+            Path::new("/repo/Starter/Mfa")
+        );
+        assert_eq!(set("/repo/Other/SignIn.xml"), Path::new("/repo/Other"));
+    }
+
+    #[test]
+    fn test_configured_globs() {
+        let root = repository("globs");
+        let discovery = Discovery {
+            include: vec![String::from("Environments/**/*.xml")],
+            exclude: vec![String::from("[invalid"), String::from("**/node_modules/**")],
+            ..Default::default()
+        };
+        let filter = FileFilter::new(&root, &discovery);
+        assert_eq!(
+            relative(&root, filter.find_files()),
+            vec!["Environments/Test/TrustFrameworkBase.xml"]
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use lsp_types::Position;
+use tree_sitter::Node;
+
+use super::queries::{element_text, get_tag_name, parent_element, parse_tag, Source};
+
+pub const POLICY_NAMESPACE: &str = "http://schemas.microsoft.com/online/cpim/schemas/2013/06";
+const SAML_METADATA_NAMESPACE: &str = "urn:oasis:names:tc:SAML:2.0:metadata";
+//Metadata items of a technical profile that say where the partner's metadata is
+const METADATA_ITEMS: [&str; 3] = ["PartnerEntity", "PartnerMetadata", "METADATA"];
+
+//What an XML file in the workspace is, going by its root element. Only policies
+//are indexed and checked, the rest are kept so policies can point at them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DocumentKind {
+    Policy,
+    //EntityDescriptor of a SAML identity or service provider
+    SamlMetadata,
+    //LocalizedResources exported from the Localization of a policy
+    Localization,
+    //Project files, HTML templates and whatever else ends in .xml
+    Other,
+}
+
+impl DocumentKind {
+    pub fn classify(root_node: Node, text: &(impl Source + ?Sized)) -> Self {
+        let Some(root) = root_element(root_node).and_then(|root| parse_tag(root, text)) else {
+            return DocumentKind::Other;
+        };
+        let (prefix, name) = match root.name.split_once(':') {
+            Some((prefix, name)) => (Some(prefix), name),
+            None => (None, root.name.as_str()),
+        };
+        let xmlns = match prefix {
+            Some(prefix) => format!("xmlns:{prefix}"),
+            None => String::from("xmlns"),
+        };
+        let namespace = root.attrs.get(&xmlns).map(String::as_str);
+        match (name, namespace) {
+            //Policies without the namespace still upload, so they count
+            ("TrustFrameworkPolicy", None | Some(POLICY_NAMESPACE)) => DocumentKind::Policy,
+            ("EntityDescriptor" | "EntitiesDescriptor", Some(SAML_METADATA_NAMESPACE)) => {
+                DocumentKind::SamlMetadata
+            }
+            ("Localization" | "LocalizedResources", None | Some(POLICY_NAMESPACE)) => {
+                DocumentKind::Localization
+            }
+            _ => DocumentKind::Other,
+        }
+    }
+}
+
+pub fn root_element(root_node: Node) -> Option<Node> {
+    let mut cursor = root_node.walk();
+    let root = root_node
+        .named_children(&mut cursor)
+        .find(|n| n.grammar_name() == "element");
+    root
+}
+
+//The URL or file of the <Metadata><Item Key="PartnerEntity"> under the cursor
+pub fn metadata_item_at(
+    root_node: &Node,
+    text: &(impl Source + ?Sized),
+    pos: Position,
+) -> Option<String> {
+    let element = get_tag_name(root_node, pos)?;
+    let tag = parse_tag(element, text)?;
+    if tag.name != "Item" || !METADATA_ITEMS.contains(&tag.attrs.get("Key")?.as_str()) {
+        return None;
+    }
+    if parse_tag(parent_element(&element)?, text)?.name != "Metadata" {
+        return None;
+    }
+    let (value, _) = element_text(&element, text)?;
+    Some(value).filter(|value| !value.is_empty())
+}
+
+//Whether a metadata item points at a SAML metadata document, either by its
+//entityID or by the file name the URL or path ends with
+pub fn names_metadata(
+    value: &str,
+    root_node: Node,
+    text: &(impl Source + ?Sized),
+    file_name: &str,
+) -> bool {
+    let entity_id = root_element(root_node)
+        .and_then(|root| parse_tag(root, text))
+        .and_then(|root| root.attrs.get("entityID").cloned());
+    let last = value
+        .trim_end_matches(['/', '\\'])
+        .rsplit(['/', '\\'])
+        .next();
+    entity_id.as_deref() == Some(value)
+        || last.is_some_and(|last| last.eq_ignore_ascii_case(file_name))
+}
+
+#[cfg(test)]
+mod test {
+    use tree_sitter::Parser;
+
+    use super::DocumentKind;
+
+    fn classify(text: &str) -> DocumentKind {
+        let mut parser = Parser::new();
+        parser
+            .set_language(&tree_sitter_xml::language_xml())
+            .unwrap();
+        let tree = parser.parse(text, None).unwrap();
+        DocumentKind::classify(tree.root_node(), text)
+    }
+
+    #[test]
+    fn test_classify() {
+        let policy = "<?xml version=\"1.0\" encoding=\"utf-8\"?>
+<TrustFrameworkPolicy xmlns=\"http://schemas.microsoft.com/online/cpim/schemas/2013/06\" PolicyId=\"B2C_1A_Base\" />";
+        assert_eq!(classify(policy), DocumentKind::Policy);
+        assert_eq!(
+            classify("<TrustFrameworkPolicy PolicyId=\"B2C_1A_Base\"></TrustFrameworkPolicy>"),
+            DocumentKind::Policy
+        );
+        assert_eq!(
+            classify("<TrustFrameworkPolicy xmlns=\"urn:something:else\" />"),
+            DocumentKind::Other
+        );
+        let metadata = "<md:EntityDescriptor xmlns:md=\"urn:oasis:names:tc:SAML:2.0:metadata\" entityID=\"https://idp.example.com\">
+  <md:IDPSSODescriptor />
+</md:EntityDescriptor>";
+        assert_eq!(classify(metadata), DocumentKind::SamlMetadata);
+        assert_eq!(
+            classify("<LocalizedResources Id=\"api.signup.en\"><LocalizedStrings /></LocalizedResources>"),
+            DocumentKind::Localization
+        );
+        assert_eq!(
+            classify("<Project Sdk=\"Microsoft.NET.Sdk\"><PropertyGroup /></Project>"),
+            DocumentKind::Other
+        );
+        assert_eq!(classify(""), DocumentKind::Other);
+    }
+}
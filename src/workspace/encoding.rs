@@ -0,0 +1,163 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+//How a policy file is stored on disk. The server works on the decoded text and
+//keeps this around so the file can be written back the way it was found
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileEncoding {
+    pub encoding: &'static Encoding,
+    pub bom: bool,
+}
+
+impl Default for FileEncoding {
+    fn default() -> Self {
+        FileEncoding {
+            encoding: UTF_8,
+            bom: false,
+        }
+    }
+}
+
+//Encoding named by encoding="..." in the XML declaration. The declaration is
+//plain ASCII in every encoding the sniffing below leaves for it to decide
+fn declared_encoding(bytes: &[u8]) -> Option<&'static Encoding> {
+    if !bytes.starts_with(b"<?xml") {
+        return None;
+    }
+    let end = bytes.windows(2).position(|w| w == b"?>")?;
+    let declaration = std::str::from_utf8(&bytes[..end]).ok()?;
+    let rest = &declaration[declaration.find("encoding")? + "encoding".len()..];
+    let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+    let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let label = rest[1..].split(quote).next()?;
+    Encoding::for_label(label.as_bytes())
+}
+
+impl FileEncoding {
+    //The BOM wins, then the layout of `<?` for UTF-16 without one (XML 1.0,
+    //appendix F), then the XML declaration. Anything else is UTF-8
+    pub fn detect(bytes: &[u8]) -> Self {
+        if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+            return FileEncoding {
+                encoding,
+                bom: true,
+            };
+        }
+        let encoding = if bytes.starts_with(&[0x3C, 0x00, 0x3F, 0x00]) {
+            UTF_16LE
+        } else if bytes.starts_with(&[0x00, 0x3C, 0x00, 0x3F]) {
+            UTF_16BE
+        } else {
+            match declared_encoding(bytes) {
+                //The bytes are not UTF-16 or the check above would have caught it
+                Some(e) if e == UTF_16LE || e == UTF_16BE => UTF_8,
+                Some(e) => e,
+                None => UTF_8,
+            }
+        };
+        FileEncoding {
+            encoding,
+            bom: false,
+        }
+    }
+
+    //Decoded text without the BOM, and whether anything had to be replaced
+    pub fn decode(&self, bytes: &[u8]) -> (String, bool) {
+        let bytes = match Encoding::for_bom(bytes) {
+            Some((_, length)) if self.bom => &bytes[length..],
+            _ => bytes,
+        };
+        let (text, malformed) = self.encoding.decode_without_bom_handling(bytes);
+        (text.into_owned(), malformed)
+    }
+
+    //Text back to bytes the way the file was found, BOM included
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(text.len() + 3);
+        //encoding_rs only encodes to UTF-16 by falling back to UTF-8
+        let units = text.encode_utf16();
+        if self.encoding == UTF_16LE {
+            if self.bom {
+                bytes.extend([0xFF, 0xFE]);
+            }
+            bytes.extend(units.flat_map(u16::to_le_bytes));
+        } else if self.encoding == UTF_16BE {
+            if self.bom {
+                bytes.extend([0xFE, 0xFF]);
+            }
+            bytes.extend(units.flat_map(u16::to_be_bytes));
+        } else {
+            if self.bom {
+                bytes.extend([0xEF, 0xBB, 0xBF]);
+            }
+            bytes.extend_from_slice(&self.encoding.encode(text).0);
+        }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use encoding_rs::{UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252};
+
+    use super::FileEncoding;
+
+    const POLICY: &str = "<?xml version=\"1.0\" encoding=\"utf-16\"?>\r\n<TrustFrameworkPolicy PolicyId=\"B2C_1A_Ünïcödé\" />\r\n";
+
+    fn utf16le(text: &str, bom: bool) -> Vec<u8> {
+        let mut bytes = if bom { vec![0xFF, 0xFE] } else { vec![] };
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+        bytes
+    }
+
+    #[test]
+    fn test_detect_bom() {
+        let utf8 = [&[0xEF, 0xBB, 0xBF], POLICY.as_bytes()].concat();
+        let detected = FileEncoding::detect(&utf8);
+        assert_eq!(detected.encoding, UTF_8);
+        assert!(detected.bom);
+        assert_eq!(detected.decode(&utf8), (String::from(POLICY), false));
+
+        let utf16 = utf16le(POLICY, true);
+        let detected = FileEncoding::detect(&utf16);
+        assert_eq!(detected.encoding, UTF_16LE);
+        assert_eq!(detected.decode(&utf16), (String::from(POLICY), false));
+    }
+
+    #[test]
+    fn test_detect_without_bom() {
+        let utf16 = utf16le(POLICY, false);
+        let detected = FileEncoding::detect(&utf16);
+        assert_eq!(detected.encoding, UTF_16LE);
+        assert!(!detected.bom);
+        assert_eq!(detected.decode(&utf16).0, POLICY);
+
+        let big_endian: Vec<u8> = POLICY.encode_utf16().flat_map(u16::to_be_bytes).collect();
+        assert_eq!(FileEncoding::detect(&big_endian).encoding, UTF_16BE);
+
+        let latin = b"<?xml version='1.0' encoding='ISO-8859-1'?><a>\xdc</a>";
+        let detected = FileEncoding::detect(latin);
+        assert_eq!(detected.encoding, WINDOWS_1252);
+        assert_eq!(
+            detected.decode(latin).0,
+            "<?xml version='1.0' encoding='ISO-8859-1'?><a>Ü</a>"
+        );
+
+        //Saved as UTF-8 despite what the declaration says
+        assert_eq!(FileEncoding::detect(POLICY.as_bytes()).encoding, UTF_8);
+        assert_eq!(FileEncoding::detect(b"<a/>").encoding, UTF_8);
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        for bytes in [
+            utf16le(POLICY, true),
+            utf16le(POLICY, false),
+            [&[0xEF, 0xBB, 0xBF], POLICY.as_bytes()].concat(),
+            POLICY.as_bytes().to_vec(),
+        ] {
+            let detected = FileEncoding::detect(&bytes);
+            let (text, _) = detected.decode(&bytes);
+            assert_eq!(detected.encode(&text), bytes);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//Small base/extensions/relying party policy set shared by the workspace tests
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use lsp_types::{Position, Url};
+
+use super::discovery::FileFilter;
+use super::index::SymbolIndex;
+use super::{xml_parser, Discovery, IEF_Policy, IEF_Workspace, PositionEncoding, RuleSeverities};
+
+pub const BASE_PATH: &str = "/policies/TrustFrameworkBase.xml";
+pub const EXT_PATH: &str = "/policies/TrustFrameworkExtensions.xml";
+pub const RP_PATH: &str = "/policies/SignUpOrSignin.xml";
+
+pub const BASE: &str = "<TrustFrameworkPolicy PolicyId=\"B2C_1A_TrustFrameworkBase\">
+  <BuildingBlocks>
+    <ClaimsSchema>
+      <ClaimType Id=\"objectId\">
+        <DataType>string</DataType>
+      </ClaimType>
+      <ClaimType Id=\"email\">
+        <DataType>string</DataType>
+      </ClaimType>
+      <ClaimType Id=\"displayName\">
+        <DataType>string</DataType>
+      </ClaimType>
+    </ClaimsSchema>
+    <ClaimsTransformations>
+      <ClaimsTransformation Id=\"CreateDisplayName\" TransformationMethod=\"FormatStringClaim\">
+        <InputClaims>
+          <InputClaim ClaimTypeReferenceId=\"email\" TransformationClaimType=\"inputClaim\" />
+        </InputClaims>
+      </ClaimsTransformation>
+    </ClaimsTransformations>
+    <ContentDefinitions>
+      <ContentDefinition Id=\"api.signuporsignin\">
+        <LoadUri>~/tenant/templates/AzureBlue/unified.cshtml</LoadUri>
+      </ContentDefinition>
+    </ContentDefinitions>
+  </BuildingBlocks>
+  <ClaimsProviders>
+    <ClaimsProvider>
+      <DisplayName>Azure Active Directory</DisplayName>
+      <TechnicalProfiles>
+        <TechnicalProfile Id=\"AAD-UserReadUsingObjectId\">
+          <Protocol Name=\"Proprietary\" Handler=\"Web.TPEngine.Providers.AzureActiveDirectoryProvider\" />
+          <Metadata>
+            <Item Key=\"Operation\">Read</Item>
+            <Item Key=\"RaiseErrorIfClaimsPrincipalDoesNotExist\">true</Item>
+          </Metadata>
+          <InputClaims>
+            <InputClaim ClaimTypeReferenceId=\"objectId\" Required=\"true\" />
+          </InputClaims>
+          <OutputClaims>
+            <OutputClaim ClaimTypeReferenceId=\"email\" />
+          </OutputClaims>
+          <OutputClaimsTransformations>
+            <OutputClaimsTransformation ReferenceId=\"CreateDisplayName\" />
+          </OutputClaimsTransformations>
+        </TechnicalProfile>
+      </TechnicalProfiles>
+    </ClaimsProvider>
+  </ClaimsProviders>
+  <UserJourneys>
+    <UserJourney Id=\"SignUpOrSignIn\">
+      <OrchestrationSteps>
+        <OrchestrationStep Order=\"1\" Type=\"CombinedSignInAndSignUp\" ContentDefinitionReferenceId=\"api.signuporsignin\" />
+        <OrchestrationStep Order=\"2\" Type=\"ClaimsExchange\">
+          <ClaimsExchanges>
+            <ClaimsExchange Id=\"AADUserReadWithObjectId\" TechnicalProfileReferenceId=\"AAD-UserReadUsingObjectId\" />
+          </ClaimsExchanges>
+        </OrchestrationStep>
+      </OrchestrationSteps>
+    </UserJourney>
+  </UserJourneys>
+</TrustFrameworkPolicy>
+";
+
+pub const EXT: &str = "<TrustFrameworkPolicy PolicyId=\"B2C_1A_TrustFrameworkExtensions\">
+  <BasePolicy>
+    <TenantId>yourtenant.onmicrosoft.com</TenantId>
+    <PolicyId>B2C_1A_TrustFrameworkBase</PolicyId>
+  </BasePolicy>
+  <ClaimsProviders>
+    <ClaimsProvider>
+      <DisplayName>Azure Active Directory</DisplayName>
+      <TechnicalProfiles>
+        <TechnicalProfile Id=\"AAD-UserReadUsingObjectId\">
+          <Metadata>
+            <Item Key=\"RaiseErrorIfClaimsPrincipalDoesNotExist\">false</Item>
+          </Metadata>
+          <OutputClaims>
+            <OutputClaim ClaimTypeReferenceId=\"displayName\" />
+          </OutputClaims>
+        </TechnicalProfile>
+      </TechnicalProfiles>
+    </ClaimsProvider>
+  </ClaimsProviders>
+</TrustFrameworkPolicy>
+";
+
+pub const RP: &str = "<TrustFrameworkPolicy PolicyId=\"B2C_1A_signup_signin\">
+  <BasePolicy>
+    <TenantId>yourtenant.onmicrosoft.com</TenantId>
+    <PolicyId>B2C_1A_TrustFrameworkExtensions</PolicyId>
+  </BasePolicy>
+  <RelyingParty>
+    <DefaultUserJourney ReferenceId=\"SignUpOrSignIn\" />
+    <TechnicalProfile Id=\"PolicyProfile\">
+      <OutputClaims>
+        <OutputClaim ClaimTypeReferenceId=\"objectId\" PartnerClaimType=\"sub\" />
+        <OutputClaim ClaimTypeReferenceId=\"email\" />
+      </OutputClaims>
+    </TechnicalProfile>
+  </RelyingParty>
+</TrustFrameworkPolicy>
+";
+
+pub fn test_workspace() -> IEF_Workspace {
+    let mut parser = xml_parser();
+    let policies = HashMap::from_iter([BASE_PATH, EXT_PATH, RP_PATH].into_iter().map(|path| {
+        let text = match path {
+            BASE_PATH => BASE,
+            EXT_PATH => EXT,
+            _ => RP,
+        };
+        let policy = IEF_Policy::from_text(&mut parser, String::from(text)).unwrap();
+        (String::from(path), Arc::new(policy))
+    }));
+    let mut workspace = IEF_Workspace {
+        files: FileFilter::new(Path::new("/policies"), &Discovery::default()),
+        policies,
+        versions: HashMap::new(),
+        symbol_index: SymbolIndex::default(),
+        parser,
+        encoding: PositionEncoding::Utf16,
+        rules: RuleSeverities::default(),
+        problems: vec![],
+    };
+    workspace.reindex_all();
+    workspace
+}
+
+pub fn uri(path: &str) -> Url {
+    Url::from_file_path(path).unwrap()
+}
+
+//Position of the nth occurrence of needle in text, moved right by offset characters
+pub fn position_of(text: &str, needle: &str, nth: usize, offset: u32) -> Position {
+    let byte = text.match_indices(needle).nth(nth).unwrap().0;
+    let line = text[..byte].matches('\n').count();
+    let line_start = text[..byte].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    Position::new(line as u32, (byte - line_start) as u32 + offset)
+}
@@ -0,0 +1,186 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use log::info;
+use lsp_types::Url;
+
+use super::{new_workspace, Discovery, IEF_Workspace, PositionEncoding, RuleSeverities};
+use crate::error::ServerError;
+
+//One logical workspace per workspace folder. A document belongs to the folder
+//that contains it, and anything outside every folder to the first one.
+//Requests read snapshots of a folder, changes copy it first if one is still out
+pub struct WorkspaceFolders {
+    folders: Vec<Arc<IEF_Workspace>>,
+    encoding: PositionEncoding,
+    discovery: Discovery,
+    rules: RuleSeverities,
+}
+
+impl WorkspaceFolders {
+    pub fn new(roots: &[String], encoding: PositionEncoding, discovery: Discovery) -> Self {
+        let mut folders = WorkspaceFolders {
+            folders: vec![],
+            encoding,
+            discovery,
+            rules: RuleSeverities::default(),
+        };
+        for root in roots {
+            folders.add(root);
+        }
+        folders
+    }
+
+    pub fn add(&mut self, root: &str) -> &mut IEF_Workspace {
+        info!("Adding workspace folder {root}");
+        let mut workspace = new_workspace(root, self.encoding, &self.discovery);
+        workspace.set_rules(self.rules.clone());
+        self.folders.push(Arc::new(workspace));
+        let added = self.folders.last_mut().unwrap();
+        Arc::make_mut(added)
+    }
+
+    //Returns the documents the folder had, other folders may still have some
+    pub fn remove(&mut self, root: &Path) -> Vec<Url> {
+        info!("Removing workspace folder {root:?}");
+        let (removed, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.folders)
+            .into_iter()
+            .partition(|workspace| workspace.root() == root);
+        self.folders = kept;
+        removed
+            .iter()
+            .flat_map(|workspace| workspace.documents())
+            .collect()
+    }
+
+    //The innermost folder containing the document
+    fn index_of(&self, document: Option<&Url>) -> usize {
+        let path = document.and_then(|uri| uri.to_file_path().ok());
+        path.and_then(|path| {
+            self.folders
+                .iter()
+                .enumerate()
+                .filter(|(_, workspace)| path.starts_with(workspace.root()))
+                .max_by_key(|(_, workspace)| workspace.root().components().count())
+                .map(|(index, _)| index)
+        })
+        .unwrap_or(0)
+    }
+
+    pub fn for_document(&mut self, document: Option<&Url>) -> Option<&mut IEF_Workspace> {
+        let index = self.index_of(document);
+        self.folders.get_mut(index).map(Arc::make_mut)
+    }
+
+    //The folder of the document as it is now, unaffected by later changes
+    pub fn snapshot(&self, document: Option<&Url>) -> Option<Arc<IEF_Workspace>> {
+        self.folders.get(self.index_of(document)).cloned()
+    }
+
+    pub fn snapshots(&self) -> Vec<Arc<IEF_Workspace>> {
+        self.folders.clone()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &IEF_Workspace> {
+        self.folders.iter().map(|workspace| workspace.as_ref())
+    }
+
+    pub fn set_rules(&mut self, rules: RuleSeverities) {
+        for workspace in &mut self.folders {
+            Arc::make_mut(workspace).set_rules(rules.clone());
+        }
+        self.rules = rules;
+    }
+
+    //Every folder picks its files again. Returns the documents that are gone
+    pub fn set_discovery(&mut self, discovery: Discovery) -> Vec<Url> {
+        let removed = self
+            .folders
+            .iter_mut()
+            .flat_map(|workspace| Arc::make_mut(workspace).set_discovery(&discovery))
+            .collect();
+        self.discovery = discovery;
+        removed
+    }
+
+    //Problems of the folders that were changed since the last requests were
+    //scheduled. Shared folders keep theirs until they are not
+    pub fn take_problems(&mut self) -> Vec<ServerError> {
+        self.folders
+            .iter_mut()
+            .filter_map(Arc::get_mut)
+            .flat_map(IEF_Workspace::take_problems)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    use lsp_types::Url;
+
+    use super::WorkspaceFolders;
+    use crate::workspace::fixtures::{BASE, RP};
+    use crate::workspace::{Discovery, PositionEncoding};
+
+    #[test]
+    fn test_documents_go_to_their_folder() {
+        let root = std::env::temp_dir().join(format!("ief_lsp_folders_{}", std::process::id()));
+        let local = root.join("LocalAccounts");
+        let social = root.join("SocialAccounts");
+        for folder in [&local, &social] {
+            fs::create_dir_all(folder).unwrap();
+            fs::write(folder.join("TrustFrameworkBase.xml"), BASE).unwrap();
+            fs::write(folder.join("SignUpOrSignin.xml"), RP).unwrap();
+        }
+        let roots = [local.to_str().unwrap(), social.to_str().unwrap()].map(String::from);
+        let mut folders =
+            WorkspaceFolders::new(&roots, PositionEncoding::Utf16, Discovery::default());
+        assert_eq!(folders.iter().count(), 2);
+
+        let document = Url::from_file_path(social.join("SignUpOrSignin.xml")).unwrap();
+        let workspace = folders.for_document(Some(&document)).unwrap();
+        assert_eq!(workspace.root(), social);
+        //Each folder resolves against its own copy of the base policy
+        let email = crate::workspace::fixtures::position_of(RP, "email", 0, 0);
+        let loc = workspace.find_definition(&document, email).unwrap();
+        assert_eq!(
+            loc.uri,
+            Url::from_file_path(social.join("TrustFrameworkBase.xml")).unwrap()
+        );
+
+        //A snapshot keeps what it saw
+        let snapshot = folders.snapshot(Some(&document)).unwrap();
+        let workspace = folders.for_document(Some(&document)).unwrap();
+        workspace
+            .open_document(document.clone(), String::from("<Unsaved />"), 1)
+            .unwrap();
+        assert!(workspace.find_definition(&document, email).is_none());
+        assert!(snapshot.find_definition(&document, email).is_some());
+        //Only the edited document was copied for it
+        let base = social.join("TrustFrameworkBase.xml");
+        let rp = social.join("SignUpOrSignin.xml");
+        let (base, rp) = (base.to_str().unwrap(), rp.to_str().unwrap());
+        assert!(Arc::ptr_eq(
+            &workspace.policies[base],
+            &snapshot.policies[base]
+        ));
+        assert!(!Arc::ptr_eq(
+            &workspace.policies[rp],
+            &snapshot.policies[rp]
+        ));
+
+        let outside = Url::from_file_path("/elsewhere/Scratch.xml").unwrap();
+        assert_eq!(folders.for_document(Some(&outside)).unwrap().root(), local);
+        let removed = folders.remove(&local);
+        assert_eq!(removed.len(), 2);
+        assert!(removed.contains(&Url::from_file_path(local.join("SignUpOrSignin.xml")).unwrap()));
+        assert_eq!(folders.for_document(None).unwrap().root(), social);
+        folders.remove(Path::new(&social));
+        assert!(folders.for_document(None).is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
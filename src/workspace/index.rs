@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+use super::symbols::IEFSymbol;
+
+//Definitions of every loaded policy for workspace/symbol. An edit only marks
+//the document, its definitions are found again by the next search. Copies share
+//the entries, so whichever snapshot searches first fills them for all
+#[derive(Default, Clone)]
+pub struct SymbolIndex {
+    definitions: HashMap<String, Arc<OnceLock<Vec<IEFSymbol>>>>,
+}
+
+fn is_word_start(previous: Option<char>, c: char) -> bool {
+    match previous {
+        None => true,
+        Some(p) => !p.is_alphanumeric() || (p.is_lowercase() && c.is_uppercase()),
+    }
+}
+
+//How well `query` matches `candidate` as a case insensitive subsequence, higher
+//is better. Matches at word starts and runs of consecutive characters count more
+pub fn fuzzy_score(query: &str, candidate: &str) -> Option<i64> {
+    let mut score = 0;
+    let mut wanted = query.chars().flat_map(char::to_lowercase).peekable();
+    let mut previous: Option<char> = None;
+    let mut previous_matched = false;
+    for c in candidate.chars() {
+        let Some(w) = wanted.peek() else {
+            break;
+        };
+        let matched = c.to_lowercase().eq(std::iter::once(*w));
+        if matched {
+            score += 1;
+            if previous_matched {
+                score += 5;
+            }
+            if is_word_start(previous, c) {
+                score += 10;
+            }
+            wanted.next();
+        }
+        previous_matched = matched;
+        previous = Some(c);
+    }
+    if wanted.peek().is_some() {
+        return None;
+    }
+    //Prefer shorter candidates when everything else is equal
+    Some(score * 100 - candidate.chars().count() as i64)
+}
+
+impl SymbolIndex {
+    //The document at path changed
+    pub fn invalidate(&mut self, path: &str) {
+        self.definitions
+            .insert(String::from(path), Arc::new(OnceLock::new()));
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        self.definitions.remove(path);
+    }
+
+    //Best matches first, ties broken by Id and then by path. `symbols` finds the
+    //symbols of a document that changed since the last search
+    pub fn search(
+        &self,
+        query: &str,
+        symbols: impl Fn(&str) -> Vec<IEFSymbol>,
+    ) -> Vec<(&String, &IEFSymbol)> {
+        let mut found: Vec<(i64, &String, &IEFSymbol)> = self
+            .definitions
+            .iter()
+            .flat_map(|(path, entry)| {
+                let definitions = entry.get_or_init(|| {
+                    symbols(path)
+                        .into_iter()
+                        .filter(|s| s.is_definition())
+                        .collect()
+                });
+                definitions.iter().filter_map(move |symbol| {
+                    fuzzy_score(query, &symbol.id).map(|score| (score, path, symbol))
+                })
+            })
+            .collect();
+        found.sort_by(|(a_score, a_path, a), (b_score, b_path, b)| {
+            b_score
+                .cmp(a_score)
+                .then_with(|| a.id.cmp(&b.id))
+                .then_with(|| a_path.cmp(b_path))
+        });
+        found
+            .into_iter()
+            .map(|(_, path, symbol)| (path, symbol))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+
+    use lsp_types::{Position, Range};
+
+    use super::{fuzzy_score, SymbolIndex};
+    use crate::workspace::symbols::{ElementKind, IEFSymbol, SymbolRole};
+
+    #[test]
+    fn test_symbols_found_on_search() {
+        let email = IEFSymbol {
+            kind: ElementKind::ClaimType,
+            id: String::from("email"),
+            range: Range::new(Position::new(1, 2), Position::new(1, 7)),
+            role: SymbolRole::Definition,
+        };
+        let runs = Cell::new(0);
+        let symbols = |_: &str| {
+            runs.set(runs.get() + 1);
+            vec![email.clone()]
+        };
+        let mut index = SymbolIndex::default();
+        index.invalidate("/policies/Base.xml");
+        index.invalidate("/policies/Base.xml");
+        assert_eq!(runs.get(), 0);
+
+        //A snapshot that searches first fills the entry for the original too
+        let snapshot = index.clone();
+        assert_eq!(snapshot.search("mail", symbols).len(), 1);
+        assert_eq!(index.search("mail", symbols)[0].1, &email);
+        assert_eq!(runs.get(), 1);
+
+        index.invalidate("/policies/Base.xml");
+        index.search("", symbols);
+        assert_eq!(runs.get(), 2);
+        index.remove("/policies/Base.xml");
+        assert!(index.search("", symbols).is_empty());
+    }
+
+    #[test]
+    fn test_fuzzy_score() {
+        assert!(fuzzy_score("AAD-UserRead", "AAD-UserReadUsingObjectId").is_some());
+        assert!(fuzzy_score("aadread", "AAD-UserReadUsingObjectId").is_some());
+        assert!(fuzzy_score("signInName", "email").is_none());
+        assert!(fuzzy_score("", "email").is_some());
+    }
+
+    #[test]
+    fn test_fuzzy_score_prefers_word_starts() {
+        let exact = fuzzy_score("signInName", "signInName").unwrap();
+        let scattered = fuzzy_score("signInName", "signInNameOrEmailAddress").unwrap();
+        assert!(exact > scattered);
+        let prefix = fuzzy_score("email", "email").unwrap();
+        let inner = fuzzy_score("email", "otherMailAddress").unwrap_or(i64::MIN);
+        assert!(prefix > inner);
+    }
+}
@@ -0,0 +1,172 @@
+use lsp_types::{Position, Range};
+use tree_sitter::Node;
+
+use super::queries::{
+    attribute_value, child_elements, element_text, get_range, get_tag_name, parent_element,
+    parse_tag, value_range, Source,
+};
+use super::symbols::ElementKind;
+
+//What B2C uses to match an element in a derived policy with the one it overrides
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MergeKey {
+    //Elements with an Id, e.g. TechnicalProfile and ClaimType
+    Element(ElementKind, String),
+    //ClaimsProviders have no Id and are matched on their DisplayName
+    ClaimsProvider(String),
+    //Steps are matched on their Order inside the journey with the given Id
+    OrchestrationStep(String, String),
+}
+
+//The merge key of an element and the range of the text that carries it
+pub fn element_merge_key(
+    element: &Node,
+    text: &(impl Source + ?Sized),
+) -> Option<(MergeKey, Range)> {
+    let tag = parse_tag(*element, text)?;
+    match tag.name.as_str() {
+        "ClaimsProvider" => {
+            let display_name = child_elements(element)
+                .into_iter()
+                .find(|child| parse_tag(*child, text).is_some_and(|t| t.name == "DisplayName"))?;
+            let (name, content) = element_text(&display_name, text)?;
+            Some((MergeKey::ClaimsProvider(name), get_range(&content)))
+        }
+        "OrchestrationStep" => {
+            let order = attribute_value(element, "Order", text)?;
+            //OrchestrationStep > OrchestrationSteps > UserJourney
+            let journey = parent_element(&parent_element(element)?)?;
+            let journey_id = parse_tag(journey, text)?.attrs.get("Id")?.clone();
+            Some((
+                MergeKey::OrchestrationStep(journey_id, tag.attrs.get("Order")?.clone()),
+                value_range(&order),
+            ))
+        }
+        name => {
+            let kind = ElementKind::from_definition_tag(name)?;
+            let id = attribute_value(element, "Id", text)?;
+            Some((
+                MergeKey::Element(kind, tag.attrs.get("Id")?.clone()),
+                value_range(&id),
+            ))
+        }
+    }
+}
+
+//The closest element around the cursor that can be overridden
+pub fn merge_key_at(
+    root_node: &Node,
+    text: &(impl Source + ?Sized),
+    pos: Position,
+) -> Option<MergeKey> {
+    let mut element = get_tag_name(root_node, pos);
+    while let Some(e) = element {
+        if let Some((key, _)) = element_merge_key(&e, text) {
+            return Some(key);
+        }
+        element = parent_element(&e);
+    }
+    None
+}
+
+fn mergeable_elements<'a>(
+    root_node: Node<'a>,
+    text: &(impl Source + ?Sized),
+) -> Vec<(Node<'a>, MergeKey, Range)> {
+    let mut elements = vec![];
+    let mut stack = vec![root_node];
+    while let Some(node) = stack.pop() {
+        if node.grammar_name() == "element" {
+            if let Some((key, range)) = element_merge_key(&node, text) {
+                elements.push((node, key, range));
+            }
+        }
+        let mut cursor = node.walk();
+        stack.extend(node.named_children(&mut cursor));
+    }
+    elements.sort_by_key(|(_, _, range)| range.start);
+    elements
+}
+
+//Every element in a document that can override or be overridden
+pub fn find_merge_keys(root_node: Node, text: &(impl Source + ?Sized)) -> Vec<(MergeKey, Range)> {
+    mergeable_elements(root_node, text)
+        .into_iter()
+        .map(|(_, key, range)| (key, range))
+        .collect()
+}
+
+//The first element in a document with the given merge key
+pub fn find_element<'a>(
+    root_node: Node<'a>,
+    text: &(impl Source + ?Sized),
+    key: &MergeKey,
+) -> Option<Node<'a>> {
+    mergeable_elements(root_node, text)
+        .into_iter()
+        .find(|(_, k, _)| k == key)
+        .map(|(node, _, _)| node)
+}
+
+#[cfg(test)]
+mod test {
+    use tree_sitter::Tree;
+
+    use super::{find_merge_keys, merge_key_at, MergeKey};
+    use crate::workspace::fixtures::{position_of, BASE};
+    use crate::workspace::symbols::ElementKind;
+
+    fn parse(s: &str) -> Tree {
+        let mut t = tree_sitter::Parser::new();
+        t.set_language(&tree_sitter_xml::language_xml()).unwrap();
+        t.parse(s, None).unwrap()
+    }
+
+    #[test]
+    fn test_merge_key_of_technical_profile() {
+        let t = parse(BASE);
+        let pos = position_of(BASE, "<Item Key=\"Operation\"", 0, 3);
+        assert_eq!(
+            merge_key_at(&t.root_node(), BASE, pos),
+            Some(MergeKey::Element(
+                ElementKind::TechnicalProfile,
+                String::from("AAD-UserReadUsingObjectId")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_merge_key_of_claims_provider() {
+        let t = parse(BASE);
+        let pos = position_of(BASE, "<ClaimsProvider>", 0, 3);
+        assert_eq!(
+            merge_key_at(&t.root_node(), BASE, pos),
+            Some(MergeKey::ClaimsProvider(String::from(
+                "Azure Active Directory"
+            )))
+        );
+    }
+
+    #[test]
+    fn test_merge_key_of_orchestration_step() {
+        let t = parse(BASE);
+        let pos = position_of(BASE, "<ClaimsExchanges>", 0, 3);
+        assert_eq!(
+            merge_key_at(&t.root_node(), BASE, pos),
+            Some(MergeKey::OrchestrationStep(
+                String::from("SignUpOrSignIn"),
+                String::from("2")
+            ))
+        );
+    }
+
+    #[test]
+    fn test_find_merge_keys() {
+        let t = parse(BASE);
+        let keys = find_merge_keys(t.root_node(), BASE);
+        //3 claim types, 1 transformation, 1 content definition, 1 provider,
+        //1 technical profile, 1 journey and 2 steps
+        assert_eq!(keys.len(), 10);
+        assert_eq!(keys[0].1.start, position_of(BASE, "objectId", 0, 0));
+    }
+}
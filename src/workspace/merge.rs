@@ -0,0 +1,368 @@
+use tree_sitter::Node;
+
+use super::queries::{child_elements, element_text, parse_tag, Source};
+
+//A claim inside InputClaims, OutputClaims or PersistedClaims
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClaimEntry {
+    pub claim_type: String,
+    //Every other attribute, e.g. PartnerClaimType or DefaultValue
+    pub details: Vec<(String, String)>,
+}
+
+//What a single TechnicalProfile element contributes before it is merged
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ProfileLayer {
+    pub display_name: Option<String>,
+    pub protocol: Option<String>,
+    pub metadata: Vec<(String, String)>,
+    pub input_claims: Vec<ClaimEntry>,
+    pub output_claims: Vec<ClaimEntry>,
+    pub persisted_claims: Vec<ClaimEntry>,
+    //Ids from IncludeTechnicalProfile
+    pub includes: Vec<String>,
+}
+
+fn claims(element: &Node, text: &(impl Source + ?Sized)) -> Vec<ClaimEntry> {
+    child_elements(element)
+        .into_iter()
+        .filter_map(|claim| {
+            let mut attrs = parse_tag(claim, text)?.attrs;
+            let claim_type = attrs.remove("ClaimTypeReferenceId")?;
+            let mut details: Vec<(String, String)> = attrs.into_iter().collect();
+            details.sort();
+            Some(ClaimEntry {
+                claim_type,
+                details,
+            })
+        })
+        .collect()
+}
+
+pub fn profile_layer(element: &Node, text: &(impl Source + ?Sized)) -> ProfileLayer {
+    let mut layer = ProfileLayer::default();
+    for child in child_elements(element) {
+        let Some(tag) = parse_tag(child, text) else {
+            continue;
+        };
+        match tag.name.as_str() {
+            "DisplayName" => layer.display_name = element_text(&child, text).map(|(t, _)| t),
+            "Protocol" => {
+                layer.protocol = tag
+                    .attrs
+                    .get("Name")
+                    .map(|name| match tag.attrs.get("Handler") {
+                        Some(handler) => format!("{name} ({handler})"),
+                        None => name.clone(),
+                    })
+            }
+            "Metadata" => {
+                layer.metadata = child_elements(&child)
+                    .into_iter()
+                    .filter_map(|item| {
+                        let key = parse_tag(item, text)?.attrs.get("Key")?.clone();
+                        let value = element_text(&item, text)
+                            .map(|(t, _)| t)
+                            .unwrap_or_default();
+                        Some((key, value))
+                    })
+                    .collect()
+            }
+            "InputClaims" => layer.input_claims = claims(&child, text),
+            "OutputClaims" => layer.output_claims = claims(&child, text),
+            "PersistedClaims" => layer.persisted_claims = claims(&child, text),
+            "IncludeTechnicalProfile" => {
+                if let Some(id) = tag.attrs.get("ReferenceId") {
+                    layer.includes.push(id.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    layer
+}
+
+//A merged value and the file it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contributed<T> {
+    pub value: T,
+    pub source: String,
+}
+
+//A TechnicalProfile after merging every layer from the base policy to the leaf
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct EffectiveProfile {
+    pub id: String,
+    pub sources: Vec<String>,
+    pub display_name: Option<Contributed<String>>,
+    pub protocol: Option<Contributed<String>>,
+    pub metadata: Vec<(String, Contributed<String>)>,
+    pub input_claims: Vec<Contributed<ClaimEntry>>,
+    pub output_claims: Vec<Contributed<ClaimEntry>>,
+    pub persisted_claims: Vec<Contributed<ClaimEntry>>,
+}
+
+fn contributed<T: Clone>(values: &[T], source: &str) -> Vec<Contributed<T>> {
+    values
+        .iter()
+        .map(|value| Contributed {
+            value: value.clone(),
+            source: String::from(source),
+        })
+        .collect()
+}
+
+//Claims with the same ClaimTypeReferenceId are updated in place, new ones are appended
+fn merge_claims(claims: &mut Vec<Contributed<ClaimEntry>>, derived: &[Contributed<ClaimEntry>]) {
+    for claim in derived {
+        match claims
+            .iter_mut()
+            .find(|c| c.value.claim_type == claim.value.claim_type)
+        {
+            Some(existing) => *existing = claim.clone(),
+            None => claims.push(claim.clone()),
+        }
+    }
+}
+
+//Text shown as is in the hover, it may hold anything a policy can. Line breaks
+//would end a table row, so they become spaces
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '`' | '*' | '_' | '|' | '<' | '[' | ']' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\r' => {}
+            '\n' => escaped.push(' '),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+//A code span around text with backticks in it, fenced by a longer run of them
+fn code(text: &str) -> String {
+    let text = text.replace("\r\n", " ").replace(['\r', '\n'], " ");
+    let longest = text.split(|c| c != '`').map(str::len).max().unwrap_or(0);
+    let fence = "`".repeat(longest + 1);
+    let pad = if text.starts_with('`') || text.ends_with('`') {
+        " "
+    } else {
+        ""
+    };
+    format!("{fence}{pad}{text}{pad}{fence}")
+}
+
+impl EffectiveProfile {
+    pub fn from_layer(id: &str, layer: &ProfileLayer, source: &str) -> Self {
+        let single = |value: &Option<String>| {
+            value.as_ref().map(|value| Contributed {
+                value: value.clone(),
+                source: String::from(source),
+            })
+        };
+        EffectiveProfile {
+            id: String::from(id),
+            sources: vec![String::from(source)],
+            display_name: single(&layer.display_name),
+            protocol: single(&layer.protocol),
+            metadata: layer
+                .metadata
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        key.clone(),
+                        Contributed {
+                            value: value.clone(),
+                            source: String::from(source),
+                        },
+                    )
+                })
+                .collect(),
+            input_claims: contributed(&layer.input_claims, source),
+            output_claims: contributed(&layer.output_claims, source),
+            persisted_claims: contributed(&layer.persisted_claims, source),
+        }
+    }
+
+    //Puts `derived` on top of this profile
+    pub fn merge(&mut self, derived: &EffectiveProfile) {
+        for source in &derived.sources {
+            if !self.sources.contains(source) {
+                self.sources.push(source.clone());
+            }
+        }
+        if derived.display_name.is_some() {
+            self.display_name = derived.display_name.clone();
+        }
+        if derived.protocol.is_some() {
+            self.protocol = derived.protocol.clone();
+        }
+        for (key, value) in &derived.metadata {
+            match self.metadata.iter_mut().find(|(k, _)| k == key) {
+                Some((_, existing)) => *existing = value.clone(),
+                None => self.metadata.push((key.clone(), value.clone())),
+            }
+        }
+        merge_claims(&mut self.input_claims, &derived.input_claims);
+        merge_claims(&mut self.output_claims, &derived.output_claims);
+        merge_claims(&mut self.persisted_claims, &derived.persisted_claims);
+    }
+
+    pub fn to_markdown(&self) -> String {
+        let mut md = format!("### TechnicalProfile {}\n\n", code(&self.id));
+        let sources: Vec<String> = self.sources.iter().map(|s| escape(s)).collect();
+        md.push_str(&format!("Merged from {}\n\n", sources.join(" → ")));
+        if let Some(name) = &self.display_name {
+            md.push_str(&format!(
+                "**DisplayName**: {} - *{}*\n\n",
+                escape(&name.value),
+                escape(&name.source)
+            ));
+        }
+        if let Some(protocol) = &self.protocol {
+            md.push_str(&format!(
+                "**Protocol**: {} - *{}*\n\n",
+                code(&protocol.value),
+                escape(&protocol.source)
+            ));
+        }
+        if !self.metadata.is_empty() {
+            md.push_str("**Metadata**\n\n| Key | Value | Source |\n| --- | --- | --- |\n");
+            for (key, value) in &self.metadata {
+                md.push_str(&format!(
+                    "| {} | {} | {} |\n",
+                    escape(key),
+                    escape(&value.value),
+                    escape(&value.source)
+                ));
+            }
+            md.push('\n');
+        }
+        for (title, claims) in [
+            ("InputClaims", &self.input_claims),
+            ("OutputClaims", &self.output_claims),
+            ("PersistedClaims", &self.persisted_claims),
+        ] {
+            if claims.is_empty() {
+                continue;
+            }
+            md.push_str(&format!("**{title}**\n\n"));
+            for claim in claims {
+                let details: Vec<String> = claim
+                    .value
+                    .details
+                    .iter()
+                    .map(|(k, v)| format!("{}=\"{}\"", escape(k), escape(v)))
+                    .collect();
+                md.push_str(&format!("- {}", code(&claim.value.claim_type)));
+                if !details.is_empty() {
+                    md.push_str(&format!(" {}", details.join(" ")));
+                }
+                md.push_str(&format!(" - *{}*\n", escape(&claim.source)));
+            }
+            md.push('\n');
+        }
+        md
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tree_sitter::Tree;
+
+    use super::{profile_layer, EffectiveProfile};
+    use crate::workspace::fixtures::{BASE, EXT};
+    use crate::workspace::inheritance::{find_element, MergeKey};
+    use crate::workspace::symbols::ElementKind;
+
+    fn parse(s: &str) -> Tree {
+        let mut t = tree_sitter::Parser::new();
+        t.set_language(&tree_sitter_xml::language_xml()).unwrap();
+        t.parse(s, None).unwrap()
+    }
+
+    fn layer(text: &str) -> super::ProfileLayer {
+        let t = parse(text);
+        let key = MergeKey::Element(
+            ElementKind::TechnicalProfile,
+            String::from("AAD-UserReadUsingObjectId"),
+        );
+        let element = find_element(t.root_node(), text, &key).unwrap();
+        profile_layer(&element, text)
+    }
+
+    #[test]
+    fn test_profile_layer() {
+        let base = layer(BASE);
+        assert_eq!(
+            base.protocol.as_deref(),
+            Some("Proprietary (Web.TPEngine.Providers.AzureActiveDirectoryProvider)")
+        );
+        assert_eq!(base.metadata.len(), 2);
+        assert_eq!(base.input_claims[0].claim_type, "objectId");
+        assert_eq!(
+            base.input_claims[0].details,
+            vec![(String::from("Required"), String::from("true"))]
+        );
+        assert_eq!(base.output_claims.len(), 1);
+    }
+
+    #[test]
+    fn test_merge_layers() {
+        let mut profile = EffectiveProfile::from_layer("AAD", &layer(BASE), "Base.xml");
+        profile.merge(&EffectiveProfile::from_layer("AAD", &layer(EXT), "Ext.xml"));
+        assert_eq!(profile.sources, vec!["Base.xml", "Ext.xml"]);
+        assert_eq!(profile.protocol.as_ref().unwrap().source, "Base.xml");
+        let (key, raise) = &profile.metadata[1];
+        assert_eq!(key, "RaiseErrorIfClaimsPrincipalDoesNotExist");
+        assert_eq!(raise.value, "false");
+        assert_eq!(raise.source, "Ext.xml");
+        let outputs: Vec<_> = profile
+            .output_claims
+            .iter()
+            .map(|c| (c.value.claim_type.as_str(), c.source.as_str()))
+            .collect();
+        assert_eq!(
+            outputs,
+            vec![("email", "Base.xml"), ("displayName", "Ext.xml")]
+        );
+    }
+
+    #[test]
+    fn test_markdown() {
+        let mut profile = EffectiveProfile::from_layer("AAD", &layer(BASE), "Base.xml");
+        profile.merge(&EffectiveProfile::from_layer("AAD", &layer(EXT), "Ext.xml"));
+        let md = profile.to_markdown();
+        assert!(md.starts_with("### TechnicalProfile `AAD`"));
+        assert!(md.contains("Merged from Base.xml → Ext.xml"));
+        assert!(md.contains("| Operation | Read | Base.xml |"));
+        assert!(md.contains("- `objectId` Required=\"true\" - *Base.xml*"));
+        assert!(md.contains("- `displayName` - *Ext.xml*"));
+    }
+
+    #[test]
+    fn test_markdown_escapes_policy_text() {
+        let mut layer = layer(BASE);
+        layer.metadata = vec![(
+            String::from("a|b"),
+            String::from("line one\n`code` | *not bold*"),
+        )];
+        layer.input_claims[0].claim_type = String::from("odd`id");
+        let profile =
+            EffectiveProfile::from_layer("AAD", &layer, "Local_Accounts/TrustFrameworkBase.xml");
+        let md = profile.to_markdown();
+        assert!(md.contains(
+            "| a\\|b | line one \\`code\\` \\| \\*not bold\\* | Local\\_Accounts/TrustFrameworkBase.xml |\n"
+        ));
+        assert!(md.contains("- ``odd`id`` Required=\"true\""));
+        //Every row still has its three cells
+        assert!(md
+            .lines()
+            .filter(|line| line.starts_with('|'))
+            .all(|line| line.matches(" | ").count() == 2));
+    }
+}
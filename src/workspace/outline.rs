@@ -0,0 +1,242 @@
+use lsp_types::{DocumentSymbol, Range, SymbolKind};
+use tree_sitter::Node;
+
+use super::queries::{
+    attribute_value, child_elements, element_text, get_range, parse_tag, start_tag, value_range,
+    Source,
+};
+use super::symbols::ElementKind;
+
+pub fn symbol_kind(kind: ElementKind) -> SymbolKind {
+    match kind {
+        ElementKind::Policy => SymbolKind::FILE,
+        ElementKind::ClaimType => SymbolKind::FIELD,
+        ElementKind::ClaimsTransformation => SymbolKind::FUNCTION,
+        ElementKind::TechnicalProfile => SymbolKind::CLASS,
+        ElementKind::UserJourney | ElementKind::SubJourney => SymbolKind::MODULE,
+        ElementKind::ContentDefinition | ElementKind::LocalizedResources => SymbolKind::FILE,
+        ElementKind::DisplayControl => SymbolKind::INTERFACE,
+        ElementKind::Predicate | ElementKind::PredicateValidation => SymbolKind::ENUM,
+    }
+}
+
+//Elements that only group other elements and get their own level in the outline
+const SECTIONS: &[&str] = &[
+    "BuildingBlocks",
+    "ClaimsSchema",
+    "Predicates",
+    "PredicateValidations",
+    "ClaimsTransformations",
+    "ContentDefinitions",
+    "Localization",
+    "DisplayControls",
+    "ClaimsProviders",
+    "UserJourneys",
+    "SubJourneys",
+    "RelyingParty",
+];
+
+#[allow(deprecated)]
+fn document_symbol(
+    name: String,
+    detail: Option<String>,
+    kind: SymbolKind,
+    range: Range,
+    selection_range: Range,
+    children: Vec<DocumentSymbol>,
+) -> DocumentSymbol {
+    DocumentSymbol {
+        name,
+        detail,
+        kind,
+        tags: None,
+        deprecated: None,
+        range,
+        selection_range,
+        children: (!children.is_empty()).then_some(children),
+    }
+}
+
+//Range of the tag name, used when an element has nothing better to select
+fn name_range(element: &Node) -> Range {
+    start_tag(element)
+        .and_then(|tag| tag.named_child(0))
+        .map(|name| get_range(&name))
+        .unwrap_or_else(|| get_range(element))
+}
+
+//The outline entry for one element, or None when it should be skipped and its
+//children lifted to the parent's level
+fn element_symbol(element: &Node, text: &(impl Source + ?Sized)) -> Option<DocumentSymbol> {
+    let tag = parse_tag(*element, text)?;
+    let range = get_range(element);
+    let children = || outline_children(element, text);
+    if SECTIONS.contains(&tag.name.as_str()) {
+        return Some(document_symbol(
+            tag.name,
+            None,
+            SymbolKind::NAMESPACE,
+            range,
+            name_range(element),
+            children(),
+        ));
+    }
+    match tag.name.as_str() {
+        "ClaimsProvider" => {
+            let display_name = child_elements(element)
+                .into_iter()
+                .find(|child| parse_tag(*child, text).is_some_and(|t| t.name == "DisplayName"))
+                .and_then(|child| element_text(&child, text));
+            let (name, selection_range) = match display_name {
+                Some((name, content)) => (name, get_range(&content)),
+                None => (tag.name, name_range(element)),
+            };
+            Some(document_symbol(
+                name,
+                Some(String::from("ClaimsProvider")),
+                SymbolKind::PACKAGE,
+                range,
+                selection_range,
+                children(),
+            ))
+        }
+        "OrchestrationStep" => {
+            let order = tag.attrs.get("Order")?;
+            let selection_range = attribute_value(element, "Order", text)
+                .map(|order| value_range(&order))
+                .unwrap_or_else(|| name_range(element));
+            Some(document_symbol(
+                format!("Step {order}"),
+                tag.attrs.get("Type").cloned(),
+                SymbolKind::EVENT,
+                range,
+                selection_range,
+                children(),
+            ))
+        }
+        "ClaimsExchange" => Some(document_symbol(
+            tag.attrs.get("Id")?.clone(),
+            tag.attrs.get("TechnicalProfileReferenceId").cloned(),
+            SymbolKind::METHOD,
+            range,
+            value_range(&attribute_value(element, "Id", text)?),
+            vec![],
+        )),
+        "DefaultUserJourney" => Some(document_symbol(
+            tag.name,
+            tag.attrs.get("ReferenceId").cloned(),
+            SymbolKind::PROPERTY,
+            range,
+            name_range(element),
+            vec![],
+        )),
+        name => {
+            let kind = ElementKind::from_definition_tag(name)?;
+            Some(document_symbol(
+                tag.attrs.get("Id")?.clone(),
+                Some(String::from(name)),
+                symbol_kind(kind),
+                range,
+                value_range(&attribute_value(element, "Id", text)?),
+                children(),
+            ))
+        }
+    }
+}
+
+fn outline_children(element: &Node, text: &(impl Source + ?Sized)) -> Vec<DocumentSymbol> {
+    child_elements(element)
+        .into_iter()
+        .flat_map(|child| match element_symbol(&child, text) {
+            Some(symbol) => vec![symbol],
+            None => outline_children(&child, text),
+        })
+        .collect()
+}
+
+//Hierarchical outline of a policy, one entry per top level section
+pub fn outline(root_node: Node, text: &(impl Source + ?Sized)) -> Vec<DocumentSymbol> {
+    let mut cursor = root_node.walk();
+    let policy = root_node
+        .named_children(&mut cursor)
+        .find(|n| n.grammar_name() == "element");
+    match policy {
+        Some(policy) => outline_children(&policy, text),
+        None => vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lsp_types::{DocumentSymbol, SymbolKind};
+    use tree_sitter::Tree;
+
+    use super::outline;
+    use crate::workspace::fixtures::{position_of, BASE, RP};
+
+    fn parse(s: &str) -> Tree {
+        let mut t = tree_sitter::Parser::new();
+        t.set_language(&tree_sitter_xml::language_xml()).unwrap();
+        t.parse(s, None).unwrap()
+    }
+
+    fn names(symbols: &[DocumentSymbol]) -> Vec<&str> {
+        symbols.iter().map(|s| s.name.as_str()).collect()
+    }
+
+    fn children(symbol: &DocumentSymbol) -> &[DocumentSymbol] {
+        symbol.children.as_deref().unwrap_or_default()
+    }
+
+    #[test]
+    fn test_outline_sections() {
+        let t = parse(BASE);
+        let symbols = outline(t.root_node(), BASE);
+        assert_eq!(
+            names(&symbols),
+            vec!["BuildingBlocks", "ClaimsProviders", "UserJourneys"]
+        );
+        let building_blocks = children(&symbols[0]);
+        assert_eq!(
+            names(building_blocks),
+            vec![
+                "ClaimsSchema",
+                "ClaimsTransformations",
+                "ContentDefinitions"
+            ]
+        );
+        let claims = children(&building_blocks[0]);
+        assert_eq!(names(claims), vec!["objectId", "email", "displayName"]);
+        assert_eq!(claims[0].kind, SymbolKind::FIELD);
+        assert_eq!(
+            claims[0].selection_range.start,
+            position_of(BASE, "objectId", 0, 0)
+        );
+    }
+
+    #[test]
+    fn test_outline_providers_and_steps() {
+        let t = parse(BASE);
+        let symbols = outline(t.root_node(), BASE);
+        let provider = &children(&symbols[1])[0];
+        assert_eq!(provider.name, "Azure Active Directory");
+        assert_eq!(names(children(provider)), vec!["AAD-UserReadUsingObjectId"]);
+        let journey = &children(&symbols[2])[0];
+        assert_eq!(journey.name, "SignUpOrSignIn");
+        let steps = children(journey);
+        assert_eq!(names(steps), vec!["Step 1", "Step 2"]);
+        assert_eq!(steps[1].detail.as_deref(), Some("ClaimsExchange"));
+        assert_eq!(names(children(&steps[1])), vec!["AADUserReadWithObjectId"]);
+    }
+
+    #[test]
+    fn test_outline_relying_party() {
+        let t = parse(RP);
+        let symbols = outline(t.root_node(), RP);
+        assert_eq!(names(&symbols), vec!["RelyingParty"]);
+        assert_eq!(
+            names(children(&symbols[0])),
+            vec!["DefaultUserJourney", "PolicyProfile"]
+        );
+    }
+}
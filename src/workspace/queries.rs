@@ -1,13 +1,103 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::ops::Range as ByteRange;
+use std::sync::OnceLock;
 
-use log::{error, info};
+use log::error;
 use lsp_types::{Position, Range};
-use tree_sitter::{InputEdit, Node, Parser, Point, Query, QueryCursor, QueryMatch, Tree};
+use ropey::Rope;
+use tree_sitter::{Node, Point, Query, QueryCursor, QueryMatch};
+
+use crate::error::ServerError;
+
+//Where the queries read the text of nodes from. Documents hand over their rope
+//so an edit never has to copy the whole text
+pub trait Source {
+    //The bytes in range, in the pieces the text is stored in
+    fn chunks(&self, range: ByteRange<usize>) -> Box<dyn Iterator<Item = &[u8]> + '_>;
+
+    //Only copied when the range spans more than one piece
+    fn slice(&self, range: ByteRange<usize>) -> Option<Cow<'_, str>>;
+
+    //Byte offset of a position with a byte column, None past the end of the line
+    fn offset(&self, pos: Position) -> Option<usize>;
+
+    //Offset of the last `byte` before `end`
+    fn rfind(&self, end: usize, byte: u8) -> Option<usize>;
+
+    fn node_text(&self, node: &Node) -> Option<Cow<'_, str>> {
+        self.slice(node.byte_range())
+    }
+}
+
+impl Source for str {
+    fn chunks(&self, range: ByteRange<usize>) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+        Box::new(self.as_bytes().get(range).into_iter())
+    }
+
+    fn slice(&self, range: ByteRange<usize>) -> Option<Cow<'_, str>> {
+        self.get(range).map(Cow::Borrowed)
+    }
+
+    fn offset(&self, pos: Position) -> Option<usize> {
+        let mut offset = 0;
+        for (i, line) in self.split('\n').enumerate() {
+            if i == pos.line as usize {
+                let character = pos.character as usize;
+                return (character <= line.len()).then_some(offset + character);
+            }
+            offset += line.len() + 1;
+        }
+        None
+    }
+
+    fn rfind(&self, end: usize, byte: u8) -> Option<usize> {
+        self.as_bytes().get(..end)?.iter().rposition(|b| *b == byte)
+    }
+}
+
+impl Source for Rope {
+    fn chunks(&self, range: ByteRange<usize>) -> Box<dyn Iterator<Item = &[u8]> + '_> {
+        match self.get_byte_slice(range) {
+            Some(slice) => Box::new(slice.chunks().map(str::as_bytes)),
+            None => Box::new(std::iter::empty()),
+        }
+    }
+
+    fn slice(&self, range: ByteRange<usize>) -> Option<Cow<'_, str>> {
+        self.get_byte_slice(range).map(Cow::from)
+    }
+
+    fn offset(&self, pos: Position) -> Option<usize> {
+        let line = self.get_line(pos.line as usize)?;
+        let mut length = line.len_bytes();
+        if length > 0 && line.byte(length - 1) == b'\n' {
+            length -= 1;
+        }
+        let character = pos.character as usize;
+        (character <= length).then(|| self.line_to_byte(pos.line as usize) + character)
+    }
+
+    //Walks back from `end` a piece at a time
+    fn rfind(&self, end: usize, byte: u8) -> Option<usize> {
+        let before = self.get_byte_slice(..end)?;
+        let mut chunk_end = end;
+        for chunk in before.chunks_at_byte(end).0.reversed() {
+            let chunk_start = chunk_end - chunk.len();
+            if let Some(i) = chunk.bytes().rposition(|b| b == byte) {
+                return Some(chunk_start + i);
+            }
+            chunk_end = chunk_start;
+        }
+        None
+    }
+}
 
 pub struct IEFQuery {
     query: Query,
 }
 
+#[derive(Clone)]
 pub struct IEFQueryMatch {
     pub txt: String,
     pub range: Range,
@@ -19,6 +109,13 @@ pub struct IEFDefinitionMatch {
     pub id_range: Range,
     //IDK if we want a full tag name or content
 }
+
+pub struct IEFReferenceMatch {
+    pub id: String,
+    pub tag_name: String,
+    pub attr_name: String,
+    pub id_range: Range,
+}
 #[derive(Clone)]
 pub struct XMLElement {
     pub name: String,
@@ -34,19 +131,31 @@ pub fn null_range() -> Range {
         line: 0,
         character: 0,
     };
-    return Range { start, end };
+    Range { start, end }
 }
 
-fn get_range(node: &Node) -> Range {
+//Columns are byte offsets like tree-sitter Points. IEF_Workspace converts them
+//to the negotiated position encoding before they reach the client
+pub fn get_range(node: &Node) -> Range {
     let start = Position {
         line: node.start_position().row as u32,
         character: node.start_position().column as u32,
     };
     let end = Position {
-        line: node.start_position().row as u32,
-        character: node.start_position().column as u32,
+        line: node.end_position().row as u32,
+        character: node.end_position().column as u32,
     };
-    return Range { start, end };
+    Range { start, end }
+}
+
+//Range of an attribute value without the surrounding quotes
+pub fn value_range(node: &Node) -> Range {
+    let mut range = get_range(node);
+    if node.kind() == "AttValue" && node.end_byte() - node.start_byte() >= 2 {
+        range.start.character += 1;
+        range.end.character -= 1;
+    }
+    range
 }
 
 pub fn get_tag_name<'a>(root_node: &'a Node, pos: Position) -> Option<Node<'a>> {
@@ -57,39 +166,143 @@ pub fn get_tag_name<'a>(root_node: &'a Node, pos: Position) -> Option<Node<'a>>
     let mut cur_node = root_node.named_descendant_for_point_range(location, location);
     while cur_node.is_some() && cur_node.unwrap().grammar_name() != "element" {
         cur_node = cur_node.unwrap().parent();
-        if let Some(n) = cur_node {
-            dbg!(n.to_string());
-        }
     }
     cur_node
 }
 
+pub fn get_attribute<'a>(root_node: &'a Node, pos: Position) -> Option<Node<'a>> {
+    let location = Point {
+        row: pos.line as usize,
+        column: pos.character as usize,
+    };
+    let mut cur_node = root_node.named_descendant_for_point_range(location, location);
+    while let Some(n) = cur_node {
+        match n.grammar_name() {
+            "Attribute" => return Some(n),
+            "element" => return None,
+            _ => cur_node = n.parent(),
+        }
+    }
+    None
+}
+
+//Name and value nodes of an Attribute node
+pub fn attribute_parts<'a>(attr: &Node<'a>) -> Option<(Node<'a>, Node<'a>)> {
+    let name = attr.named_child(0)?;
+    let value = attr.named_child(1)?;
+    Some((name, value))
+}
+
+//The start tag (or empty element tag) of an element
+pub fn start_tag<'a>(element: &Node<'a>) -> Option<Node<'a>> {
+    let mut cursor = element.walk();
+    let tag = element
+        .named_children(&mut cursor)
+        .find(|n| n.grammar_name() == "STag" || n.grammar_name() == "EmptyElemTag");
+    tag
+}
+
+//Elements directly inside an element
+pub fn child_elements<'a>(element: &Node<'a>) -> Vec<Node<'a>> {
+    let mut cursor = element.walk();
+    let content = element
+        .named_children(&mut cursor)
+        .find(|n| n.grammar_name() == "content");
+    match content {
+        Some(content) => {
+            let mut cursor = content.walk();
+            let children = content
+                .named_children(&mut cursor)
+                .filter(|n| n.grammar_name() == "element")
+                .collect();
+            children
+        }
+        None => vec![],
+    }
+}
+
+//The element this element is nested in
+pub fn parent_element<'a>(element: &Node<'a>) -> Option<Node<'a>> {
+    let mut cur_node = element.parent();
+    while let Some(n) = cur_node {
+        if n.grammar_name() == "element" {
+            return Some(n);
+        }
+        cur_node = n.parent();
+    }
+    None
+}
+
+//The AttValue node of an attribute on an element's start tag
+pub fn attribute_value<'a>(
+    element: &Node<'a>,
+    name: &str,
+    text: &(impl Source + ?Sized),
+) -> Option<Node<'a>> {
+    let tag = start_tag(element)?;
+    let mut cursor = tag.walk();
+    let attr = tag.named_children(&mut cursor).find(|n| {
+        n.grammar_name() == "Attribute"
+            && attribute_parts(n)
+                .and_then(|(attr_name, _)| text.node_text(&attr_name))
+                .is_some_and(|attr_name| attr_name == name)
+    });
+    attr.and_then(|a| attribute_parts(&a))
+        .map(|(_, value)| value)
+}
+
+//Text content of an element, e.g. <DisplayName>text</DisplayName>
+pub fn element_text<'a>(
+    element: &Node<'a>,
+    text: &(impl Source + ?Sized),
+) -> Option<(String, Node<'a>)> {
+    let mut cursor = element.walk();
+    let content = element
+        .named_children(&mut cursor)
+        .find(|n| n.grammar_name() == "content");
+    let content = content?;
+    let txt = text.node_text(&content)?.trim().to_string();
+    Some((txt, content))
+}
+
+//document > TrustFrameworkPolicy > content > BasePolicy
+const HEADER_DEPTH: u32 = 3;
+
 //I forget why this abstraction exists
 impl IEFQuery {
-    pub fn new(query_txt: &str) -> Self {
-        let query = Query::new(&tree_sitter_xml::language_xml(), query_txt).unwrap();
-        IEFQuery { query }
+    pub fn new(query_txt: &str) -> Result<Self, ServerError> {
+        let query =
+            Query::new(&tree_sitter_xml::language_xml(), query_txt).map_err(ServerError::Query)?;
+        Ok(IEFQuery { query })
     }
 
-    pub fn first(&self, root_node: Node, text: &str) -> Option<IEFQueryMatch> {
+    //Only asked about the policy header, the root element and its BasePolicy,
+    //so matches are not looked for below that
+    pub fn first(&self, root_node: Node, text: &(impl Source + ?Sized)) -> Option<IEFQueryMatch> {
         let mut cursor = QueryCursor::new();
-        return cursor
-            .matches(&self.query, root_node, text.as_bytes())
+        cursor.set_max_start_depth(Some(HEADER_DEPTH));
+        cursor
+            .matches(&self.query, root_node, |node: Node| {
+                text.chunks(node.byte_range())
+            })
             .filter_map(|m| m.captures.last())
-            .filter_map(|c| match c.node.utf8_text(text.as_bytes()) {
-                Ok(s) => Some(IEFQueryMatch {
-                    range: get_range(&c.node),
-                    txt: String::from(s).replace("\"", ""),
+            .filter_map(|c| match text.node_text(&c.node) {
+                Some(s) => Some(IEFQueryMatch {
+                    range: value_range(&c.node),
+                    txt: s.replace("\"", ""),
                 }),
-                Err(e) => {
+                None => {
                     error!("Could not find text in query node !");
                     None
                 }
             })
-            .next();
+            .next()
     }
 
-    fn parse_definition_match(m: QueryMatch, text: &str) -> Option<IEFDefinitionMatch> {
+    fn parse_definition_match(
+        m: QueryMatch,
+        text: &(impl Source + ?Sized),
+    ) -> Option<IEFDefinitionMatch> {
         let first = m.captures.first();
         let last = m.captures.last();
         if first.is_none() || last.is_none() {
@@ -97,137 +310,220 @@ impl IEFQuery {
         }
         let tag_name_capt = first.unwrap();
         let id_capt = last.unwrap();
-        let tag_name_res = tag_name_capt.node.utf8_text(text.as_bytes());
-        let id_name_res = id_capt.node.utf8_text(text.as_bytes());
-        if tag_name_res.is_err() || id_name_res.is_err() {
+        let tag_name_res = text.node_text(&tag_name_capt.node);
+        let id_name_res = text.node_text(&id_capt.node);
+        if tag_name_res.is_none() || id_name_res.is_none() {
             return None;
         }
         Some(IEFDefinitionMatch {
-            id: String::from(id_name_res.unwrap()).replace("\"", ""),
+            id: id_name_res.unwrap().replace("\"", ""),
             //Tag name not in quotes so we don't replace
-            tag_name: String::from(tag_name_res.unwrap()),
-            id_range: get_range(&id_capt.node),
+            tag_name: tag_name_res.unwrap().into_owned(),
+            id_range: value_range(&id_capt.node),
         })
     }
 
-    pub fn all(&self, root_node: Node, text: &str) -> Vec<IEFDefinitionMatch> {
+    pub fn all(&self, root_node: Node, text: &(impl Source + ?Sized)) -> Vec<IEFDefinitionMatch> {
         let mut cursor = QueryCursor::new();
-        return cursor
-            .matches(&self.query, root_node, text.as_bytes())
+        cursor
+            .matches(&self.query, root_node, |node: Node| {
+                text.chunks(node.byte_range())
+            })
             .filter_map(|m| IEFQuery::parse_definition_match(m, text))
-            .collect();
+            .collect()
+    }
+
+    fn capture_node<'a>(&self, m: &QueryMatch<'_, 'a>, name: &str) -> Option<Node<'a>> {
+        let index = self.query.capture_index_for_name(name)?;
+        Some(m.captures.iter().find(|c| c.index == index)?.node)
+    }
+
+    fn parse_reference_match(
+        &self,
+        m: QueryMatch,
+        text: &(impl Source + ?Sized),
+    ) -> Option<IEFReferenceMatch> {
+        let tag_name = text.node_text(&self.capture_node(&m, "tagName")?)?;
+        let attr_name = text.node_text(&self.capture_node(&m, "attrName")?)?;
+        let id = self.capture_node(&m, "id")?;
+        Some(IEFReferenceMatch {
+            id: text.node_text(&id)?.replace('"', ""),
+            tag_name: tag_name.into_owned(),
+            attr_name: attr_name.into_owned(),
+            id_range: value_range(&id),
+        })
+    }
+
+    pub fn references(
+        &self,
+        root_node: Node,
+        text: &(impl Source + ?Sized),
+    ) -> Vec<IEFReferenceMatch> {
+        let mut cursor = QueryCursor::new();
+        cursor
+            .matches(&self.query, root_node, |node: Node| {
+                text.chunks(node.byte_range())
+            })
+            .filter_map(|m| self.parse_reference_match(m, text))
+            .collect()
     }
 }
 
-pub fn parse_attrs(node: Node, text: &str) -> HashMap<String, String> {
-    let query = attr_query();
+pub fn parse_attrs(node: Node, text: &(impl Source + ?Sized)) -> HashMap<String, String> {
+    //Only look at the element's own tag, not the attributes of its children
+    let node = match node.grammar_name() {
+        "element" => match start_tag(&node) {
+            Some(tag) => tag,
+            None => return HashMap::new(),
+        },
+        _ => node,
+    };
+    let Some(query) = attr_query() else {
+        return HashMap::new();
+    };
     let mut cursor = QueryCursor::new();
-    return HashMap::from_iter(
+    HashMap::from_iter(
         cursor
-            .matches(&query.query, node, text.as_bytes())
+            .matches(&query.query, node, |node: Node| {
+                text.chunks(node.byte_range())
+            })
             .filter_map(|m| {
-                let key = m.captures.first();
-                let value = m.captures.last();
-                if key.is_none() || value.is_none() {
-                    return None;
-                }
-                let key = key.unwrap();
-                let value = value.unwrap();
-                let key_txt = key.node.utf8_text(text.as_bytes());
-                let val_txt = value.node.utf8_text(text.as_bytes());
-                if key_txt.is_ok() && val_txt.is_ok() {
-                    return Some((
-                        String::from(key_txt.unwrap()).replace("\"", ""),
-                        String::from(val_txt.unwrap()).replace("\"", ""),
-                    ));
-                }
-                return None;
+                let key = m.captures.first()?;
+                let value = m.captures.last()?;
+                let key_txt = text.node_text(&key.node)?;
+                let val_txt = text.node_text(&value.node)?;
+                Some((key_txt.replace('"', ""), val_txt.replace('"', "")))
             }),
-    );
+    )
 }
 
-pub fn parse_tag(node: Node, text: &str) -> Option<XMLElement> {
-    dbg!(node.to_string());
-    if let Some(name) = tag_name_query().first(node, text) {
-        return Some(XMLElement {
-            name: name.txt,
-            attrs: parse_attrs(node, text),
-        });
-    } else {
-        return None;
-    }
+pub fn parse_tag(node: Node, text: &(impl Source + ?Sized)) -> Option<XMLElement> {
+    tag_name_query()?.first(node, text).map(|name| XMLElement {
+        name: name.txt,
+        attrs: parse_attrs(node, text),
+    })
 }
 
-pub fn base_policy_query() -> IEFQuery {
-    IEFQuery::new(
+//The queries are fixed, one that does not compile is logged once and matches
+//nothing instead of taking the server down
+fn compile(
+    query: &'static OnceLock<Option<IEFQuery>>,
+    query_txt: &str,
+) -> Option<&'static IEFQuery> {
+    query
+        .get_or_init(|| IEFQuery::new(query_txt).map_err(|e| error!("{e}")).ok())
+        .as_ref()
+}
+
+pub fn base_policy_query() -> Option<&'static IEFQuery> {
+    static QUERY: OnceLock<Option<IEFQuery>> = OnceLock::new();
+    compile(
+        &QUERY,
         "(element 
-      (STag 
-        (Name) @tagName) 
-      (content 
-        (element
           (STag 
-            (Name) @innerName) (content) @basePolicyId
-          (#eq? @innerName \"PolicyId\")) @content 
-        (#eq? @tagName \"BasePolicy\")))
-      ",
+            (Name) @tagName) 
+          (content 
+            (element
+              (STag 
+                (Name) @innerName) (content) @basePolicyId
+              (#eq? @innerName \"PolicyId\")) @content 
+            (#eq? @tagName \"BasePolicy\")))
+          ",
     )
 }
 
-pub fn id_query() -> IEFQuery {
-    IEFQuery::new(
+pub fn id_query() -> Option<&'static IEFQuery> {
+    static QUERY: OnceLock<Option<IEFQuery>> = OnceLock::new();
+    compile(
+        &QUERY,
         "(element 
-         (STag 
-          (Name) 
-          (Attribute 
-           (Name) @name 
-           (AttValue) @PolicyId 
-           (#eq? @name \"PolicyId\")
-           )
-          )
-         )",
+             (STag 
+              (Name) 
+              (Attribute 
+               (Name) @name 
+               (AttValue) @PolicyId 
+               (#eq? @name \"PolicyId\")
+               )
+              )
+             )",
     )
 }
 
-pub fn tag_name_query() -> IEFQuery {
-    IEFQuery::new(
+pub fn tag_name_query() -> Option<&'static IEFQuery> {
+    static QUERY: OnceLock<Option<IEFQuery>> = OnceLock::new();
+    compile(
+        &QUERY,
         "[
-        (STag (Name) @tagName)
-        (EmptyElemTag (Name) @tagName)
-        ]",
+            (STag (Name) @tagName)
+            (EmptyElemTag (Name) @tagName)
+            ]",
     )
 }
 
-pub fn definition_query() -> IEFQuery {
-    IEFQuery::new(
+pub fn definition_query() -> Option<&'static IEFQuery> {
+    static QUERY: OnceLock<Option<IEFQuery>> = OnceLock::new();
+    compile(
+        &QUERY,
         "(element 
-         (STag 
-          (Name) @tagName
-          (Attribute 
-           (Name)  @attrName
-           (AttValue) @id 
-           (#eq? @attrName \"Id\")
-           )
-          )
-         )",
+             [(STag 
+              (Name) @tagName
+              (Attribute 
+               (Name)  @attrName
+               (AttValue) @id 
+               (#eq? @attrName \"Id\")
+               )
+              )
+             (EmptyElemTag 
+              (Name) @tagName
+              (Attribute 
+               (Name)  @attrName
+               (AttValue) @id 
+               (#eq? @attrName \"Id\")
+               )
+              )]
+             )",
+    )
+}
+
+pub fn reference_query() -> Option<&'static IEFQuery> {
+    static QUERY: OnceLock<Option<IEFQuery>> = OnceLock::new();
+    compile(
+        &QUERY,
+        "[(STag 
+              (Name) @tagName
+              (Attribute 
+               (Name)  @attrName
+               (AttValue) @id 
+               (#match? @attrName \"(ReferenceId|^Id)$\")
+               )
+              )
+             (EmptyElemTag 
+              (Name) @tagName
+              (Attribute 
+               (Name)  @attrName
+               (AttValue) @id 
+               (#match? @attrName \"(ReferenceId|^Id)$\")
+               )
+              )]",
     )
 }
 
-pub fn attr_query() -> IEFQuery {
-    IEFQuery::new(
+pub fn attr_query() -> Option<&'static IEFQuery> {
+    static QUERY: OnceLock<Option<IEFQuery>> = OnceLock::new();
+    compile(
+        &QUERY,
         "(
-           (Name)  @attrName
-           (AttValue) @id 
-        )",
+               (Name)  @attrName
+               (AttValue) @id 
+            )",
     )
 }
 #[cfg(test)]
 mod test {
-    use std::any::Any;
-
+    use crate::error::ServerError;
     use crate::workspace::queries::{base_policy_query, definition_query, parse_tag};
 
-    use super::{get_tag_name, id_query};
-    use log::error;
+    use super::{attr_query, get_tag_name, id_query, reference_query, tag_name_query, IEFQuery};
     use lsp_types::Position;
     use tree_sitter::Tree;
 
@@ -262,13 +558,13 @@ mod test {
             </TrustFrameworkPolicy>
         ");
         let mut t = tree_sitter::Parser::new();
-        t.set_language(&tree_sitter_xml::language_xml());
-        return (t.parse(s.as_str(), None).unwrap(), s);
+        t.set_language(&tree_sitter_xml::language_xml()).unwrap();
+        (t.parse(s.as_str(), None).unwrap(), s)
     }
     #[test]
     fn test_id_query() {
         let (t, s) = get_test_str();
-        let query = id_query();
+        let query = id_query().unwrap();
         let res = query.first(t.root_node(), s.as_str());
         assert!(res.is_some());
         let id = res.unwrap();
@@ -277,7 +573,7 @@ mod test {
     #[test]
     fn test_base_id_query() {
         let (t, s) = get_test_str();
-        let query = base_policy_query();
+        let query = base_policy_query().unwrap();
         let res = query.first(t.root_node(), s.as_str());
         assert!(res.is_some());
         let id = res.unwrap();
@@ -287,7 +583,7 @@ mod test {
     #[test]
     fn test_def_query() {
         let (t, s) = get_test_str();
-        let query = definition_query();
+        let query = definition_query().unwrap();
         let res = query.all(t.root_node(), s.as_str());
         assert_eq!(res.len(), 1);
         let tp_def = res.first().unwrap();
@@ -295,6 +591,20 @@ mod test {
         assert_eq!(tp_def.tag_name.as_str(), "TechnicalProfile");
     }
 
+    #[test]
+    fn test_queries_compile() {
+        assert!(base_policy_query().is_some());
+        assert!(id_query().is_some());
+        assert!(tag_name_query().is_some());
+        assert!(definition_query().is_some());
+        assert!(reference_query().is_some());
+        assert!(attr_query().is_some());
+        assert!(matches!(
+            IEFQuery::new("(element (Nonsense) @name"),
+            Err(ServerError::Query(_))
+        ));
+    }
+
     #[test]
     fn test_tag_info() {
         let (t, s) = get_test_str();
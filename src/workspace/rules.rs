@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use lsp_types::DiagnosticSeverity;
+use serde::Deserialize;
+
+//The checks behind the diagnostics. The name is the code of the diagnostic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    MissingBasePolicy,
+    MissingPolicyId,
+    DuplicatePolicyId,
+    BasePolicyOutsideSet,
+}
+
+impl Rule {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Rule::MissingBasePolicy => "missing-base-policy",
+            Rule::MissingPolicyId => "missing-policy-id",
+            Rule::DuplicatePolicyId => "duplicate-policy-id",
+            Rule::BasePolicyOutsideSet => "base-policy-outside-set",
+        }
+    }
+
+    fn default_severity(&self) -> Severity {
+        match self {
+            Rule::MissingBasePolicy | Rule::MissingPolicyId => Severity::Error,
+            //The BasePolicy may still resolve to the one that was meant
+            Rule::DuplicatePolicyId | Rule::BasePolicyOutsideSet => Severity::Warning,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+    Information,
+    Hint,
+    Off,
+}
+
+//The "rules" setting, like {"duplicate-policy-id": "off"}. Rules that are not
+//in it keep their default severity
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct RuleSeverities(HashMap<Rule, Severity>);
+
+impl RuleSeverities {
+    //None when the rule is turned off
+    pub fn severity(&self, rule: Rule) -> Option<DiagnosticSeverity> {
+        match self
+            .0
+            .get(&rule)
+            .copied()
+            .unwrap_or(rule.default_severity())
+        {
+            Severity::Error => Some(DiagnosticSeverity::ERROR),
+            Severity::Warning => Some(DiagnosticSeverity::WARNING),
+            Severity::Information => Some(DiagnosticSeverity::INFORMATION),
+            Severity::Hint => Some(DiagnosticSeverity::HINT),
+            Severity::Off => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lsp_types::DiagnosticSeverity;
+
+    use super::{Rule, RuleSeverities};
+
+    #[test]
+    fn test_rule_severities() {
+        let rules: RuleSeverities = serde_json::from_value(serde_json::json!({
+            "missing-base-policy": "warning",
+            "duplicate-policy-id": "off",
+        }))
+        .unwrap();
+        assert_eq!(
+            rules.severity(Rule::MissingBasePolicy),
+            Some(DiagnosticSeverity::WARNING)
+        );
+        assert_eq!(rules.severity(Rule::DuplicatePolicyId), None);
+        assert_eq!(
+            rules.severity(Rule::MissingPolicyId),
+            Some(DiagnosticSeverity::ERROR)
+        );
+        assert!(serde_json::from_value::<RuleSeverities>(serde_json::json!({
+            "missing-base-policy": "fatal"
+        }))
+        .is_err());
+    }
+}
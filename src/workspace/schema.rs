@@ -0,0 +1,696 @@
+//Content model of the TrustFrameworkPolicy 0.3.0.0 schema, keyed by element name.
+//Elements that share a name in different places (e.g. InputClaim in a
+//ClaimsTransformation and in a TechnicalProfile) share one entry.
+
+pub struct AttributeModel {
+    pub name: &'static str,
+    pub required: bool,
+    pub values: &'static [&'static str],
+}
+
+pub struct ElementModel {
+    pub name: &'static str,
+    //Allowed child elements in schema order
+    pub children: &'static [&'static str],
+    pub attributes: &'static [AttributeModel],
+    //Enumerated text content, e.g. <DataType>string</DataType>
+    pub values: &'static [&'static str],
+}
+
+const fn req(name: &'static str) -> AttributeModel {
+    AttributeModel {
+        name,
+        required: true,
+        values: &[],
+    }
+}
+
+const fn opt(name: &'static str) -> AttributeModel {
+    AttributeModel {
+        name,
+        required: false,
+        values: &[],
+    }
+}
+
+const fn one_of(name: &'static str, values: &'static [&'static str]) -> AttributeModel {
+    AttributeModel {
+        name,
+        required: false,
+        values,
+    }
+}
+
+const fn el(
+    name: &'static str,
+    children: &'static [&'static str],
+    attributes: &'static [AttributeModel],
+) -> ElementModel {
+    ElementModel {
+        name,
+        children,
+        attributes,
+        values: &[],
+    }
+}
+
+const fn text(name: &'static str, values: &'static [&'static str]) -> ElementModel {
+    ElementModel {
+        name,
+        children: &[],
+        attributes: &[],
+        values,
+    }
+}
+
+const BOOLEAN: &[&str] = &["true", "false"];
+const PROTOCOLS: &[&str] = &[
+    "OAuth1",
+    "OAuth2",
+    "SAML2",
+    "OpenIdConnect",
+    "Proprietary",
+    "None",
+];
+const DATA_TYPES: &[&str] = &[
+    "boolean",
+    "date",
+    "dateTime",
+    "duration",
+    "int",
+    "long",
+    "string",
+    "stringCollection",
+    "alternativeSecurityIdCollection",
+    "phoneNumber",
+    "userIdentity",
+    "userIdentityCollection",
+];
+const MERGE_BEHAVIORS: &[&str] = &["Append", "Prepend", "ReplaceAll"];
+const TOKEN_FORMATS: &[&str] = &["JSON", "JWT", "SAML11", "SAML2"];
+const CLAIM_ATTRIBUTES: &[AttributeModel] = &[
+    req("ClaimTypeReferenceId"),
+    opt("DefaultValue"),
+    opt("PartnerClaimType"),
+    one_of("Required", BOOLEAN),
+    one_of("AlwaysUseDefaultValue", BOOLEAN),
+    opt("TransformationClaimType"),
+];
+
+static ELEMENTS: &[ElementModel] = &[
+    el(
+        "TrustFrameworkPolicy",
+        &[
+            "BasePolicy",
+            "BuildingBlocks",
+            "ClaimsProviders",
+            "UserJourneys",
+            "SubJourneys",
+            "RelyingParty",
+        ],
+        &[
+            req("PolicySchemaVersion"),
+            req("TenantId"),
+            opt("TenantObjectId"),
+            req("PolicyId"),
+            req("PublicPolicyUri"),
+            one_of("DeploymentMode", &["Development", "Production"]),
+            opt("UserJourneyRecorderEndpoint"),
+        ],
+    ),
+    el("BasePolicy", &["TenantId", "PolicyId"], &[]),
+    el(
+        "BuildingBlocks",
+        &[
+            "ClaimsSchema",
+            "Predicates",
+            "PredicateValidations",
+            "ClaimsTransformations",
+            "ContentDefinitions",
+            "Localization",
+            "DisplayControls",
+        ],
+        &[],
+    ),
+    el("ClaimsSchema", &["ClaimType"], &[]),
+    el(
+        "ClaimType",
+        &[
+            "DisplayName",
+            "DataType",
+            "DefaultPartnerClaimTypes",
+            "Mask",
+            "AdminHelpText",
+            "UserHelpText",
+            "UserInputType",
+            "Restriction",
+            "PredicateValidationReference",
+        ],
+        &[req("Id")],
+    ),
+    text("DataType", DATA_TYPES),
+    text(
+        "UserInputType",
+        &[
+            "CheckboxMultiSelect",
+            "DateTimeDropdown",
+            "DropdownSingleSelect",
+            "EmailBox",
+            "Paragraph",
+            "Password",
+            "RadioSingleSelect",
+            "Readonly",
+            "TextBox",
+        ],
+    ),
+    el("DefaultPartnerClaimTypes", &["Protocol"], &[]),
+    el(
+        "Protocol",
+        &[],
+        &[
+            one_of("Name", PROTOCOLS),
+            opt("Handler"),
+            opt("PartnerClaimType"),
+        ],
+    ),
+    el(
+        "Mask",
+        &[],
+        &[one_of("Type", &["Simple", "Regex"]), opt("Regex")],
+    ),
+    el(
+        "Restriction",
+        &["Enumeration", "Pattern"],
+        &[one_of("MergeBehavior", MERGE_BEHAVIORS)],
+    ),
+    el(
+        "Enumeration",
+        &[],
+        &[
+            req("Text"),
+            req("Value"),
+            one_of("SelectByDefault", BOOLEAN),
+        ],
+    ),
+    el("Pattern", &[], &[req("RegularExpression"), opt("HelpText")]),
+    el("PredicateValidationReference", &[], &[req("Id")]),
+    el("Predicates", &["Predicate"], &[]),
+    el(
+        "Predicate",
+        &["UserHelpText", "Parameters"],
+        &[
+            req("Id"),
+            one_of(
+                "Method",
+                &[
+                    "IsLengthRange",
+                    "MatchesRegex",
+                    "IncludesCharacters",
+                    "IsDateRange",
+                ],
+            ),
+            opt("HelpText"),
+        ],
+    ),
+    el("Parameters", &["Parameter"], &[]),
+    el(
+        "Parameter",
+        &[],
+        &[one_of(
+            "Id",
+            &["Minimum", "Maximum", "Pattern", "CharacterSet"],
+        )],
+    ),
+    el("PredicateValidations", &["PredicateValidation"], &[]),
+    el("PredicateValidation", &["PredicateGroups"], &[req("Id")]),
+    el("PredicateGroups", &["PredicateGroup"], &[]),
+    el(
+        "PredicateGroup",
+        &["UserHelpText", "PredicateReferences"],
+        &[req("Id")],
+    ),
+    el(
+        "PredicateReferences",
+        &["PredicateReference"],
+        &[opt("MatchAtLeast")],
+    ),
+    el("PredicateReference", &[], &[req("Id")]),
+    el("ClaimsTransformations", &["ClaimsTransformation"], &[]),
+    el(
+        "ClaimsTransformation",
+        &["InputClaims", "InputParameters", "OutputClaims"],
+        &[
+            req("Id"),
+            one_of(
+                "TransformationMethod",
+                &[
+                    "AddItemToStringCollection",
+                    "AddParameterToStringCollection",
+                    "AndClaims",
+                    "AssertBooleanClaimIsEqualToValue",
+                    "AssertDateTimeIsGreaterThan",
+                    "AssertStringClaimsAreEqual",
+                    "BuildUri",
+                    "ChangeCase",
+                    "CompareClaims",
+                    "CompareClaimToValue",
+                    "ConvertBooleanToString",
+                    "ConvertDateTimeToDateClaim",
+                    "ConvertNumberToStringClaim",
+                    "ConvertStringToDateTime",
+                    "CopyClaim",
+                    "CopyClaimIfPredicateMatch",
+                    "CreateAlternativeSecurityId",
+                    "CreateRandomString",
+                    "CreateStringClaim",
+                    "DateTimeComparison",
+                    "DoesClaimExist",
+                    "FormatLocalizedString",
+                    "FormatStringClaim",
+                    "FormatStringMultipleClaims",
+                    "GetClaimFromJson",
+                    "GetClaimsFromJsonArray",
+                    "GetCurrentDateTime",
+                    "GetLocalizedStringsTransformation",
+                    "GetMappedValueFromLocalizedCollection",
+                    "GetSingleItemFromJson",
+                    "GetSingleValueFromJsonArray",
+                    "Hash",
+                    "IsTermsOfUseConsentRequired",
+                    "LookupValue",
+                    "NullClaim",
+                    "ParseDomain",
+                    "ParsePhoneNumber",
+                    "SetClaimIfBooleansMatch",
+                    "SetClaimsIfRegexMatch",
+                    "SetClaimsIfStringsAreEqual",
+                    "SetClaimsIfStringsMatch",
+                    "StringContains",
+                    "StringJoin",
+                    "StringReplace",
+                    "StringSplit",
+                    "StringSubstring",
+                ],
+            ),
+        ],
+    ),
+    el("InputParameters", &["InputParameter"], &[]),
+    el(
+        "InputParameter",
+        &[],
+        &[req("Id"), one_of("DataType", DATA_TYPES), req("Value")],
+    ),
+    el("ContentDefinitions", &["ContentDefinition"], &[]),
+    el(
+        "ContentDefinition",
+        &[
+            "LoadUri",
+            "RecoveryUri",
+            "DataUri",
+            "Metadata",
+            "LocalizedResourcesReferences",
+        ],
+        &[req("Id")],
+    ),
+    el(
+        "LocalizedResourcesReferences",
+        &["LocalizedResourcesReference"],
+        &[one_of("MergeBehavior", MERGE_BEHAVIORS)],
+    ),
+    el(
+        "LocalizedResourcesReference",
+        &[],
+        &[req("Language"), req("LocalizedResourcesReferenceId")],
+    ),
+    el(
+        "Localization",
+        &["SupportedLanguages", "LocalizedResources"],
+        &[one_of("Enabled", BOOLEAN)],
+    ),
+    el(
+        "SupportedLanguages",
+        &["SupportedLanguage"],
+        &[
+            req("DefaultLanguage"),
+            one_of("MergeBehavior", MERGE_BEHAVIORS),
+        ],
+    ),
+    el(
+        "LocalizedResources",
+        &["LocalizedCollections", "LocalizedStrings"],
+        &[req("Id")],
+    ),
+    el("LocalizedCollections", &["LocalizedCollection"], &[]),
+    el(
+        "LocalizedCollection",
+        &["Item"],
+        &[
+            req("ElementType"),
+            req("ElementId"),
+            req("TargetCollection"),
+        ],
+    ),
+    el("LocalizedStrings", &["LocalizedString"], &[]),
+    el(
+        "LocalizedString",
+        &[],
+        &[
+            one_of(
+                "ElementType",
+                &[
+                    "ClaimsProvider",
+                    "ClaimType",
+                    "ClaimsTransformation",
+                    "DisplayControl",
+                    "ErrorMessage",
+                    "FormatLocalizedStringTransformationClaimType",
+                    "GetLocalizedStringsTransformationClaimType",
+                    "InputValidation",
+                    "Predicate",
+                    "UxElement",
+                ],
+            ),
+            opt("ElementId"),
+            req("StringId"),
+        ],
+    ),
+    el("DisplayControls", &["DisplayControl"], &[]),
+    el(
+        "DisplayControl",
+        &["InputClaims", "DisplayClaims", "OutputClaims", "Actions"],
+        &[
+            req("Id"),
+            one_of(
+                "UserInterfaceControlType",
+                &["VerificationControl", "TotpQrCodeControl", "CaptchaControl"],
+            ),
+        ],
+    ),
+    el("DisplayClaims", &["DisplayClaim"], &[]),
+    el(
+        "DisplayClaim",
+        &[],
+        &[
+            opt("ClaimTypeReferenceId"),
+            opt("DisplayControlReferenceId"),
+            one_of("Required", BOOLEAN),
+        ],
+    ),
+    el("Actions", &["Action"], &[]),
+    el("Action", &["ValidationClaimsExchange"], &[req("Id")]),
+    el(
+        "ValidationClaimsExchange",
+        &["ValidationClaimsExchangeTechnicalProfile"],
+        &[],
+    ),
+    el(
+        "ValidationClaimsExchangeTechnicalProfile",
+        &["Preconditions"],
+        &[req("TechnicalProfileReferenceId")],
+    ),
+    el("ClaimsProviders", &["ClaimsProvider"], &[]),
+    el(
+        "ClaimsProvider",
+        &["Domain", "DisplayName", "TechnicalProfiles"],
+        &[],
+    ),
+    el("TechnicalProfiles", &["TechnicalProfile"], &[]),
+    el(
+        "TechnicalProfile",
+        &[
+            "Domain",
+            "DisplayName",
+            "Description",
+            "Protocol",
+            "InputTokenFormat",
+            "OutputTokenFormat",
+            "Metadata",
+            "CryptographicKeys",
+            "InputClaimsTransformations",
+            "InputClaims",
+            "DisplayClaims",
+            "PersistedClaims",
+            "OutputClaims",
+            "OutputClaimsTransformations",
+            "ValidationTechnicalProfiles",
+            "SubjectNamingInfo",
+            "IncludeInSso",
+            "IncludeClaimsFromTechnicalProfile",
+            "IncludeTechnicalProfile",
+            "UseTechnicalProfileForSessionManagement",
+            "EnabledForUserJourneys",
+        ],
+        &[req("Id")],
+    ),
+    text("InputTokenFormat", TOKEN_FORMATS),
+    text("OutputTokenFormat", TOKEN_FORMATS),
+    el("Metadata", &["Item"], &[]),
+    el("Item", &[], &[opt("Key"), opt("Text"), opt("Value")]),
+    el("CryptographicKeys", &["Key"], &[]),
+    el("Key", &[], &[req("Id"), req("StorageReferenceId")]),
+    el(
+        "InputClaimsTransformations",
+        &["InputClaimsTransformation"],
+        &[],
+    ),
+    el("InputClaimsTransformation", &[], &[req("ReferenceId")]),
+    el("InputClaims", &["InputClaim"], &[]),
+    el("InputClaim", &[], CLAIM_ATTRIBUTES),
+    el("PersistedClaims", &["PersistedClaim"], &[]),
+    el("PersistedClaim", &[], CLAIM_ATTRIBUTES),
+    el("OutputClaims", &["OutputClaim"], &[]),
+    el("OutputClaim", &[], CLAIM_ATTRIBUTES),
+    el(
+        "OutputClaimsTransformations",
+        &["OutputClaimsTransformation"],
+        &[],
+    ),
+    el("OutputClaimsTransformation", &[], &[req("ReferenceId")]),
+    el(
+        "ValidationTechnicalProfiles",
+        &["ValidationTechnicalProfile"],
+        &[],
+    ),
+    el(
+        "ValidationTechnicalProfile",
+        &["Preconditions"],
+        &[
+            req("ReferenceId"),
+            one_of("ContinueOnError", BOOLEAN),
+            one_of("ContinueOnSuccess", BOOLEAN),
+        ],
+    ),
+    el(
+        "SubjectNamingInfo",
+        &[],
+        &[req("ClaimType"), opt("NameFormat"), opt("ExcludeAsClaim")],
+    ),
+    text("IncludeInSso", BOOLEAN),
+    el(
+        "IncludeClaimsFromTechnicalProfile",
+        &[],
+        &[req("ReferenceId")],
+    ),
+    el("IncludeTechnicalProfile", &[], &[req("ReferenceId")]),
+    el(
+        "UseTechnicalProfileForSessionManagement",
+        &[],
+        &[req("ReferenceId")],
+    ),
+    text(
+        "EnabledForUserJourneys",
+        &[
+            "true",
+            "false",
+            "Always",
+            "Never",
+            "OnClaimsExistence",
+            "OnItemExistenceInStringCollectionClaim",
+            "OnItemAbsenceInStringCollectionClaim",
+        ],
+    ),
+    el("UserJourneys", &["UserJourney"], &[]),
+    el(
+        "UserJourney",
+        &[
+            "AuthorizationTechnicalProfiles",
+            "OrchestrationSteps",
+            "ClientDefinition",
+        ],
+        &[
+            req("Id"),
+            opt("DefaultCpimIssuerTechnicalProfileReferenceId"),
+        ],
+    ),
+    el("OrchestrationSteps", &["OrchestrationStep"], &[]),
+    el(
+        "OrchestrationStep",
+        &[
+            "Preconditions",
+            "ClaimsProviderSelections",
+            "ClaimsExchanges",
+            "JourneyList",
+        ],
+        &[
+            req("Order"),
+            one_of(
+                "Type",
+                &[
+                    "ClaimsProviderSelection",
+                    "CombinedSignInAndSignUp",
+                    "ClaimsExchange",
+                    "GetClaims",
+                    "InvokeSubJourney",
+                    "ReviewScreen",
+                    "SendClaims",
+                    "UserDialog",
+                ],
+            ),
+            opt("ContentDefinitionReferenceId"),
+            opt("CpimIssuerTechnicalProfileReferenceId"),
+        ],
+    ),
+    el("Preconditions", &["Precondition"], &[]),
+    el(
+        "Precondition",
+        &["Value", "Action"],
+        &[
+            one_of("Type", &["ClaimsExist", "ClaimEquals"]),
+            one_of("ExecuteActionsIf", BOOLEAN),
+        ],
+    ),
+    el(
+        "ClaimsProviderSelections",
+        &["ClaimsProviderSelection"],
+        &[one_of(
+            "DisplayOption",
+            &["DoNotShowSingleProvider", "ShowSingleProvider"],
+        )],
+    ),
+    el(
+        "ClaimsProviderSelection",
+        &[],
+        &[
+            opt("TargetClaimsExchangeId"),
+            opt("ValidationClaimsExchangeId"),
+        ],
+    ),
+    el(
+        "ClaimsExchanges",
+        &["ClaimsExchange"],
+        &[opt("UserIdentity")],
+    ),
+    el(
+        "ClaimsExchange",
+        &[],
+        &[req("Id"), req("TechnicalProfileReferenceId")],
+    ),
+    el("JourneyList", &["Candidate"], &[]),
+    el("Candidate", &[], &[req("SubJourneyReferenceId")]),
+    el("SubJourneys", &["SubJourney"], &[]),
+    el(
+        "SubJourney",
+        &["OrchestrationSteps"],
+        &[req("Id"), one_of("Type", &["Call", "Transfer"])],
+    ),
+    el(
+        "RelyingParty",
+        &[
+            "DefaultUserJourney",
+            "UserJourneyBehaviors",
+            "Endpoints",
+            "TechnicalProfile",
+        ],
+        &[],
+    ),
+    el("DefaultUserJourney", &[], &[req("ReferenceId")]),
+    el(
+        "UserJourneyBehaviors",
+        &[
+            "SingleSignOn",
+            "SessionExpiryType",
+            "SessionExpiryInSeconds",
+            "JourneyInsights",
+            "ContentDefinitionParameters",
+            "ScriptExecution",
+            "JourneyFraming",
+        ],
+        &[],
+    ),
+    el(
+        "SingleSignOn",
+        &[],
+        &[
+            one_of("Scope", &["Suppressed", "Tenant", "Application", "Policy"]),
+            opt("KeepAliveInDays"),
+            one_of("EnforceIdTokenHintOnLogout", BOOLEAN),
+        ],
+    ),
+    text("SessionExpiryType", &["Rolling", "Absolute"]),
+    text("ScriptExecution", &["Allow", "Disallow"]),
+    el(
+        "JourneyInsights",
+        &[],
+        &[
+            one_of("TelemetryEngine", &["ApplicationInsights"]),
+            req("InstrumentationKey"),
+            one_of("DeveloperMode", BOOLEAN),
+            one_of("ClientEnabled", BOOLEAN),
+            one_of("ServerEnabled", BOOLEAN),
+            opt("TelemetryVersion"),
+        ],
+    ),
+    el(
+        "JourneyFraming",
+        &[],
+        &[one_of("Enabled", BOOLEAN), opt("Sources")],
+    ),
+    el("Endpoints", &["Endpoint"], &[]),
+    el("Endpoint", &[], &[req("Id"), req("UserJourneyReferenceId")]),
+];
+
+pub fn element_model(name: &str) -> Option<&'static ElementModel> {
+    ELEMENTS.iter().find(|e| e.name == name)
+}
+
+impl ElementModel {
+    pub fn attribute(&self, name: &str) -> Option<&AttributeModel> {
+        self.attributes.iter().find(|a| a.name == name)
+    }
+
+    //Children that may still follow a child with the given name
+    pub fn children_after(&self, previous: Option<&str>) -> &[&'static str] {
+        match previous.and_then(|p| self.children.iter().position(|c| *c == p)) {
+            Some(index) => &self.children[index..],
+            None => self.children,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{element_model, ELEMENTS};
+
+    #[test]
+    fn test_element_names_are_unique() {
+        for model in ELEMENTS {
+            assert_eq!(element_model(model.name).unwrap().name, model.name);
+            assert_eq!(
+                ELEMENTS.iter().filter(|m| m.name == model.name).count(),
+                1,
+                "{} is modelled twice",
+                model.name
+            );
+        }
+        assert!(element_model("Unknown").is_none());
+    }
+
+    #[test]
+    fn test_children_after() {
+        let profile = element_model("TechnicalProfile").unwrap();
+        let after = profile.children_after(Some("Metadata"));
+        assert_eq!(after[0], "Metadata");
+        assert!(!after.contains(&"Protocol"));
+        assert_eq!(profile.children_after(None).len(), profile.children.len());
+    }
+}
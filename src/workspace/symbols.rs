@@ -0,0 +1,369 @@
+use lsp_types::{Position, Range};
+use tree_sitter::Node;
+
+use super::queries::{
+    attribute_parts, base_policy_query, definition_query, get_attribute, get_range, get_tag_name,
+    id_query, parse_tag, reference_query, value_range, Source,
+};
+
+//Kinds of elements that can be pointed at from somewhere else in a policy set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ElementKind {
+    Policy,
+    ClaimType,
+    ClaimsTransformation,
+    TechnicalProfile,
+    UserJourney,
+    SubJourney,
+    ContentDefinition,
+    DisplayControl,
+    Predicate,
+    PredicateValidation,
+    LocalizedResources,
+}
+
+impl ElementKind {
+    //Tag name of an element that defines an Id of this kind
+    pub fn from_definition_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "ClaimType" => Some(Self::ClaimType),
+            "ClaimsTransformation" => Some(Self::ClaimsTransformation),
+            "TechnicalProfile" => Some(Self::TechnicalProfile),
+            "UserJourney" => Some(Self::UserJourney),
+            "SubJourney" => Some(Self::SubJourney),
+            "ContentDefinition" => Some(Self::ContentDefinition),
+            "DisplayControl" => Some(Self::DisplayControl),
+            "Predicate" => Some(Self::Predicate),
+            "PredicateValidation" => Some(Self::PredicateValidation),
+            "LocalizedResources" => Some(Self::LocalizedResources),
+            _ => None,
+        }
+    }
+
+    //Kind of element a reference attribute points to
+    pub fn from_reference(tag: &str, attr: &str) -> Option<Self> {
+        match (tag, attr) {
+            (_, "ClaimTypeReferenceId") => Some(Self::ClaimType),
+            (_, "TechnicalProfileReferenceId") => Some(Self::TechnicalProfile),
+            (_, "ContentDefinitionReferenceId") => Some(Self::ContentDefinition),
+            (_, "DisplayControlReferenceId") => Some(Self::DisplayControl),
+            (_, "SubJourneyReferenceId") => Some(Self::SubJourney),
+            (_, "UserJourneyReferenceId") => Some(Self::UserJourney),
+            (_, "LocalizedResourcesReferenceId") => Some(Self::LocalizedResources),
+            ("InputClaimsTransformation" | "OutputClaimsTransformation", "ReferenceId") => {
+                Some(Self::ClaimsTransformation)
+            }
+            ("DefaultUserJourney", "ReferenceId") => Some(Self::UserJourney),
+            (
+                "ValidationTechnicalProfile"
+                | "IncludeTechnicalProfile"
+                | "UseTechnicalProfileForSessionManagement",
+                "ReferenceId",
+            ) => Some(Self::TechnicalProfile),
+            ("PredicateReference", "Id") => Some(Self::Predicate),
+            ("PredicateValidationReference", "Id") => Some(Self::PredicateValidation),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Policy => "TrustFrameworkPolicy",
+            Self::ClaimType => "ClaimType",
+            Self::ClaimsTransformation => "ClaimsTransformation",
+            Self::TechnicalProfile => "TechnicalProfile",
+            Self::UserJourney => "UserJourney",
+            Self::SubJourney => "SubJourney",
+            Self::ContentDefinition => "ContentDefinition",
+            Self::DisplayControl => "DisplayControl",
+            Self::Predicate => "Predicate",
+            Self::PredicateValidation => "PredicateValidation",
+            Self::LocalizedResources => "LocalizedResources",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolRole {
+    Definition,
+    Reference,
+}
+
+//One occurrence of an Id in a policy, either where it is defined or where it is used
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IEFSymbol {
+    pub kind: ElementKind,
+    pub id: String,
+    //Range of the Id text, without quotes
+    pub range: Range,
+    pub role: SymbolRole,
+}
+
+impl IEFSymbol {
+    //Policy ids are compared the same way find_policy_by_id does
+    pub fn same_target(&self, other: &IEFSymbol) -> bool {
+        if self.kind != other.kind {
+            return false;
+        }
+        match self.kind {
+            ElementKind::Policy => self.id.to_lowercase() == other.id.to_lowercase(),
+            _ => self.id == other.id,
+        }
+    }
+
+    pub fn is_definition(&self) -> bool {
+        self.role == SymbolRole::Definition
+    }
+}
+
+fn classify(tag: &str, attr: &str) -> Option<(ElementKind, SymbolRole)> {
+    if tag == "TrustFrameworkPolicy" && attr == "PolicyId" {
+        return Some((ElementKind::Policy, SymbolRole::Definition));
+    }
+    if attr == "Id" {
+        if let Some(kind) = ElementKind::from_definition_tag(tag) {
+            return Some((kind, SymbolRole::Definition));
+        }
+    }
+    ElementKind::from_reference(tag, attr).map(|kind| (kind, SymbolRole::Reference))
+}
+
+//Every definition and reference in a policy document
+pub fn find_symbols(root_node: Node, text: &(impl Source + ?Sized)) -> Vec<IEFSymbol> {
+    let mut symbols = vec![];
+    if let Some(id_match) = id_query().and_then(|q| q.first(root_node, text)) {
+        symbols.push(IEFSymbol {
+            kind: ElementKind::Policy,
+            id: id_match.txt,
+            range: id_match.range,
+            role: SymbolRole::Definition,
+        });
+    }
+    if let Some(base_match) = base_policy_query().and_then(|q| q.first(root_node, text)) {
+        symbols.push(IEFSymbol {
+            kind: ElementKind::Policy,
+            id: base_match.txt,
+            range: base_match.range,
+            role: SymbolRole::Reference,
+        });
+    }
+    symbols.extend(
+        definition_query()
+            .map(|q| q.all(root_node, text))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|def| {
+                Some(IEFSymbol {
+                    kind: ElementKind::from_definition_tag(def.tag_name.as_str())?,
+                    id: def.id,
+                    range: def.id_range,
+                    role: SymbolRole::Definition,
+                })
+            }),
+    );
+    symbols.extend(
+        reference_query()
+            .map(|q| q.references(root_node, text))
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|reference| {
+                let (kind, role) = classify(&reference.tag_name, &reference.attr_name)?;
+                if role != SymbolRole::Reference {
+                    return None;
+                }
+                Some(IEFSymbol {
+                    kind,
+                    id: reference.id,
+                    range: reference.id_range,
+                    role,
+                })
+            }),
+    );
+    symbols
+}
+
+//The definition or reference under the cursor, if there is one
+pub fn symbol_at(
+    root_node: &Node,
+    text: &(impl Source + ?Sized),
+    pos: Position,
+) -> Option<IEFSymbol> {
+    let element = get_tag_name(root_node, pos)?;
+    let tag = parse_tag(element, text)?;
+    if let Some(attr) = get_attribute(root_node, pos) {
+        let (name, value) = attribute_parts(&attr)?;
+        let attr_name = text.node_text(&name)?;
+        let (kind, role) = classify(tag.name.as_str(), &attr_name)?;
+        return Some(IEFSymbol {
+            kind,
+            id: tag.attrs.get(attr_name.as_ref())?.clone(),
+            range: value_range(&value),
+            role,
+        });
+    }
+    //<BasePolicy><PolicyId>...</PolicyId></BasePolicy>
+    if tag.name != "PolicyId" {
+        return None;
+    }
+    let parent = element.parent()?.parent()?;
+    if parse_tag(parent, text)?.name != "BasePolicy" {
+        return None;
+    }
+    let mut cursor = element.walk();
+    let content = element
+        .named_children(&mut cursor)
+        .find(|n| n.grammar_name() == "content")?;
+    Some(IEFSymbol {
+        kind: ElementKind::Policy,
+        id: text.node_text(&content)?.trim().to_string(),
+        range: get_range(&content),
+        role: SymbolRole::Reference,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use lsp_types::Position;
+    use tree_sitter::Tree;
+
+    use super::{find_symbols, symbol_at, ElementKind, SymbolRole};
+
+    fn get_test_str() -> (Tree, String) {
+        let s = String::from(
+            "<TrustFrameworkPolicy PolicyId=\"B2C_1A_Ext\">
+  <BasePolicy>
+    <PolicyId>B2C_1A_Base</PolicyId>
+  </BasePolicy>
+  <BuildingBlocks>
+    <ClaimsSchema>
+      <ClaimType Id=\"email\">
+        <PredicateValidationReference Id=\"StrongPassword\" />
+      </ClaimType>
+    </ClaimsSchema>
+  </BuildingBlocks>
+  <ClaimsProviders>
+    <ClaimsProvider>
+      <TechnicalProfiles>
+        <TechnicalProfile Id=\"AAD-UserRead\">
+          <InputClaims>
+            <InputClaim ClaimTypeReferenceId=\"email\" />
+          </InputClaims>
+          <OutputClaimsTransformations>
+            <OutputClaimsTransformation ReferenceId=\"CreateDisplayName\" />
+          </OutputClaimsTransformations>
+          <UseTechnicalProfileForSessionManagement ReferenceId=\"SM-AAD\" />
+        </TechnicalProfile>
+      </TechnicalProfiles>
+    </ClaimsProvider>
+  </ClaimsProviders>
+</TrustFrameworkPolicy>
+",
+        );
+        let mut t = tree_sitter::Parser::new();
+        t.set_language(&tree_sitter_xml::language_xml()).unwrap();
+        (t.parse(s.as_str(), None).unwrap(), s)
+    }
+
+    #[test]
+    fn test_find_symbols() {
+        let (t, s) = get_test_str();
+        let symbols = find_symbols(t.root_node(), s.as_str());
+        let found: Vec<_> = symbols
+            .iter()
+            .map(|sym| (sym.kind, sym.id.as_str(), sym.role))
+            .collect();
+        assert!(found.contains(&(ElementKind::Policy, "B2C_1A_Ext", SymbolRole::Definition)));
+        assert!(found.contains(&(ElementKind::Policy, "B2C_1A_Base", SymbolRole::Reference)));
+        assert!(found.contains(&(ElementKind::ClaimType, "email", SymbolRole::Definition)));
+        assert!(found.contains(&(ElementKind::ClaimType, "email", SymbolRole::Reference)));
+        assert!(found.contains(&(
+            ElementKind::TechnicalProfile,
+            "AAD-UserRead",
+            SymbolRole::Definition
+        )));
+        assert!(found.contains(&(
+            ElementKind::ClaimsTransformation,
+            "CreateDisplayName",
+            SymbolRole::Reference
+        )));
+        assert!(found.contains(&(
+            ElementKind::TechnicalProfile,
+            "SM-AAD",
+            SymbolRole::Reference
+        )));
+        assert!(found.contains(&(
+            ElementKind::PredicateValidation,
+            "StrongPassword",
+            SymbolRole::Reference
+        )));
+        assert_eq!(found.len(), 8);
+    }
+
+    #[test]
+    fn test_symbols_from_rope() {
+        //Ids long enough that some of them are split between rope chunks
+        let claims: String = (0..200)
+            .map(|i| format!("<ClaimType Id=\"{i}{}\" />\n", "x".repeat(300)))
+            .collect();
+        let s = format!("<TrustFrameworkPolicy PolicyId=\"B2C_1A_Big\"><ClaimsSchema>\n{claims}</ClaimsSchema></TrustFrameworkPolicy>");
+        let rope = ropey::Rope::from_str(&s);
+        assert!(rope.chunks().count() > 1);
+        let mut t = tree_sitter::Parser::new();
+        t.set_language(&tree_sitter_xml::language_xml()).unwrap();
+        let tree = t.parse(s.as_str(), None).unwrap();
+        let symbols = find_symbols(tree.root_node(), &rope);
+        assert_eq!(symbols.len(), 201);
+        assert_eq!(symbols, find_symbols(tree.root_node(), s.as_str()));
+    }
+
+    #[test]
+    fn test_symbol_ranges_exclude_quotes() {
+        let (t, s) = get_test_str();
+        let symbols = find_symbols(t.root_node(), s.as_str());
+        let email = symbols
+            .iter()
+            .find(|sym| sym.kind == ElementKind::ClaimType && sym.is_definition())
+            .unwrap();
+        assert_eq!(email.range.start, Position::new(6, 21));
+        assert_eq!(email.range.end, Position::new(6, 26));
+        let policy = symbols
+            .iter()
+            .find(|sym| sym.kind == ElementKind::Policy && sym.is_definition())
+            .unwrap();
+        assert_eq!(policy.range.start, Position::new(0, 32));
+        assert_eq!(policy.range.end, Position::new(0, 42));
+    }
+
+    #[test]
+    fn test_symbol_at_reference() {
+        let (t, s) = get_test_str();
+        let sym = symbol_at(&t.root_node(), s.as_str(), Position::new(16, 48)).unwrap();
+        assert_eq!(sym.kind, ElementKind::ClaimType);
+        assert_eq!(sym.id, "email");
+        assert_eq!(sym.role, SymbolRole::Reference);
+    }
+
+    #[test]
+    fn test_symbol_at_definition() {
+        let (t, s) = get_test_str();
+        let sym = symbol_at(&t.root_node(), s.as_str(), Position::new(14, 34)).unwrap();
+        assert_eq!(sym.kind, ElementKind::TechnicalProfile);
+        assert_eq!(sym.id, "AAD-UserRead");
+        assert_eq!(sym.role, SymbolRole::Definition);
+    }
+
+    #[test]
+    fn test_symbol_at_base_policy() {
+        let (t, s) = get_test_str();
+        let sym = symbol_at(&t.root_node(), s.as_str(), Position::new(2, 18)).unwrap();
+        assert_eq!(sym.kind, ElementKind::Policy);
+        assert_eq!(sym.id, "B2C_1A_Base");
+        assert_eq!(sym.role, SymbolRole::Reference);
+    }
+
+    #[test]
+    fn test_symbol_at_plain_attribute() {
+        let (t, s) = get_test_str();
+        assert!(symbol_at(&t.root_node(), s.as_str(), Position::new(12, 8)).is_none());
+    }
+}
@@ -1,126 +1,252 @@
-use log::info;
-use lsp_types::{Position, Range, TextEdit};
-pub struct TextSync {
-    raw_text: String,
+use lsp_types::{Position, PositionEncodingKind, Range, TextEdit};
+use ropey::{Rope, RopeSlice};
+use tree_sitter::{InputEdit, Parser, Point, Tree};
+
+//Unit the client counts Position::character in. Everything inside the server
+//works on byte columns, which is what tree-sitter uses for Points
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PositionEncoding {
+    Utf8,
+    //The LSP default when the client does not offer anything else
+    #[default]
+    Utf16,
+    Utf32,
 }
 
-fn delete_range(txt: &mut str, start_byte: usize, end_byte: usize) -> String {
-    let (pre_delete, extra) = txt.split_at_mut(start_byte);
-    let (_, post_delete) = extra.split_at_mut(end_byte - start_byte);
-    let mut new_str = String::from(pre_delete);
-    new_str.push_str(post_delete);
-    return new_str;
+impl PositionEncoding {
+    //The first encoding the client offers that the server supports
+    pub fn negotiate(offered: Option<&[PositionEncodingKind]>) -> Self {
+        offered
+            .unwrap_or_default()
+            .iter()
+            .find_map(Self::from_kind)
+            .unwrap_or_default()
+    }
+
+    pub fn from_kind(kind: &PositionEncodingKind) -> Option<Self> {
+        match kind.as_str() {
+            "utf-8" => Some(Self::Utf8),
+            "utf-16" => Some(Self::Utf16),
+            "utf-32" => Some(Self::Utf32),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> PositionEncodingKind {
+        match self {
+            Self::Utf8 => PositionEncodingKind::UTF8,
+            Self::Utf16 => PositionEncodingKind::UTF16,
+            Self::Utf32 => PositionEncodingKind::UTF32,
+        }
+    }
+
+    fn units(&self, c: char) -> usize {
+        match self {
+            Self::Utf8 => c.len_utf8(),
+            Self::Utf16 => c.len_utf16(),
+            Self::Utf32 => 1,
+        }
+    }
 }
 
-fn insert_text(txt: &mut str, range: &str, byte: usize) -> String {
-    let (pre_insert, post_insert) = txt.split_at_mut(byte);
-    let mut new_str = String::from(pre_insert);
-    new_str.push_str(range);
-    new_str.push_str(post_insert);
-    return new_str;
+//Document text in a rope so edits and line lookups stay logarithmic in the size
+//of the file. Lines only break on \n, the same rows tree-sitter counts
+#[derive(Clone)]
+pub struct TextSync {
+    rope: Rope,
 }
 
 impl TextSync {
     pub fn new(text: String) -> Self {
-        TextSync { raw_text: text }
+        TextSync {
+            rope: Rope::from_str(&text),
+        }
+    }
+
+    //The queries and completion read the rope, only tests want one piece
+    #[cfg(test)]
+    pub fn text(&self) -> String {
+        self.rope.to_string()
     }
 
-    pub fn text(&self) -> &str {
-        self.raw_text.as_str()
+    pub fn rope(&self) -> &Rope {
+        &self.rope
     }
 
-    pub fn edit(&mut self, edit: &TextEdit) {
+    //Replaces the range, which has byte columns, with the new text and returns
+    //the matching edit for the syntax tree
+    pub fn edit(&mut self, edit: &TextEdit) -> InputEdit {
         let start = edit.range.start;
         let end = edit.range.end;
-        let start_byte = self.byte_pos(
-            start.line.try_into().unwrap(),
-            start.character.try_into().unwrap(),
-        );
-        let end_byte = self.byte_pos(
-            end.line.try_into().unwrap(),
-            end.character.try_into().unwrap(),
-        );
-        let text = edit.new_text.clone();
-        let new_text = match text.as_str() {
-            "" => delete_range(&mut self.raw_text, start_byte, end_byte),
-            _ => insert_text(&mut self.raw_text, text.as_str(), start_byte),
-        };
-        info!("Updating text");
-        info!("Old text {:?}", self.raw_text);
-        info!("New text {new_text:?}");
-        self.raw_text = new_text;
+        let start_byte = self.byte_pos(start.line as usize, start.character as usize);
+        let old_end_byte = self
+            .byte_pos(end.line as usize, end.character as usize)
+            .max(start_byte);
+        let start_position = self.point(start_byte);
+        let old_end_position = self.point(old_end_byte);
+        let start_char = self.rope.byte_to_char(start_byte);
+        let end_char = self.rope.byte_to_char(old_end_byte);
+        self.rope.remove(start_char..end_char);
+        self.rope.insert(start_char, &edit.new_text);
+        let new_end_byte = start_byte + edit.new_text.len();
+        InputEdit {
+            start_byte,
+            old_end_byte,
+            new_end_byte,
+            start_position,
+            old_end_position,
+            new_end_position: self.point(new_end_byte),
+        }
     }
 
+    //Full document sync
+    pub fn replace(&mut self, text: String) {
+        *self = TextSync::new(text);
+    }
+
+    //Row and byte column of a byte offset
+    fn point(&self, byte: usize) -> Point {
+        let row = self.rope.byte_to_line(byte);
+        Point::new(row, byte - self.rope.line_to_byte(row))
+    }
+
+    //Hands the text to tree-sitter one rope chunk at a time
+    pub fn parse(&self, parser: &mut Parser, old_tree: Option<&Tree>) -> Option<Tree> {
+        parser.parse_with(&mut |byte, _| self.chunk_from(byte), old_tree)
+    }
+
+    fn chunk_from(&self, byte: usize) -> &[u8] {
+        if byte >= self.rope.len_bytes() {
+            return &[];
+        }
+        let (chunk, chunk_start, _, _) = self.rope.chunk_at_byte(byte);
+        &chunk.as_bytes()[byte - chunk_start..]
+    }
+
+    #[allow(dead_code)]
     pub fn lines(&self) -> usize {
-        self.raw_text.lines().count()
+        match self.rope.len_bytes() {
+            0 => 0,
+            _ if self.rope.byte(self.rope.len_bytes() - 1) == b'\n' => self.rope.len_lines() - 1,
+            _ => self.rope.len_lines(),
+        }
     }
 
+    #[allow(dead_code)]
     pub fn characters(&self, line: usize) -> usize {
-        match self.raw_text.lines().nth(line) {
-            Some(l) => l.len(),
-            None => 0,
+        self.line_text(line as u32)
+            .map(|l| l.len_bytes())
+            .unwrap_or(0)
+    }
+
+    //Whether a position on the line is in the document
+    pub fn has_line(&self, line: u32) -> bool {
+        (line as usize) < self.rope.len_lines()
+    }
+
+    //Text of a line without the \n or \r\n that ends it, so columns past the end
+    //are never clamped to between the \r and the \n
+    fn line_text(&self, line: u32) -> Option<RopeSlice<'_>> {
+        let line = self.rope.get_line(line as usize)?;
+        let mut end = line.len_chars();
+        if end > 0 && line.char(end - 1) == '\n' {
+            end -= 1;
+            if end > 0 && line.char(end - 1) == '\r' {
+                end -= 1;
+            }
         }
+        Some(line.slice(..end))
     }
 
-    pub fn byte_pos(&self, line: usize, character: usize) -> usize {
-        let mut byte_count = 0;
-        for (i, l) in self.raw_text.lines().enumerate() {
-            if i == line {
-                byte_count += character;
+    //Client position to a position with a byte column. Columns past the end of
+    //the line are clamped to it
+    pub fn to_byte_position(&self, pos: Position, encoding: PositionEncoding) -> Position {
+        let Some(line) = self.line_text(pos.line) else {
+            return pos;
+        };
+        let mut units = 0;
+        let mut bytes = 0;
+        for c in line.chars() {
+            if units >= pos.character as usize {
+                break;
+            }
+            units += encoding.units(c);
+            bytes += c.len_utf8();
+        }
+        Position::new(pos.line, bytes as u32)
+    }
+
+    //Position with a byte column to what the client expects
+    pub fn to_encoded_position(&self, pos: Position, encoding: PositionEncoding) -> Position {
+        let Some(line) = self.line_text(pos.line) else {
+            return pos;
+        };
+        let mut units = 0;
+        let mut bytes = 0;
+        for c in line.chars() {
+            bytes += c.len_utf8();
+            if bytes > pos.character as usize {
                 break;
             }
-            byte_count += l.len();
-            byte_count += 1; // for the \n
+            units += encoding.units(c);
         }
-        return byte_count;
+        Position::new(pos.line, units as u32)
+    }
+
+    pub fn to_byte_range(&self, range: Range, encoding: PositionEncoding) -> Range {
+        Range::new(
+            self.to_byte_position(range.start, encoding),
+            self.to_byte_position(range.end, encoding),
+        )
+    }
+
+    pub fn to_encoded_range(&self, range: Range, encoding: PositionEncoding) -> Range {
+        Range::new(
+            self.to_encoded_position(range.start, encoding),
+            self.to_encoded_position(range.end, encoding),
+        )
+    }
+
+    //Byte offset of a line and byte column, clamped to the end of the text
+    pub fn byte_pos(&self, line: usize, character: usize) -> usize {
+        if line >= self.rope.len_lines() {
+            return self.rope.len_bytes();
+        }
+        (self.rope.line_to_byte(line) + character).min(self.rope.len_bytes())
     }
 }
 #[cfg(test)]
 mod test {
-    use lsp_types::TextEdit;
+    use lsp_types::{Position, PositionEncodingKind, Range, TextEdit};
 
-    use super::TextSync;
+    use super::{PositionEncoding, TextSync};
 
-    #[test]
-    fn test_tests() {
-        assert_eq!(2 + 2, 4);
-    }
     #[test]
     fn test_lines() {
-        let obj = TextSync {
-            raw_text: String::from("\n\n\n"),
-        };
+        let obj = TextSync::new(String::from("\n\n\n"));
         assert_eq!(obj.lines(), 3)
     }
     #[test]
     fn test_characters() {
-        let obj = TextSync {
-            raw_text: String::from("\n\n\n"),
-        };
+        let obj = TextSync::new(String::from("\n\n\n"));
         assert_eq!(obj.characters(1), 0)
     }
     #[test]
     fn test_byte_pos() {
-        let obj = TextSync {
-            raw_text: String::from("\n\n\n"),
-        };
+        let obj = TextSync::new(String::from("\n\n\n"));
         assert_eq!(obj.byte_pos(1, 0), 1);
         assert_eq!(obj.byte_pos(1, 1), 2);
     }
     #[test]
     fn test_byte_pos_with_text() {
-        let obj = TextSync {
-            raw_text: String::from("abc\n\n\n"),
-        };
+        let obj = TextSync::new(String::from("abc\n\n\n"));
         assert_eq!(obj.byte_pos(0, 2), 2);
         assert_eq!(obj.byte_pos(1, 0), 4);
     }
 
     #[test]
     fn test_delete_text() {
-        let mut obj = TextSync {
-            raw_text: String::from("abc\nabc\n\n"),
-        };
+        let mut obj = TextSync::new(String::from("abc\nabc\n\n"));
         let s = TextEdit {
             range: lsp_types::Range {
                 start: lsp_types::Position {
@@ -135,13 +261,11 @@ mod test {
             new_text: String::from(""),
         };
         obj.edit(&s);
-        assert_eq!(obj.raw_text.as_str(), "abc\nc\n\n")
+        assert_eq!(obj.text(), "abc\nc\n\n")
     }
     #[test]
     fn test_insert_text() {
-        let mut obj = TextSync {
-            raw_text: String::from("abc\nc\n\n"),
-        };
+        let mut obj = TextSync::new(String::from("abc\nc\n\n"));
         let s = TextEdit {
             range: lsp_types::Range {
                 start: lsp_types::Position {
@@ -156,6 +280,96 @@ mod test {
             new_text: String::from("ab"),
         };
         obj.edit(&s);
-        assert_eq!(obj.raw_text.as_str(), "abc\nabc\n\n")
+        assert_eq!(obj.text(), "abc\nabc\n\n")
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        assert_eq!(PositionEncoding::negotiate(None), PositionEncoding::Utf16);
+        let offered = [PositionEncodingKind::UTF32, PositionEncodingKind::UTF8];
+        assert_eq!(
+            PositionEncoding::negotiate(Some(&offered)),
+            PositionEncoding::Utf32
+        );
+        let offered = [PositionEncodingKind::new("utf-7")];
+        assert_eq!(
+            PositionEncoding::negotiate(Some(&offered)),
+            PositionEncoding::Utf16
+        );
+    }
+
+    #[test]
+    fn test_position_conversion() {
+        //é is 2 bytes and 1 UTF-16 unit, 😀 is 4 bytes and 2 UTF-16 units
+        let obj = TextSync::new(String::from("x\n<a b=\"é😀\" c=\"d\"/>\n"));
+        let c = Position::new(1, 12);
+        assert_eq!(
+            obj.to_byte_position(Position::new(1, 9), PositionEncoding::Utf16),
+            c
+        );
+        assert_eq!(
+            obj.to_byte_position(Position::new(1, 8), PositionEncoding::Utf32),
+            c
+        );
+        assert_eq!(obj.to_byte_position(c, PositionEncoding::Utf8), c);
+        assert_eq!(
+            obj.to_encoded_position(c, PositionEncoding::Utf16),
+            Position::new(1, 9)
+        );
+        assert_eq!(
+            obj.to_encoded_position(c, PositionEncoding::Utf32),
+            Position::new(1, 8)
+        );
+        //Past the end of the line
+        assert_eq!(
+            obj.to_byte_position(Position::new(0, 5), PositionEncoding::Utf16),
+            Position::new(0, 1)
+        );
+    }
+
+    #[test]
+    fn test_replace_text() {
+        let mut obj = TextSync::new(String::from("<a Id=\"old\"/>\n<b/>\n"));
+        obj.edit(&TextEdit {
+            range: Range::new(Position::new(0, 7), Position::new(0, 10)),
+            new_text: String::from("new\nline"),
+        });
+        assert_eq!(obj.text(), "<a Id=\"new\nline\"/>\n<b/>\n");
+        assert_eq!(obj.byte_pos(2, 0), 19);
+    }
+
+    #[test]
+    fn test_crlf() {
+        let mut obj = TextSync::new(String::from("<a>\r\n  <b/>\r\n</a>\r\n"));
+        assert_eq!(obj.lines(), 3);
+        assert_eq!(obj.characters(1), 6);
+        assert_eq!(obj.byte_pos(1, 2), 7);
+        //Past the end of the line stays in front of the \r
+        let end = obj.to_byte_position(Position::new(0, 10), PositionEncoding::Utf16);
+        assert_eq!(end, Position::new(0, 3));
+        let input_edit = obj.edit(&TextEdit {
+            range: Range::new(end, end),
+            new_text: String::from("\r\n  <c/>"),
+        });
+        assert_eq!(obj.text(), "<a>\r\n  <c/>\r\n  <b/>\r\n</a>\r\n");
+        assert_eq!(input_edit.new_end_position, tree_sitter::Point::new(1, 6));
+    }
+
+    #[test]
+    fn test_parse_from_chunks() {
+        //Long enough to span several rope chunks
+        let text = "<a>\n".to_string() + &"  <b Id=\"é\"/>\n".repeat(2000) + "</a>\n";
+        let obj = TextSync::new(text.clone());
+        let mut parser = tree_sitter::Parser::new();
+        parser
+            .set_language(&tree_sitter_xml::language_xml())
+            .unwrap();
+        let from_chunks = obj.parse(&mut parser, None).unwrap();
+        let from_str = parser.parse(&text, None).unwrap();
+        assert_eq!(
+            from_chunks.root_node().to_sexp(),
+            from_str.root_node().to_sexp()
+        );
+        assert!(!from_chunks.root_node().has_error());
     }
 }